@@ -0,0 +1,655 @@
+use crate::cache::{Cache, Inode};
+use crate::fs::{BLOCK_SIZE, RECOVERY_FILE};
+use crate::object::{DirectoryEntry, DirectoryObject, FileObject, FileSystemObject};
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::PoisonError;
+use std::time::{Duration, SystemTime};
+
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use tar::{Archive, Builder, EntryType, Header};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+/// An import stores its objects in transactions of about this many bytes of file contents
+const BATCH_BYTES: usize = 4 * 1024 * 1024;
+/// ...or of this many files, whichever comes first
+const BATCH_FILES: usize = 256;
+
+const PAX_ATIME: &str = "atime";
+const PAX_MTIME: &str = "mtime";
+const PAX_CTIME: &str = "ctime";
+const PAX_CRTIME: &str = "LIBARCHIVE.creationtime";
+
+#[derive(Debug, Error)]
+pub enum ArchiveError<TCache: Cache> {
+    #[error("Cache error: {0}")]
+    Cache(<TCache as Cache>::Error),
+    #[error("Archive error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} leaves the root of the filesystem")]
+    InvalidPath(PathBuf),
+    #[error("{0} already exists as a different type")]
+    TypeMismatch(PathBuf),
+    #[error("{0} already exists")]
+    Exists(PathBuf),
+    #[error("Object of inode {0} is missing")]
+    Missing(Inode),
+}
+
+/// What an export wrote or an import read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    pub directories: usize,
+    pub files: usize,
+    pub links: usize,
+    /// Bytes of file contents
+    pub bytes: u64,
+    /// Entries that were left out, like symlinks or objects that couldn't be found
+    pub skipped: usize,
+}
+
+/// Writes every directory and file below the root to `writer` as a tar archive, parents
+/// before their children. Files linked from several directories become hard links. The
+/// welcome file is left out, every filesystem has its own.
+pub async fn export<TCache: Cache>(
+    cache: &TCache,
+    writer: impl Write,
+) -> Result<ArchiveStats, ArchiveError<TCache>> {
+    let mut builder = Builder::new(writer);
+    let mut stats = ArchiveStats::default();
+    // Where files linked from more than one directory were first written
+    let mut linked: HashMap<Inode, PathBuf> = HashMap::new();
+    let mut pending = vec![(FUSE_ROOT_ID, PathBuf::from("."))];
+    while let Some((ino, path)) = pending.pop() {
+        let Some(obj) = cache.get(ino).await.map_err(ArchiveError::Cache)? else {
+            warn!(%ino, path = %path.display(), "Skipping missing object");
+            stats.skipped += 1;
+            continue;
+        };
+        let obj = obj.read().unwrap_or_else(PoisonError::into_inner);
+        match &*obj {
+            FileSystemObject::Dir(dir) => {
+                append(
+                    &mut builder,
+                    &dir.attr,
+                    EntryType::Directory,
+                    &path,
+                    &[][..],
+                )?;
+                stats.directories += 1;
+                let mut children: Vec<_> = dir
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.name != "." && entry.name != "..")
+                    .filter(|entry| entry.ino != RECOVERY_FILE)
+                    .collect();
+                // Popped in order of their names
+                children.sort_by(|a, b| b.name.cmp(&a.name));
+                pending.extend(
+                    children
+                        .into_iter()
+                        .map(|entry| (entry.ino, path.join(&entry.name))),
+                );
+            }
+            FileSystemObject::File(file) => {
+                if let Some(target) = linked.get(&ino) {
+                    let mut header = header(&file.attr, EntryType::Link, 0);
+                    builder.append_link(&mut header, &path, target)?;
+                    stats.links += 1;
+                    continue;
+                }
+                if file.attr.nlink > 1 {
+                    linked.insert(ino, path.clone());
+                }
+                append(
+                    &mut builder,
+                    &file.attr,
+                    EntryType::Regular,
+                    &path,
+                    &file.data[..],
+                )?;
+                stats.files += 1;
+                stats.bytes += file.data.len() as u64;
+            }
+        }
+    }
+    builder.into_inner()?.flush()?;
+    info!(?stats, "Exported filesystem");
+    Ok(stats)
+}
+
+/// Adds the entries of an archive to the filesystem. Existing directories get the attributes
+/// from the archive and existing files are replaced. Objects are stored in batches, each
+/// batch one transaction together with the directories it changed.
+pub async fn import<TCache: Cache>(
+    cache: &TCache,
+    reader: impl Read,
+) -> Result<ArchiveStats, ArchiveError<TCache>> {
+    let mut importer = Importer::new(cache);
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = relative_path(&entry.path()?)?;
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                let attr = entry_attr(&mut entry)?;
+                importer.directory(&path, attr).await?;
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let attr = entry_attr(&mut entry)?;
+                // The header size is untrusted, so let the buffer grow with what is actually read
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                importer.file(&path, attr, data).await?;
+            }
+            EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| ArchiveError::InvalidPath(path.clone()))?;
+                importer.link(&path, &relative_path(&target)?).await?;
+            }
+            kind => {
+                warn!(?kind, path = %path.display(), "Skipping unsupported entry");
+                importer.stats.skipped += 1;
+            }
+        }
+    }
+    importer.flush().await?;
+    info!(stats = ?importer.stats, "Imported archive");
+    Ok(importer.stats)
+}
+
+fn header(attr: &FileAttr, kind: EntryType, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(u32::from(attr.perm & 0o7777));
+    header.set_uid(u64::from(attr.uid));
+    header.set_gid(u64::from(attr.gid));
+    header.set_mtime(since_epoch(attr.mtime).as_secs());
+    header.set_size(size);
+    header
+}
+
+/// Writes an entry with the timestamps of `attr` in a PAX header before it
+fn append<W: Write>(
+    builder: &mut Builder<W>,
+    attr: &FileAttr,
+    kind: EntryType,
+    path: &Path,
+    data: &[u8],
+) -> io::Result<()> {
+    let times = [
+        (PAX_ATIME, attr.atime),
+        (PAX_MTIME, attr.mtime),
+        (PAX_CTIME, attr.ctime),
+        (PAX_CRTIME, attr.crtime),
+    ]
+    .map(|(key, time)| {
+        let time = since_epoch(time);
+        (
+            key,
+            format!("{}.{:09}", time.as_secs(), time.subsec_nanos()),
+        )
+    });
+    builder.append_pax_extensions(times.iter().map(|(key, time)| (*key, time.as_bytes())))?;
+    let mut header = header(attr, kind, data.len() as u64);
+    builder.append_data(&mut header, path, data)
+}
+
+/// Times before the epoch don't fit tar headers, and become the epoch itself
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Parses a PAX timestamp, seconds since the epoch with an optional fraction
+fn parse_time(value: &str) -> Option<SystemTime> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let secs = secs.parse().ok()?;
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        format!("{:0<9}", &fraction[..fraction.len().min(9)])
+            .parse()
+            .ok()?
+    };
+    SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+}
+
+/// Attributes of an archive entry, apart from its inode, size and link count
+fn entry_attr<R: Read>(entry: &mut tar::Entry<'_, R>) -> io::Result<FileAttr> {
+    let header = entry.header();
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+    let mut attr = FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind: FileType::RegularFile,
+        perm: (header.mode()? & 0o7777) as u16,
+        nlink: 1,
+        uid: header.uid()? as u32,
+        gid: header.gid()? as u32,
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    };
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let (Ok(key), Ok(value)) = (extension.key(), extension.value()) else {
+                continue;
+            };
+            let Some(time) = parse_time(value) else {
+                continue;
+            };
+            match key {
+                PAX_ATIME => attr.atime = time,
+                PAX_MTIME => attr.mtime = time,
+                PAX_CTIME => attr.ctime = time,
+                PAX_CRTIME => attr.crtime = time,
+                _ => {}
+            }
+        }
+    }
+    Ok(attr)
+}
+
+/// Path of an archive entry relative to the root, refusing any that would leave it
+fn relative_path<TCache: Cache>(path: &Path) -> Result<PathBuf, ArchiveError<TCache>> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(ArchiveError::InvalidPath(path.to_path_buf()))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Directories and files of an import that haven't been stored yet
+struct Importer<'a, TCache: Cache> {
+    cache: &'a TCache,
+    /// Inodes of the directories found so far, by path
+    dirs: HashMap<PathBuf, Inode>,
+    /// Every directory the import has looked at, as it is to be stored
+    dir_objects: HashMap<Inode, DirectoryObject>,
+    /// Directories changed since the last batch was stored
+    changed: HashSet<Inode>,
+    /// Inodes of the files imported so far, for hard links to them
+    files: HashMap<PathBuf, Inode>,
+    batch: Vec<(Inode, FileObject)>,
+    batch_bytes: usize,
+    stats: ArchiveStats,
+}
+
+impl<'a, TCache: Cache> Importer<'a, TCache> {
+    fn new(cache: &'a TCache) -> Self {
+        Self {
+            cache,
+            dirs: HashMap::from([(PathBuf::new(), FUSE_ROOT_ID)]),
+            dir_objects: HashMap::new(),
+            changed: HashSet::new(),
+            files: HashMap::new(),
+            batch: Vec::new(),
+            batch_bytes: 0,
+            stats: ArchiveStats::default(),
+        }
+    }
+
+    async fn directory(&mut self, path: &Path, attr: FileAttr) -> Result<(), ArchiveError<TCache>> {
+        let ino = self.directory_at(path, attr).await?;
+        let dir = self
+            .dir_objects
+            .get_mut(&ino)
+            .expect("directory was loaded");
+        dir.attr = FileAttr {
+            ino,
+            size: dir.attr.size,
+            blocks: dir.attr.blocks,
+            kind: FileType::Directory,
+            nlink: dir.attr.nlink,
+            ..attr
+        };
+        self.changed.insert(ino);
+        self.stats.directories += 1;
+        Ok(())
+    }
+
+    async fn file(
+        &mut self,
+        path: &Path,
+        attr: FileAttr,
+        data: Vec<u8>,
+    ) -> Result<(), ArchiveError<TCache>> {
+        let (parent, name) = self.parent_of(path, attr).await?;
+        let existing = self.dir_objects[&parent].get_entry_by_name(name).cloned();
+        let (ino, nlink) = match existing {
+            Some(entry) if entry.file_type == FileType::RegularFile => {
+                (entry.ino, self.file_object(entry.ino).await?.attr.nlink)
+            }
+            Some(_) => return Err(ArchiveError::TypeMismatch(path.to_path_buf())),
+            None => {
+                let ino = self.cache.new_inode();
+                self.add_entry(parent, ino, FileType::RegularFile, name);
+                (ino, 1)
+            }
+        };
+        self.stats.files += 1;
+        self.stats.bytes += data.len() as u64;
+        self.batch_bytes += data.len();
+        let file = FileObject {
+            attr: FileAttr {
+                ino,
+                size: data.len() as u64,
+                kind: FileType::RegularFile,
+                nlink,
+                ..attr
+            },
+            name: name.to_string_lossy().to_string(),
+            data,
+        };
+        self.stage(ino, file);
+        self.files.insert(path.to_path_buf(), ino);
+        if self.batch_bytes >= BATCH_BYTES || self.batch.len() >= BATCH_FILES {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn link(&mut self, path: &Path, target: &Path) -> Result<(), ArchiveError<TCache>> {
+        let Some(&ino) = self.files.get(target) else {
+            warn!(path = %path.display(), target = %target.display(), "Skipping link to a file that wasn't imported");
+            self.stats.skipped += 1;
+            return Ok(());
+        };
+        let (parent, name) = self
+            .parent_of(path, self.file_object(ino).await?.attr)
+            .await?;
+        if self.dir_objects[&parent].get_entry_by_name(name).is_some() {
+            return Err(ArchiveError::Exists(path.to_path_buf()));
+        }
+        self.add_entry(parent, ino, FileType::RegularFile, name);
+        let mut file = self.file_object(ino).await?;
+        file.attr.nlink += 1;
+        self.stage(ino, file);
+        self.files.insert(path.to_path_buf(), ino);
+        self.stats.links += 1;
+        Ok(())
+    }
+
+    /// Inode of the directory at `path`, creating it and any missing parents with `attr`
+    /// for now
+    async fn directory_at(
+        &mut self,
+        path: &Path,
+        attr: FileAttr,
+    ) -> Result<Inode, ArchiveError<TCache>> {
+        let mut ino = FUSE_ROOT_ID;
+        let mut walked = PathBuf::new();
+        self.load_dir(ino).await?;
+        for name in path.iter() {
+            walked.push(name);
+            if let Some(&found) = self.dirs.get(&walked) {
+                ino = found;
+                continue;
+            }
+            let existing = self.dir_objects[&ino].get_entry_by_name(name).cloned();
+            ino = match existing {
+                Some(entry) if entry.file_type == FileType::Directory => {
+                    self.load_dir(entry.ino).await?;
+                    entry.ino
+                }
+                Some(_) => return Err(ArchiveError::TypeMismatch(walked)),
+                None => self.create_directory(ino, name, attr),
+            };
+            self.dirs.insert(walked.clone(), ino);
+        }
+        Ok(ino)
+    }
+
+    /// Directory a path is in, created if it's missing, and the path's last component
+    async fn parent_of<'p>(
+        &mut self,
+        path: &'p Path,
+        attr: FileAttr,
+    ) -> Result<(Inode, &'p OsStr), ArchiveError<TCache>> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| ArchiveError::InvalidPath(path.to_path_buf()))?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        // Directories an archive leaves out are searchable, unlike most files
+        let attr = FileAttr {
+            perm: attr.perm | 0o700,
+            ..attr
+        };
+        Ok((self.directory_at(parent, attr).await?, name))
+    }
+
+    fn create_directory(&mut self, parent: Inode, name: &OsStr, attr: FileAttr) -> Inode {
+        let ino = self.cache.new_inode();
+        let name = name.to_string_lossy().to_string();
+        debug!(%parent, %ino, %name, "Creating directory");
+        let entries = HashSet::from([
+            DirectoryEntry {
+                ino,
+                file_type: FileType::Directory,
+                name: String::from("."),
+            },
+            DirectoryEntry {
+                ino: parent,
+                file_type: FileType::Directory,
+                name: String::from(".."),
+            },
+        ]);
+        let dir = DirectoryObject {
+            attr: FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                kind: FileType::Directory,
+                nlink: 2,
+                ..attr
+            },
+            entries,
+            name: name.clone(),
+        };
+        self.dir_objects.insert(ino, dir);
+        self.changed.insert(ino);
+        // The new directory's `..`
+        self.dir_objects
+            .get_mut(&parent)
+            .expect("parent was loaded")
+            .attr
+            .nlink += 1;
+        self.add_entry(parent, ino, FileType::Directory, OsStr::new(&name));
+        ino
+    }
+
+    fn add_entry(&mut self, parent: Inode, ino: Inode, file_type: FileType, name: &OsStr) {
+        let dir = self
+            .dir_objects
+            .get_mut(&parent)
+            .expect("parent was loaded");
+        dir.entries.insert(DirectoryEntry {
+            ino,
+            file_type,
+            name: name.to_string_lossy().to_string(),
+        });
+        self.changed.insert(parent);
+    }
+
+    /// Reads a directory of the filesystem into `dir_objects`, unless it's there already
+    async fn load_dir(&mut self, ino: Inode) -> Result<(), ArchiveError<TCache>> {
+        if self.dir_objects.contains_key(&ino) {
+            return Ok(());
+        }
+        let obj = self.cache.get(ino).await.map_err(ArchiveError::Cache)?;
+        let dir = obj.and_then(
+            |obj| match &*obj.read().unwrap_or_else(PoisonError::into_inner) {
+                FileSystemObject::Dir(dir) => Some(dir.clone()),
+                FileSystemObject::File(_) => None,
+            },
+        );
+        let Some(dir) = dir else {
+            return Err(ArchiveError::Missing(ino));
+        };
+        self.dir_objects.insert(ino, dir);
+        Ok(())
+    }
+
+    /// A file as it is to be stored, whether it's still in the batch or already stored
+    async fn file_object(&self, ino: Inode) -> Result<FileObject, ArchiveError<TCache>> {
+        if let Some((_, file)) = self.batch.iter().find(|(staged, _)| *staged == ino) {
+            return Ok(file.clone());
+        }
+        let obj = self.cache.get(ino).await.map_err(ArchiveError::Cache)?;
+        obj.and_then(
+            |obj| match &*obj.read().unwrap_or_else(PoisonError::into_inner) {
+                FileSystemObject::File(file) => Some(file.clone()),
+                FileSystemObject::Dir(_) => None,
+            },
+        )
+        .ok_or(ArchiveError::Missing(ino))
+    }
+
+    fn stage(&mut self, ino: Inode, file: FileObject) {
+        match self.batch.iter_mut().find(|(staged, _)| *staged == ino) {
+            Some((_, staged)) => *staged = file,
+            None => self.batch.push((ino, file)),
+        }
+    }
+
+    /// Stores the batch and the directories changed along with it as one transaction
+    async fn flush(&mut self) -> Result<(), ArchiveError<TCache>> {
+        let mut items: Vec<_> = self
+            .batch
+            .drain(..)
+            .map(|(ino, file)| (ino, FileSystemObject::File(file)))
+            .collect();
+        items.extend(
+            self.changed
+                .drain()
+                .map(|ino| (ino, FileSystemObject::Dir(self.dir_objects[&ino].clone()))),
+        );
+        if items.is_empty() {
+            return Ok(());
+        }
+        debug!(
+            number_of_objects = items.len(),
+            bytes = self.batch_bytes,
+            "Storing imported objects"
+        );
+        self.batch_bytes = 0;
+        self.cache
+            .insert_all(items)
+            .await
+            .map_err(ArchiveError::Cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import, ArchiveStats};
+    use crate::{
+        cache::{memory_cache, CacheConfig, WhenFSCache},
+        calendar::memory::MemoryClient,
+        fs::WhenFS,
+        store::CalStore,
+    };
+    use std::{path::Path, time::SystemTime};
+
+    async fn empty_fs() -> WhenFSCache<CalStore<MemoryClient>> {
+        let cache = memory_cache(CacheConfig::default()).await;
+        WhenFS::create_root(&cache).await.unwrap();
+        cache
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_round_trip() {
+        let mut archive = tar::Builder::new(Vec::new());
+        let mut add = |path: &str, kind: tar::EntryType, mode: u32, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(mode);
+            header.set_uid(1000);
+            header.set_gid(100);
+            header.set_mtime(1_700_000_000);
+            header.set_size(data.len() as u64);
+            archive.append_data(&mut header, path, data).unwrap();
+        };
+        add("./docs/", tar::EntryType::Directory, 0o750, b"");
+        add("./docs/notes.txt", tar::EntryType::Regular, 0o600, b"notes");
+        // The parent of this one isn't in the archive
+        add(
+            "./src/deep/main.rs",
+            tar::EntryType::Regular,
+            0o644,
+            b"fn main() {}",
+        );
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        archive
+            .append_link(&mut header, "./notes-link", "./docs/notes.txt")
+            .unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let cache = empty_fs().await;
+        let imported = import(&cache, &archive[..]).await.unwrap();
+        assert_eq!(
+            imported,
+            ArchiveStats {
+                directories: 1,
+                files: 2,
+                links: 1,
+                bytes: 17,
+                skipped: 0,
+            }
+        );
+        let mut exported = Vec::new();
+        export(&cache, &mut exported).await.unwrap();
+
+        let copy = empty_fs().await;
+        import(&copy, &exported[..]).await.unwrap();
+        let fs = WhenFS::new(copy, tokio::runtime::Handle::current())
+            .await
+            .unwrap();
+        let notes = fs.resolve(0, 0, Path::new("docs/notes.txt")).await.unwrap();
+        assert_eq!(fs.read(0, 0, notes).await.unwrap(), b"notes");
+        let attr = fs.stat(notes).await.unwrap();
+        assert_eq!(
+            (attr.perm, attr.uid, attr.gid, attr.nlink),
+            (0o600, 1000, 100, 2)
+        );
+        assert_eq!(
+            attr.mtime,
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)
+        );
+        let link = fs.resolve(0, 0, Path::new("notes-link")).await.unwrap();
+        assert_eq!(link, notes);
+        let docs = fs.resolve(0, 0, Path::new("docs")).await.unwrap();
+        assert_eq!(fs.stat(docs).await.unwrap().perm, 0o750);
+        let main = fs
+            .resolve(0, 0, Path::new("src/deep/main.rs"))
+            .await
+            .unwrap();
+        assert_eq!(fs.read(0, 0, main).await.unwrap(), b"fn main() {}");
+        let root = fs.list(0, 0, 1).await.unwrap();
+        let mut names: Vec<_> = root.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["WelcomeToWhenFS", "docs", "notes-link", "src"]);
+        // `docs` and `src` hang off the root
+        assert_eq!(fs.stat(1).await.unwrap().nlink, 4);
+    }
+}
@@ -1,158 +1,1839 @@
-use crate::store::Store;
+use crate::store::{LayoutOverrides, Store};
 use crate::{object::FileSystemObject, store::RecoveryDetails};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clean::{Budget, CleanObjects};
 use dashmap::DashMap;
+use disk::{DiskCache, DiskCacheConfig};
+use futures::future::join_all;
+use journal::{Journal, Record};
+use locks::InodeLocks;
+use lru::LruCache;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc, RwLock,
+    Arc, Mutex, PoisonError, RwLock,
 };
-use tracing::{debug, info};
+use std::time::{Duration, Instant};
+use superblock::{Snapshot, Superblock, SuperblockError, SUPERBLOCK_NAME};
+use table::InodeTable;
+use tokio::{
+    sync::{Notify, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{debug, info, trace, warn};
+
+pub mod clean;
+pub mod disk;
+pub mod fsck;
+pub mod journal;
+pub mod locks;
+pub mod superblock;
+pub mod table;
 
 pub type Inode = u64;
+
+/// Number of objects read from snapshots that are kept in memory
+const SNAPSHOT_OBJECTS: NonZeroUsize = NonZeroUsize::new(64).unwrap();
+/// Shortest and longest wait before the flusher retries after a failed flush
+const MIN_FLUSH_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_FLUSH_BACKOFF: Duration = Duration::from_secs(300);
 pub type CachedWhenFSObject = Arc<RwLock<FileSystemObject>>;
 
-#[async_trait(?Send)]
+#[async_trait]
 pub trait Cache {
     type Error: Send + Sync + std::fmt::Debug + std::error::Error;
 
     async fn get(&self, ino: Inode) -> Result<Option<CachedWhenFSObject>, Self::Error>;
 
-    async fn insert(&mut self, ino: Inode, item: FileSystemObject) -> Result<Inode, Self::Error>;
+    async fn insert(&self, ino: Inode, item: FileSystemObject) -> Result<Inode, Self::Error>;
+
+    /// Changes several objects as one transaction: after a crash or error, either all of
+    /// the changes are stored or none are
+    async fn insert_all(&self, items: Vec<(Inode, FileSystemObject)>) -> Result<(), Self::Error>;
+
+    /// Drops inodes and changes objects as one transaction, e.g. a file and the directory it
+    /// was unlinked from. Takes effect in the store right away, even with write-back.
+    async fn remove_all(
+        &self,
+        items: Vec<(Inode, FileSystemObject)>,
+        removed: Vec<Inode>,
+    ) -> Result<(), Self::Error>;
+
+    /// Writes buffered changes out to the store, either for every inode or just for `only`
+    async fn flush(&self, only: Option<Inode>) -> Result<(), Self::Error>;
+
+    /// Keeps an inode's object in memory until a matching `close`
+    fn open(&self, ino: Inode);
+
+    fn close(&self, ino: Inode);
+
+    /// Starts fetching objects in the background, e.g. the children of a directory that
+    /// was just listed
+    fn prefetch(&self, inos: Vec<Inode>);
 
     fn new_inode(&self) -> Inode;
 
     fn get_recovery_id(&self) -> RecoveryDetails;
+
+    /// Snapshots of the filesystem, oldest first
+    fn snapshots(&self) -> Vec<SnapshotInfo>;
+
+    /// Reads an object as it was when the snapshot at `snapshot` in `snapshots` was taken
+    async fn get_snapshot(
+        &self,
+        snapshot: usize,
+        ino: Inode,
+    ) -> Result<Option<CachedWhenFSObject>, Self::Error>;
+
+    fn stats(&self) -> CacheStats;
+}
+
+/// Name and time of a snapshot, without where it's stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Buffer changes in memory and flush them in the background, instead of writing
+    /// every change through to the store
+    pub write_back: Option<WriteBack>,
+    /// Evict unchanged objects once they take up more than this many bytes
+    pub max_cached_bytes: Option<usize>,
+    /// Evict unchanged objects once there are more than this many
+    pub max_cached_objects: Option<usize>,
+    /// Keep downloaded objects on disk, so later mounts don't download them again
+    pub disk: Option<DiskCacheConfig>,
+    pub prefetch: Option<Prefetch>,
+    /// Local journal of transactions, to clean up after crashes
+    pub journal: Option<PathBuf>,
+    /// Mount the snapshot with this name instead of the current root. Nothing may be
+    /// changed while a snapshot is mounted.
+    pub snapshot: Option<String>,
+    /// Mount without writing anything to the store: leftovers of a crash stay where they
+    /// are, and a filesystem without a superblock doesn't get one. Nothing may be changed
+    /// either, as with a snapshot.
+    pub read_only: bool,
+    /// Layout settings to write new objects with instead of the filesystem's
+    pub layout: LayoutOverrides,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            write_back: None,
+            max_cached_bytes: Some(256 * 1024 * 1024),
+            max_cached_objects: None,
+            disk: None,
+            prefetch: Some(Prefetch::default()),
+            journal: None,
+            snapshot: None,
+            read_only: false,
+            layout: LayoutOverrides::default(),
+        }
+    }
+}
+
+/// Fetching objects before they are asked for. Prefetching stops as soon as cached objects
+/// would have to be evicted to make room.
+#[derive(Clone, Copy, Debug)]
+pub struct Prefetch {
+    /// Maximum number of objects fetched at the same time
+    pub concurrency: usize,
+    /// Maximum number of objects fetched per request, e.g. children of one directory
+    pub max_objects: usize,
+}
+
+impl Default for Prefetch {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            max_objects: 64,
+        }
+    }
+}
+
+/// Counters of how well cached objects are serving reads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Misses served from the disk cache instead of the store
+    pub disk_hits: u64,
+    /// Objects fetched before they were asked for
+    pub prefetched: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WriteBack {
+    /// How long a change may sit in memory before it is flushed
+    pub delay: Duration,
+    /// Flush everything early once this many bytes of changed objects pile up
+    pub max_dirty_bytes: usize,
+}
+
+impl WriteBack {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            max_dirty_bytes: 8 * 1024 * 1024,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct WhenFSCache<TStore: Store> {
-    ino_to_id: DashMap<Inode, TStore::Entry>,
-    id_to_obj: DashMap<TStore::Entry, CachedWhenFSObject>,
+    shared: Arc<Shared<TStore>>,
+    flusher: Option<JoinHandle<()>>,
+    prefetch: Option<Prefetch>,
+}
+
+/// Cache state, shared with the background flusher
+#[derive(Debug)]
+struct Shared<TStore: Store> {
+    /// Only written to switch in a change that is already stored, so lookups never wait on
+    /// the calendar
+    table: tokio::sync::RwLock<InodeTable<TStore>>,
+    /// Held while changing the inode table or the superblock, so changes are prepared
+    /// against the table they will be applied to
+    committing: tokio::sync::Mutex<()>,
+    /// Superblock as last stored, pointing at the root of the inode table. Only changed
+    /// while `committing` is held.
+    superblock: RwLock<Superblock<TStore::Entry>>,
+    /// Where the superblock is stored, which never changes
+    superblock_id: TStore::Entry,
+    /// Objects that snapshots refer to, which are never deleted
+    snapshotted: Mutex<HashSet<TStore::Entry>>,
+    /// Inode tables of the snapshots read from so far, by position in the superblock
+    snapshot_tables: tokio::sync::Mutex<HashMap<usize, InodeTable<TStore>>>,
+    /// Objects read from snapshots. They never change, so they're cached by entry.
+    snapshot_objects: Mutex<LruCache<TStore::Entry, CachedWhenFSObject>>,
+    /// Objects as they were last stored. Dirty objects are kept in `dirty` instead.
+    clean: Mutex<CleanObjects<TStore::Entry>>,
+    /// Number of open handles per inode, whose objects are never evicted
+    open: DashMap<Inode, usize>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    disk: Option<DiskCache>,
+    disk_hits: AtomicU64,
+    prefetch_permits: Semaphore,
+    /// Inodes with a prefetch queued or running
+    prefetching: DashMap<Inode, ()>,
+    /// Bumped to cancel every prefetch started before
+    prefetch_epoch: AtomicU64,
+    prefetched: AtomicU64,
+    /// Changed objects that haven't been stored yet, at most one per inode
+    dirty: DashMap<Inode, Dirty>,
+    dirty_generation: AtomicU64,
+    /// Held while flushing, so that versions of an inode reach the table in order
+    flushing: tokio::sync::Mutex<()>,
+    /// Held while storing an inode, so its versions reach the table in order
+    writing: InodeLocks,
+    journal: Option<Journal>,
+    wake_flusher: Notify,
     inode_count: AtomicU64,
     store: TStore,
-    root_event: TStore::Entry,
 }
 
-impl<TStore: Store> WhenFSCache<TStore> {
-    pub async fn new(store: TStore) -> Result<Self, <Self as Cache>::Error> {
-        let ino_to_id = DashMap::new();
-        let root_event = store.store(&ino_to_id, "root event".to_string()).await?;
-        let this = Self {
-            inode_count: AtomicU64::new(fuser::FUSE_ROOT_ID + 1),
-            ino_to_id,
-            id_to_obj: DashMap::new(),
-            store,
-            root_event,
+#[derive(Debug)]
+struct Dirty {
+    object: CachedWhenFSObject,
+    /// Tells versions apart, so a flush doesn't clear a change that arrived during it
+    generation: u64,
+    /// When the inode first became dirty; later changes don't push its flush back
+    since: Instant,
+    size: usize,
+}
+
+impl<TStore: Store + 'static> WhenFSCache<TStore> {
+    pub async fn new(store: TStore, config: CacheConfig) -> Result<Self, <Self as Cache>::Error> {
+        let table = InodeTable::create(&store).await?;
+        let superblock = Superblock::new(store.layout(), table.root().clone());
+        let superblock_id = store
+            .store_fixed(&superblock, SUPERBLOCK_NAME.to_string())
+            .await?;
+        info!(uuid = %superblock.uuid, "Created filesystem");
+        let journal = match &config.journal {
+            Some(path) => Self::open_journal(path.clone(), table.root().clone())
+                .await
+                .map(|(journal, _)| journal),
+            None => None,
         };
+        Ok(Self::start(
+            store,
+            table,
+            superblock,
+            superblock_id,
+            HashSet::new(),
+            config,
+            journal,
+        ))
+    }
 
-        Ok(this)
+    /// Finds the superblock of the filesystem in the store, if it has one. Filesystems from
+    /// before superblocks can't be found this way.
+    pub async fn find_superblock(
+        store: &TStore,
+    ) -> Result<Option<TStore::Entry>, SuperblockError<TStore::Error>> {
+        let mut found = store
+            .find_fixed(SUPERBLOCK_NAME)
+            .await
+            .map_err(SuperblockError::Store)?;
+        if found.len() > 1 {
+            return Err(SuperblockError::Ambiguous(
+                found
+                    .iter()
+                    .map(|entry| store.get_raw_id(entry).root_id)
+                    .collect(),
+            ));
+        }
+        Ok(found.pop())
     }
 
+    /// Mounts the filesystem whose superblock is stored under `superblock_id`, or one of its
+    /// snapshots if the config names one. An inode table root written before superblocks
+    /// existed is accepted too, and gets a new superblock pointing at it.
     pub async fn recover(
-        store: TStore,
-        root_id: TStore::Entry,
-    ) -> Result<Self, <Self as Cache>::Error> {
+        mut store: TStore,
+        superblock_id: TStore::Entry,
+        config: CacheConfig,
+    ) -> Result<Self, SuperblockError<TStore::Error>> {
         debug!("Attempting cache recovery");
-        let ino_to_id: DashMap<u64, TStore::Entry> = store.retrieve(root_id.clone()).await?;
+        let read_only = config.read_only || config.snapshot.is_some();
+        let found: serde_json::Value = store
+            .retrieve(superblock_id.clone())
+            .await
+            .map_err(SuperblockError::Store)?;
+        let (mut superblock, superblock_id) = match Superblock::parse(found)? {
+            Some(superblock) => (superblock, superblock_id),
+            // Good enough to read with, since it's never written
+            None if read_only => (
+                Superblock::new(store.layout(), superblock_id.clone()),
+                superblock_id,
+            ),
+            None => {
+                let superblock = Superblock::new(store.layout(), superblock_id);
+                let superblock_id = store
+                    .store_fixed(&superblock, SUPERBLOCK_NAME.to_string())
+                    .await
+                    .map_err(SuperblockError::Store)?;
+                warn!(
+                    superblock = store.get_raw_id(&superblock_id).root_id,
+                    "Wrote a superblock for a filesystem that had none, mount it from there \
+                     from now on"
+                );
+                (superblock, superblock_id)
+            }
+        };
+        info!(
+            uuid = %superblock.uuid,
+            version = superblock.version,
+            created = %superblock.created,
+            "Found filesystem"
+        );
+        // New objects are written like the rest of the filesystem unless told otherwise, and
+        // nothing is written without being able to change
+        let overrides = match read_only {
+            true => LayoutOverrides::default(),
+            false => config.layout,
+        };
+        store.set_layout(&overrides.apply(superblock.layout));
+        if overrides != LayoutOverrides::default() && superblock.layout != store.layout() {
+            info!(
+                previous = ?superblock.layout,
+                current = ?store.layout(),
+                "Writing new objects with a different layout"
+            );
+            if superblock.layout.erasure.is_some() && store.layout().erasure.is_none() {
+                warn!("New objects are written without parity and won't survive lost events");
+            }
+            superblock.set_layout(store.layout());
+        }
+        let root = match &config.snapshot {
+            Some(name) => {
+                let snapshot = superblock
+                    .snapshot(name)
+                    .ok_or_else(|| SuperblockError::NoSuchSnapshot(name.clone()))?;
+                info!(%name, created = %snapshot.created, "Mounting snapshot");
+                snapshot.root.clone()
+            }
+            None => superblock.root.clone(),
+        };
+        let table = InodeTable::load(&store, root.clone())
+            .await
+            .map_err(SuperblockError::Store)?;
         debug!("Recovered inode mapping");
-        let inode_count = ino_to_id
-            .iter()
-            .map(|entry| *entry.key())
-            .max()
-            .expect("Couldn't find inode count")
-            + 1;
+        let snapshotted = Self::snapshotted(&store, &superblock)
+            .await
+            .map_err(SuperblockError::Store)?;
+        // The journal belongs to the current root, which a read-only mount never changes
+        let journal = match config.journal.as_ref().filter(|_| !read_only) {
+            Some(path) => match Self::open_journal(path.clone(), root.clone()).await {
+                Some((journal, records)) => {
+                    Self::recover_journal(&store, records, &root, &snapshotted).await;
+                    Some(journal)
+                }
+                None => None,
+            },
+            None => None,
+        };
         info!("Recovered filesystem cache");
-        Ok(Self {
-            ino_to_id,
-            id_to_obj: DashMap::new(),
+        Ok(Self::start(
+            store,
+            table,
+            superblock,
+            superblock_id,
+            snapshotted,
+            config,
+            journal,
+        ))
+    }
+
+    /// Every object the snapshots in a superblock refer to
+    async fn snapshotted(
+        store: &TStore,
+        superblock: &Superblock<TStore::Entry>,
+    ) -> Result<HashSet<TStore::Entry>, TStore::Error> {
+        let mut snapshotted = HashSet::new();
+        for snapshot in &superblock.snapshots {
+            let table = InodeTable::load(store, snapshot.root.clone()).await?;
+            snapshotted.extend(table.reachable().cloned());
+        }
+        debug!(
+            number_of_snapshots = superblock.snapshots.len(),
+            number_of_objects = snapshotted.len(),
+            "Found objects kept by snapshots"
+        );
+        Ok(snapshotted)
+    }
+
+    /// Opens the journal and restarts it from `root`, returning the records it had
+    async fn open_journal(
+        path: PathBuf,
+        root: TStore::Entry,
+    ) -> Option<(Journal, Vec<Record<TStore::Entry>>)> {
+        let opened = async {
+            let (journal, records) = Journal::open(path).await?;
+            journal.restart(root).await?;
+            std::io::Result::Ok((journal, records))
+        };
+        match opened.await {
+            Ok(opened) => Some(opened),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                // Its records belong to transactions the other process may still be running
+                warn!("Journal is in use by another process, continuing without it");
+                None
+            }
+            Err(error) => {
+                warn!(%error, "Couldn't open journal, continuing without it");
+                None
+            }
+        }
+    }
+
+    /// Deletes what transactions that were cut short by a crash left behind
+    async fn recover_journal(
+        store: &TStore,
+        records: Vec<Record<TStore::Entry>>,
+        root: &TStore::Entry,
+        snapshotted: &HashSet<TStore::Entry>,
+    ) {
+        if records.is_empty() {
+            return;
+        }
+        // The mounted root may only name the last event of the root object
+        let root_id = store.get_raw_id(root).root_id;
+        let is_root = |entry: &TStore::Entry| store.get_raw_id(entry).root_id == root_id;
+        let Some(recovery) = Journal::recover(records, is_root) else {
+            warn!("Journal doesn't mention the mounted root, ignoring it");
+            return;
+        };
+        info!(
+            replayed = recovery.replay.len(),
+            rolled_back = recovery.rollback.len(),
+            "Recovering transactions from journal"
+        );
+        let replay = recovery
+            .replay
+            .into_iter()
+            .filter(|entry| !snapshotted.contains(entry));
+        for entry in replay.chain(recovery.rollback) {
+            if let Err(error) = store.delete(entry).await {
+                warn!(%error, "Failed to delete object left behind by a transaction");
+            }
+        }
+    }
+
+    fn start(
+        store: TStore,
+        table: InodeTable<TStore>,
+        superblock: Superblock<TStore::Entry>,
+        superblock_id: TStore::Entry,
+        snapshotted: HashSet<TStore::Entry>,
+        config: CacheConfig,
+        journal: Option<Journal>,
+    ) -> Self {
+        let inode_count = table
+            .max_inode()
+            .map_or(fuser::FUSE_ROOT_ID + 1, |max| max + 1);
+        let shared = Arc::new(Shared {
+            table: tokio::sync::RwLock::new(table),
+            committing: tokio::sync::Mutex::new(()),
+            superblock: RwLock::new(superblock),
+            superblock_id,
+            snapshotted: Mutex::new(snapshotted),
+            snapshot_tables: tokio::sync::Mutex::new(HashMap::new()),
+            snapshot_objects: Mutex::new(LruCache::new(SNAPSHOT_OBJECTS)),
+            clean: Mutex::new(CleanObjects::new(Budget {
+                max_bytes: config.max_cached_bytes,
+                max_objects: config.max_cached_objects,
+            })),
+            open: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            disk: config.disk.and_then(|disk| match DiskCache::open(disk) {
+                Ok(disk) => Some(disk),
+                Err(error) => {
+                    warn!(%error, "Couldn't open disk cache, continuing without it");
+                    None
+                }
+            }),
+            disk_hits: AtomicU64::new(0),
+            prefetch_permits: Semaphore::new(config.prefetch.map_or(0, |p| p.concurrency)),
+            prefetching: DashMap::new(),
+            prefetch_epoch: AtomicU64::new(0),
+            prefetched: AtomicU64::new(0),
+            dirty: DashMap::new(),
+            dirty_generation: AtomicU64::new(0),
+            flushing: tokio::sync::Mutex::new(()),
+            writing: InodeLocks::default(),
+            journal,
+            wake_flusher: Notify::new(),
             inode_count: inode_count.into(),
             store,
-            root_event: root_id,
-        })
+        });
+        let flusher = config.write_back.map(|write_back| {
+            info!(?write_back, "Starting write-back flusher");
+            tokio::spawn(Arc::clone(&shared).run_flusher(write_back))
+        });
+        Self {
+            shared,
+            flusher,
+            prefetch: config.prefetch,
+        }
     }
 }
 
-#[async_trait(?Send)]
-impl<TStore: Store> Cache for WhenFSCache<TStore> {
-    type Error = TStore::Error;
+impl<TStore: Store + 'static> WhenFSCache<TStore> {
+    /// Recreates lost redundant events of the inode table and of every object in it,
+    /// returning how many events were recreated.
+    pub async fn repair(&self) -> Result<usize, <Self as Cache>::Error> {
+        self.flush(None).await?;
+        let table = self.shared.table.read().await;
+        let mut repaired = 0;
+        for entry in table.metadata() {
+            repaired += self.shared.store.repair(entry.clone()).await?;
+        }
+        for (ino, entry) in table.iter() {
+            let recreated = self.shared.store.repair(entry.clone()).await?;
+            if recreated > 0 {
+                info!(%ino, recreated, "Repaired inode");
+            }
+            repaired += recreated;
+        }
+        Ok(repaired)
+    }
 
-    async fn get(&self, ino: Inode) -> Result<Option<CachedWhenFSObject>, TStore::Error> {
-        if let Some(id) = self.ino_to_id.get(&ino) {
-            let cached = match self.id_to_obj.get(&id) {
-                Some(cached) => Arc::clone(&cached),
-                None => {
-                    let retrieved = Arc::new(RwLock::new(self.store.retrieve(id.clone()).await?));
-                    self.id_to_obj.insert(id.clone(), retrieved.clone());
-                    retrieved
+    /// Deletes every event that isn't part of the inode table or an object in it. Nothing
+    /// else may be writing to the filesystem while this runs.
+    ///
+    /// Refuses to run if the calendar holds any other filesystem, as everything of it would
+    /// look unreachable.
+    pub async fn sweep(&self) -> Result<usize, SuperblockError<TStore::Error>> {
+        let store = &self.shared.store;
+        let superblock_id = store.get_raw_id(&self.shared.superblock_id).root_id;
+        let superblocks: Vec<String> = store
+            .find_fixed(SUPERBLOCK_NAME)
+            .await
+            .map_err(SuperblockError::Store)?
+            .iter()
+            .map(|entry| store.get_raw_id(entry).root_id)
+            .collect();
+        if superblocks.iter().any(|id| *id != superblock_id) {
+            return Err(SuperblockError::Ambiguous(superblocks));
+        }
+        self.flush(None).await.map_err(SuperblockError::Store)?;
+        let live = {
+            let table = self.shared.table.read().await;
+            let snapshotted = self.shared.snapshotted();
+            table
+                .reachable()
+                .chain(snapshotted.iter())
+                .chain([&self.shared.superblock_id])
+                .cloned()
+                .collect()
+        };
+        store.sweep(live).await.map_err(SuperblockError::Store)
+    }
+
+    /// Superblock as last stored
+    pub fn superblock(&self) -> Superblock<TStore::Entry> {
+        self.shared.superblock().clone()
+    }
+
+    /// Number of inodes in the inode table
+    pub async fn inode_count(&self) -> usize {
+        self.shared.table.read().await.iter().count()
+    }
+
+    /// Records the current root under `name`. Everything it refers to is kept from then on,
+    /// even once files change.
+    pub async fn snapshot(
+        &self,
+        name: String,
+    ) -> Result<Snapshot<TStore::Entry>, SuperblockError<TStore::Error>> {
+        self.flush(None).await.map_err(SuperblockError::Store)?;
+        let _committing = self.shared.committing.lock().await;
+        let mut superblock = self.shared.superblock().clone();
+        // Another process may have changed the filesystem since it was opened here, so the
+        // snapshot is of the root as stored
+        superblock.root = self
+            .shared
+            .merge_snapshots(&mut superblock)
+            .await
+            .map_err(SuperblockError::Store)?;
+        let snapshot = superblock
+            .add_snapshot(name.clone())
+            .ok_or(SuperblockError::SnapshotExists(name))?
+            .clone();
+        let reachable: Vec<TStore::Entry> = {
+            let table = self.shared.table.read().await;
+            if *table.root() == snapshot.root {
+                table.reachable().cloned().collect()
+            } else {
+                InodeTable::load(&self.shared.store, snapshot.root.clone())
+                    .await
+                    .map_err(SuperblockError::Store)?
+                    .reachable()
+                    .cloned()
+                    .collect()
+            }
+        };
+        self.shared
+            .store
+            .overwrite(&self.shared.superblock_id, &superblock)
+            .await
+            .map_err(SuperblockError::Store)?;
+        self.shared.snapshotted().extend(reachable);
+        *self
+            .shared
+            .superblock
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = superblock;
+        info!(name = %snapshot.name, "Created snapshot");
+        Ok(snapshot)
+    }
+}
+
+impl<TStore: Store> Shared<TStore> {
+    /// Stores objects and points their inodes at them, and drops the `removed` inodes, as one
+    /// transaction. The superblock is switched to a new inode table root once, after
+    /// everything it refers to is stored, so either all of the changes take effect or none do.
+    async fn commit(
+        &self,
+        items: Vec<(Inode, FileSystemObject)>,
+        removed: Vec<Inode>,
+    ) -> Result<(), TStore::Error> {
+        let _writing = self
+            .writing
+            .lock_all(
+                items
+                    .iter()
+                    .map(|(ino, _)| *ino)
+                    .chain(removed.iter().copied())
+                    .collect(),
+            )
+            .await;
+        let txn = self.journal.as_ref().map(Journal::begin);
+        let stored = join_all(
+            items
+                .iter()
+                .map(|(_, item)| self.store.store(item, item.name().to_string())),
+        )
+        .await;
+        let mut ids = Vec::with_capacity(stored.len());
+        let mut failed = None;
+        for stored in stored {
+            match stored {
+                Ok(id) => ids.push(id),
+                Err(error) => failed = Some(error),
+            }
+        }
+        if let Some(error) = failed {
+            self.abort(txn, ids).await;
+            return Err(error);
+        }
+        if let Some(txn) = txn {
+            self.record(Record::Prepared {
+                txn,
+                written: ids.clone(),
+            })
+            .await;
+        }
+        if let Some(disk) = &self.disk {
+            for ((_, item), id) in items.iter().zip(&ids) {
+                disk.put(id, item).await;
+            }
+        }
+
+        let committing = self.committing.lock().await;
+        // Only commits change the table, so it can be read while the change is stored
+        // without holding up lookups
+        let changes = items
+            .iter()
+            .map(|(ino, _)| *ino)
+            .zip(ids.iter().cloned().map(Some))
+            .chain(removed.iter().map(|ino| (*ino, None)))
+            .collect();
+        let prepared = self.table.read().await.prepare(&self.store, changes).await;
+        let pending = match prepared {
+            Ok(pending) => pending,
+            Err(error) => {
+                drop(committing);
+                self.abort(txn, ids).await;
+                return Err(error);
+            }
+        };
+        let mut superblock = self.superblock().clone();
+        if let Err(error) = self.merge_snapshots(&mut superblock).await {
+            drop(committing);
+            self.abort(txn, ids.into_iter().chain(pending.written()).collect())
+                .await;
+            return Err(error);
+        }
+        superblock.root = pending.root().clone();
+        // Recorded before the switch and while `committing` is held, so commits are
+        // journaled in order and a crash during the switch can be rolled back
+        if let Some(txn) = txn {
+            self.record(Record::Prepared {
+                txn,
+                written: pending.written(),
+            })
+            .await;
+            self.record(Record::Committed {
+                txn,
+                root: superblock.root.clone(),
+                garbage: pending
+                    .switch
+                    .superseded
+                    .iter()
+                    .chain(&pending.switch.garbage)
+                    .cloned()
+                    .collect(),
+            })
+            .await;
+        }
+        // Overwriting the superblock is what makes the transaction take effect
+        if let Err(error) = self.store.overwrite(&self.superblock_id, &superblock).await {
+            // The overwrite may have gone through even though it failed, e.g. if only the
+            // response was lost, and then deleting what it refers to would break the
+            // filesystem
+            let stored = self
+                .store
+                .retrieve::<Superblock<TStore::Entry>>(self.superblock_id.clone())
+                .await;
+            match stored {
+                Ok(stored) if stored.root == *pending.root() => {
+                    warn!(%error, "Superblock was overwritten despite failing, committing anyway");
                 }
-            };
-            Ok(Some(cached))
+                Ok(_) => {
+                    drop(committing);
+                    self.abort(txn, ids.into_iter().chain(pending.written()).collect())
+                        .await;
+                    return Err(error);
+                }
+                Err(read_error) => {
+                    // Recovering the journal or collecting garbage cleans up either way
+                    warn!(
+                        %error,
+                        %read_error,
+                        "Couldn't tell whether the superblock was overwritten, keeping the \
+                         transaction's objects"
+                    );
+                    return Err(error);
+                }
+            }
+        }
+        *self
+            .superblock
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = superblock;
+        let switch = {
+            let mut table = self.table.write().await;
+            let switch = table.apply(pending);
+            for ((ino, item), id) in items.into_iter().zip(ids) {
+                let size = item.size();
+                self.cache_clean(ino, id, Arc::new(RwLock::new(item)), size);
+            }
+            let mut clean = self.clean();
+            for ino in &removed {
+                clean.remove(*ino);
+            }
+            switch
+        };
+        drop(committing);
+        trace!(?txn, "Committed transaction");
+
+        // Only drop old objects once the inode table referencing their successors is stored
+        if let Some(disk) = &self.disk {
+            for superseded in &switch.superseded {
+                disk.remove(superseded).await;
+            }
+        }
+        self.delete_all(switch.superseded, "superseded object")
+            .await;
+        self.delete_all(switch.garbage, "superseded inode table object")
+            .await;
+        if let Some(txn) = txn {
+            self.record(Record::Done { txn }).await;
+        }
+        Ok(())
+    }
+
+    /// Adds snapshots that another process stored since the superblock was last read here,
+    /// so overwriting it doesn't drop them, and keeps what they refer to from being deleted.
+    /// Returns the root as stored.
+    async fn merge_snapshots(
+        &self,
+        superblock: &mut Superblock<TStore::Entry>,
+    ) -> Result<TStore::Entry, TStore::Error> {
+        let stored: Superblock<TStore::Entry> =
+            self.store.retrieve(self.superblock_id.clone()).await?;
+        let root = stored.root.clone();
+        for snapshot in superblock.merge_snapshots(stored) {
+            let table = InodeTable::load(&self.store, snapshot.root).await?;
+            self.snapshotted().extend(table.reachable().cloned());
+            info!(name = %snapshot.name, "Found snapshot created by another process");
+        }
+        Ok(root)
+    }
+
+    /// Deletes what a failed transaction wrote
+    async fn abort(&self, txn: Option<u64>, written: Vec<TStore::Entry>) {
+        self.delete_all(written, "object of failed transaction")
+            .await;
+        if let Some(txn) = txn {
+            self.record(Record::Aborted { txn }).await;
+        }
+    }
+
+    /// Deletes entries, except those a snapshot still refers to
+    async fn delete_all(&self, entries: Vec<TStore::Entry>, what: &str) {
+        let entries: Vec<_> = {
+            let snapshotted = self.snapshotted();
+            entries
+                .into_iter()
+                .filter(|entry| !snapshotted.contains(entry))
+                .collect()
+        };
+        for entry in entries {
+            if let Err(error) = self.store.delete(entry).await {
+                warn!(%error, "Failed to delete {what}");
+            }
+        }
+    }
+
+    /// Appends to the journal. Transactions are atomic without it, so failing to write it
+    /// only means leftovers of a crash can't be found.
+    async fn record(&self, record: Record<TStore::Entry>) {
+        if let Some(journal) = &self.journal {
+            if let Err(error) = journal.append(&record).await {
+                warn!(%error, "Failed to write journal");
+            }
+        }
+    }
+
+    /// Stores every dirty object in one transaction, if any of them matches `due`. Objects
+    /// changed together are never stored apart.
+    async fn flush(&self, due: impl Fn(Inode, &Dirty) -> bool + Send) -> Result<(), TStore::Error> {
+        let _flushing = self.flushing.lock().await;
+        if !self
+            .dirty
+            .iter()
+            .any(|dirty| due(*dirty.key(), dirty.value()))
+        {
+            return Ok(());
+        }
+        let (generations, pending) = self.dirty_objects(|_| true);
+        debug!(number_of_inodes = pending.len(), "Flushing dirty objects");
+        self.commit(pending, Vec::new()).await?;
+        self.flushed(generations);
+        Ok(())
+    }
+
+    /// Stores `items` and drops the `removed` inodes in one transaction, along with every
+    /// dirty object, since those may have been changed together with the removal
+    async fn remove_all(
+        &self,
+        items: Vec<(Inode, FileSystemObject)>,
+        removed: Vec<Inode>,
+    ) -> Result<(), TStore::Error> {
+        let _flushing = self.flushing.lock().await;
+        let replaced: HashSet<Inode> = items
+            .iter()
+            .map(|(ino, _)| *ino)
+            .chain(removed.iter().copied())
+            .collect();
+        let (generations, mut pending) = self.dirty_objects(|ino| !replaced.contains(&ino));
+        pending.extend(items);
+        debug!(
+            number_of_inodes = pending.len(),
+            number_removed = removed.len(),
+            "Removing inodes"
+        );
+        self.commit(pending, removed).await?;
+        self.flushed(generations);
+        for ino in replaced {
+            self.dirty.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Copies of the dirty objects of inodes matching `include`, with their generations
+    #[allow(clippy::type_complexity)]
+    fn dirty_objects(
+        &self,
+        include: impl Fn(Inode) -> bool,
+    ) -> (Vec<(Inode, u64)>, Vec<(Inode, FileSystemObject)>) {
+        self.dirty
+            .iter()
+            .filter(|dirty| include(*dirty.key()))
+            .map(|dirty| {
+                let object = dirty
+                    .object
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+                ((*dirty.key(), dirty.generation), (*dirty.key(), object))
+            })
+            .unzip()
+    }
+
+    /// Marks dirty objects as stored, unless they changed again while they were being stored
+    fn flushed(&self, generations: Vec<(Inode, u64)>) {
+        for (ino, generation) in generations {
+            self.dirty
+                .remove_if(&ino, |_, dirty| dirty.generation == generation);
+        }
+    }
+
+    /// Reads an object from the disk cache if it's there, or from the store
+    async fn retrieve(&self, id: &TStore::Entry) -> Result<FileSystemObject, TStore::Error> {
+        let Some(disk) = &self.disk else {
+            return self.store.retrieve(id.clone()).await;
+        };
+        if let Some(object) = disk.get(id).await {
+            self.disk_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(object);
+        }
+        let object = self.store.retrieve(id.clone()).await?;
+        disk.put(id, &object).await;
+        Ok(object)
+    }
+
+    fn cache_clean(
+        &self,
+        ino: Inode,
+        entry: TStore::Entry,
+        object: CachedWhenFSObject,
+        size: usize,
+    ) {
+        let evicted = self.clean().insert(ino, entry, object, size, |ino| {
+            self.open.contains_key(&ino) || self.dirty.contains_key(&ino)
+        });
+        if evicted > 0 {
+            self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+            self.prefetch_epoch.fetch_add(1, Ordering::SeqCst);
+            debug!(evicted, "Evicted objects from cache, cancelling prefetches");
+        }
+    }
+
+    /// Fetches an object into the cache unless it's already there, the cache is full, or
+    /// prefetches were cancelled since `epoch`
+    async fn prefetch(&self, ino: Inode, epoch: u64) -> Result<(), TStore::Error> {
+        let cancelled = || self.prefetch_epoch.load(Ordering::SeqCst) != epoch;
+        if cancelled() || self.dirty.contains_key(&ino) {
+            return Ok(());
+        }
+        let Some(id) = self.table.read().await.get(ino).cloned() else {
+            return Ok(());
+        };
+        if self.clean().contains(ino, &id) {
+            return Ok(());
+        }
+        let object = self.retrieve(&id).await?;
+        let size = object.size();
+        let table = self.table.read().await;
+        if cancelled() || table.get(ino) != Some(&id) {
+            return Ok(());
+        }
+        if self
+            .clean()
+            .insert_if_room(ino, id, Arc::new(RwLock::new(object)), size)
+        {
+            trace!(%ino, "Prefetched object");
+            self.prefetched.fetch_add(1, Ordering::Relaxed);
         } else {
-            Ok(None)
+            debug!(%ino, "Cache is full, cancelling prefetches");
+            self.prefetch_epoch.fetch_add(1, Ordering::SeqCst);
         }
+        Ok(())
     }
 
-    async fn insert(&mut self, ino: Inode, item: FileSystemObject) -> Result<Inode, TStore::Error> {
-        let id = self.store.store(&item, item.name().to_string()).await?;
-        self.ino_to_id.insert(ino, id.clone());
-        self.id_to_obj.insert(id, Arc::new(RwLock::new(item)));
-        let new_block = self
-            .store
-            .update(self.root_event.clone(), &self.ino_to_id)
-            .await?;
-        self.root_event = new_block;
+    fn superblock(&self) -> std::sync::RwLockReadGuard<'_, Superblock<TStore::Entry>> {
+        self.superblock
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn snapshotted(&self) -> std::sync::MutexGuard<'_, HashSet<TStore::Entry>> {
+        self.snapshotted
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn snapshot_objects(
+        &self,
+    ) -> std::sync::MutexGuard<'_, LruCache<TStore::Entry, CachedWhenFSObject>> {
+        self.snapshot_objects
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn clean(&self) -> std::sync::MutexGuard<'_, CleanObjects<TStore::Entry>> {
+        self.clean.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn dirty_bytes(&self) -> usize {
+        self.dirty.iter().map(|dirty| dirty.size).sum()
+    }
+
+    async fn run_flusher(self: Arc<Self>, write_back: WriteBack) {
+        let mut backoff: Option<Duration> = None;
+        loop {
+            match backoff {
+                // Failed changes are overdue, so without waiting they'd be retried right away
+                Some(backoff) => tokio::time::sleep(backoff).await,
+                None => {
+                    let next_due = self.dirty.iter().map(|dirty| dirty.since).min().map_or(
+                        write_back.delay,
+                        |since| {
+                            (since + write_back.delay).saturating_duration_since(Instant::now())
+                        },
+                    );
+                    tokio::select! {
+                        _ = self.wake_flusher.notified() => (),
+                        _ = tokio::time::sleep(next_due) => (),
+                    }
+                }
+            }
+            let now = Instant::now();
+            let over_budget = self.dirty_bytes() > write_back.max_dirty_bytes;
+            let result = self
+                .flush(|_, dirty| {
+                    over_budget || now.duration_since(dirty.since) >= write_back.delay
+                })
+                .await;
+            match result {
+                Ok(()) => backoff = None,
+                Err(error) => {
+                    let retry_in = backoff
+                        .map_or(write_back.delay.max(MIN_FLUSH_BACKOFF), |backoff| {
+                            backoff * 2
+                        })
+                        .min(MAX_FLUSH_BACKOFF);
+                    warn!(%error, ?retry_in, "Background flush failed, retrying later");
+                    backoff = Some(retry_in);
+                }
+            }
+        }
+    }
+}
+
+impl<TStore: Store> Drop for WhenFSCache<TStore> {
+    fn drop(&mut self) {
+        if let Some(flusher) = &self.flusher {
+            flusher.abort();
+        }
+        let unflushed = self.shared.dirty.len();
+        if unflushed > 0 {
+            warn!(unflushed, "Dropping cache with unflushed changes");
+        }
+    }
+}
+
+#[async_trait]
+impl<TStore: Store + 'static> Cache for WhenFSCache<TStore> {
+    type Error = TStore::Error;
+
+    async fn get(&self, ino: Inode) -> Result<Option<CachedWhenFSObject>, TStore::Error> {
+        if let Some(dirty) = self.shared.dirty.get(&ino) {
+            trace!(%ino, "Found dirty object");
+            return Ok(Some(Arc::clone(&dirty.object)));
+        }
+        let Some(id) = self.shared.table.read().await.get(ino).cloned() else {
+            return Ok(None);
+        };
+        let cached = self
+            .shared
+            .clean()
+            .get(ino)
+            .filter(|clean| clean.entry == id)
+            .map(|clean| Arc::clone(&clean.object));
+        if let Some(cached) = cached {
+            self.shared.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(cached));
+        }
+        self.shared.misses.fetch_add(1, Ordering::Relaxed);
+        let retrieved = self.shared.retrieve(&id).await?;
+        let size = retrieved.size();
+        let retrieved = Arc::new(RwLock::new(retrieved));
+        // A write may have replaced the object while it was being retrieved
+        let table = self.shared.table.read().await;
+        if table.get(ino) == Some(&id) {
+            self.shared
+                .cache_clean(ino, id, Arc::clone(&retrieved), size);
+        }
+        Ok(Some(retrieved))
+    }
+
+    async fn insert(&self, ino: Inode, item: FileSystemObject) -> Result<Inode, TStore::Error> {
+        self.insert_all(vec![(ino, item)]).await?;
         Ok(ino)
     }
 
+    async fn insert_all(&self, items: Vec<(Inode, FileSystemObject)>) -> Result<(), TStore::Error> {
+        if self.flusher.is_none() {
+            return self.shared.commit(items, Vec::new()).await;
+        }
+        // Flushes store every dirty object at once, so these stay together
+        for (ino, item) in items {
+            let size = item.size();
+            let object = Arc::new(RwLock::new(item));
+            let generation = self.shared.dirty_generation.fetch_add(1, Ordering::SeqCst);
+            self.shared
+                .dirty
+                .entry(ino)
+                .and_modify(|dirty| {
+                    dirty.object = Arc::clone(&object);
+                    dirty.generation = generation;
+                    dirty.size = size;
+                })
+                .or_insert_with(|| Dirty {
+                    object: Arc::clone(&object),
+                    generation,
+                    since: Instant::now(),
+                    size,
+                });
+            trace!(%ino, generation, "Marked object dirty");
+        }
+        self.shared.wake_flusher.notify_one();
+        Ok(())
+    }
+
+    async fn remove_all(
+        &self,
+        items: Vec<(Inode, FileSystemObject)>,
+        removed: Vec<Inode>,
+    ) -> Result<(), TStore::Error> {
+        self.shared.remove_all(items, removed).await
+    }
+
+    async fn flush(&self, only: Option<Inode>) -> Result<(), TStore::Error> {
+        self.shared
+            .flush(|ino, _| only.is_none_or(|only| only == ino))
+            .await
+    }
+
+    fn open(&self, ino: Inode) {
+        *self.shared.open.entry(ino).or_insert(0) += 1;
+    }
+
+    fn close(&self, ino: Inode) {
+        self.shared.open.remove_if_mut(&ino, |_, count| {
+            *count = count.saturating_sub(1);
+            *count == 0
+        });
+    }
+
+    fn prefetch(&self, inos: Vec<Inode>) {
+        let Some(prefetch) = self.prefetch else {
+            return;
+        };
+        let epoch = self.shared.prefetch_epoch.load(Ordering::SeqCst);
+        for ino in inos.into_iter().take(prefetch.max_objects) {
+            if self.shared.prefetching.insert(ino, ()).is_some() {
+                continue;
+            }
+            let shared = Arc::clone(&self.shared);
+            tokio::spawn(async move {
+                if let Ok(_permit) = shared.prefetch_permits.acquire().await {
+                    if let Err(error) = shared.prefetch(ino, epoch).await {
+                        debug!(%ino, %error, "Prefetch failed");
+                    }
+                }
+                shared.prefetching.remove(&ino);
+            });
+        }
+    }
+
     fn new_inode(&self) -> Inode {
-        self.inode_count.fetch_add(1, Ordering::SeqCst)
+        self.shared.inode_count.fetch_add(1, Ordering::SeqCst)
     }
 
     fn get_recovery_id(&self) -> RecoveryDetails {
-        self.store.get_raw_id(&self.root_event)
+        self.shared.store.get_raw_id(&self.shared.superblock_id)
     }
-}
 
-pub trait BlockingCache
-where
-    Self: Cache,
-{
-    type Error: std::fmt::Debug + std::error::Error;
-
-    // async fn recover<TStore: Store>(store: TStore, root_event_id: TStore::Id) -> Self;
+    fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.shared
+            .superblock()
+            .snapshots
+            .iter()
+            .map(|snapshot| SnapshotInfo {
+                name: snapshot.name.clone(),
+                created: snapshot.created,
+            })
+            .collect()
+    }
 
-    fn get_blocking(
+    async fn get_snapshot(
         &self,
+        snapshot: usize,
         ino: Inode,
-    ) -> Result<Option<CachedWhenFSObject>, <Self as Cache>::Error>;
+    ) -> Result<Option<CachedWhenFSObject>, TStore::Error> {
+        let id = {
+            let mut tables = self.shared.snapshot_tables.lock().await;
+            let table = match tables.entry(snapshot) {
+                std::collections::hash_map::Entry::Occupied(table) => table.into_mut(),
+                std::collections::hash_map::Entry::Vacant(vacant) => {
+                    let Some(root) = self
+                        .shared
+                        .superblock()
+                        .snapshots
+                        .get(snapshot)
+                        .map(|snapshot| snapshot.root.clone())
+                    else {
+                        return Ok(None);
+                    };
+                    debug!(snapshot, "Loading inode table of snapshot");
+                    vacant.insert(InodeTable::load(&self.shared.store, root).await?)
+                }
+            };
+            match table.get(ino) {
+                Some(id) => id.clone(),
+                None => return Ok(None),
+            }
+        };
+        let cached = self.shared.snapshot_objects().get(&id).map(Arc::clone);
+        if let Some(cached) = cached {
+            return Ok(Some(cached));
+        }
+        let retrieved = Arc::new(RwLock::new(self.shared.retrieve(&id).await?));
+        self.shared
+            .snapshot_objects()
+            .put(id, Arc::clone(&retrieved));
+        Ok(Some(retrieved))
+    }
 
-    fn insert_blocking(
-        &mut self,
-        ino: Inode,
-        item: FileSystemObject,
-    ) -> Result<Inode, <Self as Cache>::Error>;
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            evictions: self.shared.evictions.load(Ordering::Relaxed),
+            disk_hits: self.shared.disk_hits.load(Ordering::Relaxed),
+            prefetched: self.shared.prefetched.load(Ordering::Relaxed),
+        }
+    }
 }
 
-impl<TStore: Store> BlockingCache for WhenFSCache<TStore> {
-    type Error = <Self as Cache>::Error;
+/// Creates a cache over a fresh in-memory calendar for tests
+#[cfg(test)]
+pub(crate) async fn memory_cache(
+    config: CacheConfig,
+) -> WhenFSCache<crate::store::CalStore<crate::calendar::memory::MemoryClient>> {
+    use crate::{
+        calendar::{memory::MemoryClient, CalendarClient},
+        store::CalStore,
+    };
+    let client = MemoryClient::default();
+    let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+    WhenFSCache::new(CalStore::new(client, calendar), config)
+        .await
+        .unwrap()
+}
 
-    fn get_blocking(
-        &self,
-        ino: Inode,
-    ) -> Result<Option<CachedWhenFSObject>, <Self as Cache>::Error> {
-        info!(%ino, "Handling request for inode");
-        let handle = tokio::runtime::Handle::current();
-        let _guard = handle.enter();
-        futures::executor::block_on(self.get(ino))
+#[cfg(test)]
+mod tests {
+    use super::{
+        memory_cache, Cache, CacheConfig, CacheStats, DiskCacheConfig, Prefetch, Record,
+        SnapshotInfo, Superblock, SuperblockError, WhenFSCache, WriteBack, MIN_FLUSH_BACKOFF,
+    };
+    use crate::{
+        calendar::{memory::MemoryClient, CalendarClient},
+        object::{FileObject, FileSystemObject},
+        store::{CalStore, CalStoreConfig, CalStoreEntry, Layout, LayoutOverrides, Packing, Store},
+    };
+    use fuser::{FileAttr, FileType};
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    async fn write_back_cache(delay: Duration) -> WhenFSCache<CalStore<MemoryClient>> {
+        memory_cache(CacheConfig {
+            write_back: Some(WriteBack::new(delay)),
+            ..Default::default()
+        })
+        .await
     }
 
-    fn insert_blocking(
-        &mut self,
-        ino: Inode,
-        item: FileSystemObject,
-    ) -> Result<Inode, <Self as Cache>::Error> {
-        let handle = tokio::runtime::Handle::current();
-        let _guard = handle.enter();
-        futures::executor::block_on(self.insert(ino, item))
+    fn file(ino: u64, data: &str) -> FileSystemObject {
+        let now = SystemTime::now();
+        FileSystemObject::File(FileObject {
+            attr: FileAttr {
+                ino,
+                size: data.len() as u64,
+                blocks: 1,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::RegularFile,
+                perm: 0o644,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: 512,
+                flags: 0,
+            },
+            name: format!("file{ino}"),
+            data: data.as_bytes().to_vec(),
+        })
+    }
+
+    /// Contents of an inode as the store has it, bypassing cached objects
+    async fn stored_data(cache: &WhenFSCache<CalStore<MemoryClient>>, ino: u64) -> Option<String> {
+        let entry = cache.shared.table.read().await.get(ino).cloned()?;
+        match cache.shared.store.retrieve(entry).await.unwrap() {
+            FileSystemObject::File(file) => Some(String::from_utf8(file.data).unwrap()),
+            FileSystemObject::Dir(_) => panic!("expected a file"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_back_coalesces_until_flushed() {
+        let cache = write_back_cache(Duration::from_secs(3600)).await;
+        for data in ["first", "second", "third"] {
+            cache.insert(2, file(2, data)).await.unwrap();
+        }
+        let cached = cache.get(2).await.unwrap().unwrap();
+        assert_eq!(cached.read().unwrap().name(), "file2");
+        assert_eq!(stored_data(&cache, 2).await, None);
+
+        cache.flush(Some(2)).await.unwrap();
+        assert!(cache.shared.dirty.is_empty());
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("third"));
+    }
+
+    #[tokio::test]
+    async fn test_write_back_flushes_in_background() {
+        let cache = write_back_cache(Duration::from_millis(10)).await;
+        cache.insert(2, file(2, "data")).await.unwrap();
+        for _ in 0..100 {
+            if cache.shared.dirty.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("data"));
+    }
+
+    #[tokio::test]
+    async fn test_write_back_backs_off_after_failures() {
+        let cache = write_back_cache(Duration::from_millis(10)).await;
+        cache.shared.store.client().set_offline(true);
+        cache.insert(2, file(2, "data")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // One failed flush, then waiting a second before trying again
+        assert_eq!(cache.shared.store.client().refused(), 1);
+
+        cache.shared.store.client().set_offline(false);
+        tokio::time::sleep(MIN_FLUSH_BACKOFF).await;
+        assert!(cache.shared.dirty.is_empty());
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("data"));
+    }
+
+    #[tokio::test]
+    async fn test_evicts_clean_objects_over_budget() {
+        let cache = memory_cache(CacheConfig {
+            max_cached_objects: Some(2),
+            ..Default::default()
+        })
+        .await;
+        for ino in 2..=4 {
+            cache.insert(ino, file(ino, "data")).await.unwrap();
+        }
+        assert_eq!(cache.stats().evictions, 1);
+
+        // Inode 2 was evicted, so reading it goes to the store and evicts inode 3
+        cache.get(4).await.unwrap().unwrap();
+        cache.get(2).await.unwrap().unwrap();
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 2,
+                disk_hits: 0,
+                prefetched: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_never_evicts_open_objects() {
+        let cache = memory_cache(CacheConfig {
+            max_cached_objects: Some(1),
+            ..Default::default()
+        })
+        .await;
+        cache.insert(2, file(2, "open")).await.unwrap();
+        cache.open(2);
+        cache.insert(3, file(3, "closed")).await.unwrap();
+        cache.insert(4, file(4, "closed")).await.unwrap();
+        cache.get(2).await.unwrap().unwrap();
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().evictions, 1);
+
+        cache.close(2);
+        cache.insert(5, file(5, "closed")).await.unwrap();
+        cache.get(2).await.unwrap().unwrap();
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reads_evicted_objects_from_disk() {
+        let dir = std::env::temp_dir().join(format!("whenfs-cache-{}", uuid::Uuid::new_v4()));
+        let cache = memory_cache(CacheConfig {
+            max_cached_objects: Some(1),
+            disk: Some(DiskCacheConfig {
+                dir: dir.clone(),
+                max_bytes: DiskCacheConfig::DEFAULT_MAX_BYTES,
+            }),
+            ..Default::default()
+        })
+        .await;
+        cache.insert(2, file(2, "first")).await.unwrap();
+        cache.insert(2, file(2, "second")).await.unwrap();
+        cache.insert(3, file(3, "other")).await.unwrap();
+
+        let object = cache.get(2).await.unwrap().unwrap();
+        match &*object.read().unwrap() {
+            FileSystemObject::File(file) => assert_eq!(file.data, b"second"),
+            FileSystemObject::Dir(_) => panic!("expected a file"),
+        }
+        assert_eq!(cache.stats().disk_hits, 1);
+        // Only the latest version of each inode is kept
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_inserts() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        let inserts = (2..10).map(|ino| cache.insert(ino, file(ino, "data")));
+        futures::future::try_join_all(inserts).await.unwrap();
+        for ino in 2..10 {
+            assert_eq!(stored_data(&cache, ino).await.as_deref(), Some("data"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reads_dont_wait_for_commits() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        cache.insert(2, file(2, "data")).await.unwrap();
+        cache.shared.clean().remove(2);
+        // Stands in for a commit that is still storing its objects
+        let committing = cache.shared.committing.lock().await;
+        let read = tokio::time::timeout(Duration::from_secs(5), cache.get(2))
+            .await
+            .expect("read waited for the commit")
+            .unwrap();
+        assert!(read.is_some());
+        drop(committing);
+    }
+
+    #[tokio::test]
+    async fn test_remove_all_stores_dirty_objects_with_removal() {
+        let cache = write_back_cache(Duration::from_secs(3600)).await;
+        cache.insert(2, file(2, "removed")).await.unwrap();
+        cache.insert(3, file(3, "dirty")).await.unwrap();
+        cache.insert(4, file(4, "old")).await.unwrap();
+        cache
+            .remove_all(vec![(4, file(4, "new"))], vec![2])
+            .await
+            .unwrap();
+
+        assert!(cache.shared.dirty.is_empty());
+        assert!(cache.get(2).await.unwrap().is_none());
+        assert_eq!(stored_data(&cache, 2).await, None);
+        assert_eq!(stored_data(&cache, 3).await.as_deref(), Some("dirty"));
+        assert_eq!(stored_data(&cache, 4).await.as_deref(), Some("new"));
+    }
+
+    /// Unmounts the cache, handing back its store
+    fn into_store(cache: WhenFSCache<CalStore<MemoryClient>>) -> CalStore<MemoryClient> {
+        let shared = Arc::clone(&cache.shared);
+        drop(cache);
+        Arc::into_inner(shared).unwrap().store
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_superblock() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        cache.insert(2, file(2, "data")).await.unwrap();
+        let superblock_id = cache.shared.superblock_id.clone();
+        let uuid = cache.shared.superblock().uuid;
+        let root = cache.shared.table.read().await.root().clone();
+        let store = into_store(cache);
+
+        assert_eq!(
+            WhenFSCache::find_superblock(&store).await.unwrap().as_ref(),
+            Some(&superblock_id)
+        );
+        let cache = WhenFSCache::recover(store, superblock_id.clone(), CacheConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(cache.shared.superblock().uuid, uuid);
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("data"));
+
+        // An inode table root from before superblocks, named by its last event only, gets
+        // a new superblock
+        let store = into_store(cache);
+        let root = CalStoreEntry {
+            name: root.name,
+            event_ids: root.event_ids.last().cloned().into_iter().collect(),
+        };
+        let cache = WhenFSCache::recover(store, root, CacheConfig::default())
+            .await
+            .unwrap();
+        assert_ne!(cache.shared.superblock_id, superblock_id);
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("data"));
+    }
+
+    #[tokio::test]
+    async fn test_sweep_refuses_with_other_filesystems() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        cache.insert(2, file(2, "data")).await.unwrap();
+        let store = into_store(cache);
+        let cache = WhenFSCache::new(store, CacheConfig::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            cache.sweep().await,
+            Err(SuperblockError::Ambiguous(superblocks)) if superblocks.len() == 2
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_commit_survives_lost_superblock_response() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        cache.insert(2, file(2, "old")).await.unwrap();
+        cache.shared.store.client().lose_update_responses(true);
+        cache.insert(2, file(2, "new")).await.unwrap();
+        cache.shared.store.client().lose_update_responses(false);
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("new"));
+
+        let superblock_id = cache.shared.superblock_id.clone();
+        let store = into_store(cache);
+        let cache = WhenFSCache::recover(store, superblock_id, CacheConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("new"));
+    }
+
+    #[tokio::test]
+    async fn test_recover_keeps_layout() {
+        let client = MemoryClient::default();
+        let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+        let erasure = Some("4+2".parse().unwrap());
+        let config = CalStoreConfig {
+            packing: Packing::AllFields,
+            erasure,
+            ..Default::default()
+        };
+        let store = CalStore::with_config(client, calendar, config);
+        let cache = WhenFSCache::new(store, CacheConfig::default())
+            .await
+            .unwrap();
+        let superblock_id = cache.shared.superblock_id.clone();
+        let mut store = into_store(cache);
+
+        // A run without layout settings keeps the filesystem's
+        store.set_layout(&Layout {
+            packing: Packing::Description,
+            erasure: None,
+            ..store.layout()
+        });
+        let cache = WhenFSCache::recover(store, superblock_id.clone(), CacheConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(cache.shared.store.layout().packing, Packing::AllFields);
+        assert_eq!(cache.shared.store.layout().erasure, erasure);
+        assert_eq!(cache.superblock().layout.packing, Packing::AllFields);
+
+        let store = into_store(cache);
+        let layout = LayoutOverrides {
+            packing: Some(Packing::Description),
+            erasure: Some(None),
+        };
+        // Read-only runs can't write anything, so they ignore layout settings
+        let config = CacheConfig {
+            layout,
+            read_only: true,
+            ..Default::default()
+        };
+        let cache = WhenFSCache::recover(store, superblock_id.clone(), config)
+            .await
+            .unwrap();
+        assert_eq!(cache.shared.store.layout().packing, Packing::AllFields);
+        assert_eq!(cache.superblock().layout.packing, Packing::AllFields);
+
+        let store = into_store(cache);
+        let config = CacheConfig {
+            layout,
+            ..Default::default()
+        };
+        let cache = WhenFSCache::recover(store, superblock_id, config)
+            .await
+            .unwrap();
+        assert_eq!(cache.shared.store.layout().packing, Packing::Description);
+        assert_eq!(cache.shared.store.layout().erasure, None);
+        assert_eq!(cache.superblock().layout.packing, Packing::Description);
+    }
+
+    #[tokio::test]
+    async fn test_recovery_rolls_back_unfinished_transactions() {
+        let path = std::env::temp_dir()
+            .join(format!("whenfs-cache-{}", uuid::Uuid::new_v4()))
+            .join("journal");
+        let config = || CacheConfig {
+            journal: Some(path.clone()),
+            ..Default::default()
+        };
+        let cache = memory_cache(config()).await;
+        cache
+            .insert_all(vec![(2, file(2, "first")), (3, file(3, "second"))])
+            .await
+            .unwrap();
+
+        // Crash after storing an object, before switching the table to it
+        let orphan = cache
+            .shared
+            .store
+            .store(&file(4, "orphan"), String::from("4"))
+            .await
+            .unwrap();
+        cache
+            .shared
+            .record(Record::Prepared {
+                txn: 100,
+                written: vec![orphan.clone()],
+            })
+            .await;
+        let superblock_id = cache.shared.superblock_id.clone();
+        let store = into_store(cache);
+
+        // A read-only mount leaves the leftovers alone
+        let read_only = CacheConfig {
+            read_only: true,
+            ..config()
+        };
+        let cache = WhenFSCache::recover(store, superblock_id.clone(), read_only)
+            .await
+            .unwrap();
+        assert!(cache
+            .shared
+            .store
+            .retrieve::<FileSystemObject>(orphan.clone())
+            .await
+            .is_ok());
+        let store = into_store(cache);
+
+        let cache = WhenFSCache::recover(store, superblock_id, config())
+            .await
+            .unwrap();
+        assert!(cache
+            .shared
+            .store
+            .retrieve::<FileSystemObject>(orphan)
+            .await
+            .is_err());
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("first"));
+        assert_eq!(stored_data(&cache, 3).await.as_deref(), Some("second"));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_keeps_old_objects() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        cache.insert(2, file(2, "before")).await.unwrap();
+        let old = cache.shared.table.read().await.get(2).cloned().unwrap();
+        let snapshot = cache.snapshot(String::from("first")).await.unwrap();
+        assert!(matches!(
+            cache.snapshot(String::from("first")).await,
+            Err(SuperblockError::SnapshotExists(_))
+        ));
+        cache.insert(2, file(2, "after")).await.unwrap();
+        assert_eq!(cache.sweep().await.unwrap(), 0);
+        assert!(cache
+            .shared
+            .store
+            .retrieve::<FileSystemObject>(old)
+            .await
+            .is_ok());
+        assert_eq!(
+            cache.snapshots(),
+            vec![SnapshotInfo {
+                name: snapshot.name,
+                created: snapshot.created,
+            }]
+        );
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("after"));
+        match &*cache
+            .get_snapshot(0, 2)
+            .await
+            .unwrap()
+            .unwrap()
+            .read()
+            .unwrap()
+        {
+            FileSystemObject::File(file) => assert_eq!(file.data, b"before"),
+            FileSystemObject::Dir(_) => panic!("expected a file"),
+        }
+        assert!(cache.get_snapshot(1, 2).await.unwrap().is_none());
+
+        let superblock_id = cache.shared.superblock_id.clone();
+        let store = into_store(cache);
+        let config = |snapshot: &str| CacheConfig {
+            snapshot: Some(String::from(snapshot)),
+            ..Default::default()
+        };
+        let cache = WhenFSCache::recover(store, superblock_id.clone(), config("first"))
+            .await
+            .unwrap();
+        assert_eq!(stored_data(&cache, 2).await.as_deref(), Some("before"));
+        let store = into_store(cache);
+        assert!(matches!(
+            WhenFSCache::recover(store, superblock_id, config("missing")).await,
+            Err(SuperblockError::NoSuchSnapshot(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_commit_keeps_snapshots_of_other_processes() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        cache.insert(2, file(2, "before")).await.unwrap();
+        let old = cache.shared.table.read().await.get(2).cloned().unwrap();
+        // Another process snapshots the filesystem behind the cache's back
+        let mut stored = cache.superblock();
+        stored.add_snapshot(String::from("offline")).unwrap();
+        cache
+            .shared
+            .store
+            .overwrite(&cache.shared.superblock_id, &stored)
+            .await
+            .unwrap();
+
+        cache.insert(2, file(2, "after")).await.unwrap();
+        assert_eq!(cache.snapshots()[0].name, "offline");
+        assert!(cache
+            .shared
+            .store
+            .retrieve::<FileSystemObject>(old)
+            .await
+            .is_ok());
+        let stored: Superblock<CalStoreEntry> = cache
+            .shared
+            .store
+            .retrieve(cache.shared.superblock_id.clone())
+            .await
+            .unwrap();
+        assert!(stored.snapshot("offline").is_some());
+    }
+
+    /// Forgets every cached object, as if the cache had just been recovered
+    fn forget_cached(cache: &WhenFSCache<CalStore<MemoryClient>>, inos: impl Iterator<Item = u64>) {
+        let mut clean = cache.shared.clean();
+        for ino in inos {
+            clean.remove(ino);
+        }
+    }
+
+    async fn wait_for_prefetches(cache: &WhenFSCache<CalStore<MemoryClient>>) {
+        for _ in 0..100 {
+            if cache.shared.prefetching.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("prefetches didn't finish");
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        for ino in 2..6 {
+            cache.insert(ino, file(ino, "data")).await.unwrap();
+        }
+        forget_cached(&cache, 2..6);
+        cache.prefetch((2..6).collect());
+        wait_for_prefetches(&cache).await;
+        for ino in 2..6 {
+            cache.get(ino).await.unwrap().unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.prefetched), (4, 0, 4));
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_stops_when_cache_is_full() {
+        let cache = memory_cache(CacheConfig {
+            max_cached_objects: Some(2),
+            prefetch: Some(Prefetch {
+                concurrency: 1,
+                max_objects: 64,
+            }),
+            ..Default::default()
+        })
+        .await;
+        for ino in 2..6 {
+            cache.insert(ino, file(ino, "data")).await.unwrap();
+        }
+        forget_cached(&cache, 2..6);
+        cache.prefetch((2..6).collect());
+        wait_for_prefetches(&cache).await;
+        assert_eq!(cache.stats().prefetched, 2);
+        assert_eq!(cache.stats().evictions, 2);
     }
 }
@@ -0,0 +1,211 @@
+use super::{CachedWhenFSObject, Inode};
+use lru::LruCache;
+use tracing::trace;
+
+/// Objects that match what the store has, evicted least recently used first once they take
+/// up more than the budget
+#[derive(Debug)]
+pub struct CleanObjects<TEntry> {
+    objects: LruCache<Inode, Clean<TEntry>>,
+    bytes: usize,
+    budget: Budget,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub max_bytes: Option<usize>,
+    pub max_objects: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Clean<TEntry> {
+    /// Entry the object is stored under
+    pub entry: TEntry,
+    pub object: CachedWhenFSObject,
+    size: usize,
+}
+
+impl<TEntry> CleanObjects<TEntry> {
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            objects: LruCache::unbounded(),
+            bytes: 0,
+            budget,
+        }
+    }
+
+    pub fn get(&mut self, ino: Inode) -> Option<&Clean<TEntry>> {
+        self.objects.get(&ino)
+    }
+
+    /// Caches an object, then evicts others until the cache is within budget again, skipping
+    /// any inode that is `pinned`. Returns how many objects were evicted.
+    pub fn insert(
+        &mut self,
+        ino: Inode,
+        entry: TEntry,
+        object: CachedWhenFSObject,
+        size: usize,
+        pinned: impl Fn(Inode) -> bool,
+    ) -> usize {
+        if let Some(replaced) = self.objects.put(
+            ino,
+            Clean {
+                entry,
+                object,
+                size,
+            },
+        ) {
+            self.bytes -= replaced.size;
+        }
+        self.bytes += size;
+
+        let mut victims = Vec::new();
+        let (mut bytes, mut count) = (self.bytes, self.objects.len());
+        // Least recently used first, never the object that was just inserted
+        for (&candidate, clean) in self.objects.iter().rev() {
+            if !self.over_budget(bytes, count) {
+                break;
+            }
+            if candidate == ino || pinned(candidate) {
+                continue;
+            }
+            victims.push(candidate);
+            bytes -= clean.size;
+            count -= 1;
+        }
+        for victim in &victims {
+            trace!(ino = %victim, "Evicting object from cache");
+            self.remove(*victim);
+        }
+        victims.len()
+    }
+
+    /// Caches an object only if that doesn't take the cache over budget, returning whether
+    /// it did
+    pub fn insert_if_room(
+        &mut self,
+        ino: Inode,
+        entry: TEntry,
+        object: CachedWhenFSObject,
+        size: usize,
+    ) -> bool {
+        let (bytes, count) = match self.objects.peek(&ino) {
+            Some(replaced) => (self.bytes - replaced.size + size, self.objects.len()),
+            None => (self.bytes + size, self.objects.len() + 1),
+        };
+        if self.over_budget(bytes, count) {
+            return false;
+        }
+        self.insert(ino, entry, object, size, |_| true);
+        true
+    }
+
+    /// Whether an inode's object is cached under `entry`, without counting as a use
+    pub fn contains(&self, ino: Inode, entry: &TEntry) -> bool
+    where
+        TEntry: PartialEq,
+    {
+        self.objects
+            .peek(&ino)
+            .is_some_and(|clean| clean.entry == *entry)
+    }
+
+    pub fn remove(&mut self, ino: Inode) {
+        if let Some(removed) = self.objects.pop(&ino) {
+            self.bytes -= removed.size;
+        }
+    }
+
+    fn over_budget(&self, bytes: usize, count: usize) -> bool {
+        self.budget.max_bytes.is_some_and(|max| bytes > max)
+            || self.budget.max_objects.is_some_and(|max| count > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Budget, CleanObjects};
+    use crate::object::{DirectoryObject, FileSystemObject};
+    use fuser::{FileAttr, FileType};
+    use std::{
+        sync::{Arc, RwLock},
+        time::SystemTime,
+    };
+
+    fn object() -> Arc<RwLock<FileSystemObject>> {
+        let now = SystemTime::now();
+        Arc::new(RwLock::new(FileSystemObject::Dir(DirectoryObject {
+            attr: FileAttr {
+                ino: 0,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::Directory,
+                perm: 0o755,
+                nlink: 2,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: 512,
+                flags: 0,
+            },
+            entries: Default::default(),
+            name: String::new(),
+        })))
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut clean = CleanObjects::new(Budget {
+            max_bytes: Some(300),
+            max_objects: None,
+        });
+        for ino in 1..=3 {
+            assert_eq!(clean.insert(ino, (), object(), 100, |_| false), 0);
+        }
+        // Touching 1 makes 2 the least recently used
+        assert!(clean.get(1).is_some());
+        assert_eq!(clean.insert(4, (), object(), 100, |_| false), 1);
+        assert!(clean.get(2).is_none());
+        assert_eq!(clean.bytes, 300);
+
+        // Replacing an object only counts its new size
+        assert_eq!(clean.insert(4, (), object(), 50, |_| false), 0);
+        assert_eq!(clean.bytes, 250);
+    }
+
+    #[test]
+    fn test_never_evicts_pinned() {
+        let mut clean = CleanObjects::new(Budget {
+            max_bytes: None,
+            max_objects: Some(2),
+        });
+        clean.insert(1, (), object(), 100, |_| false);
+        clean.insert(2, (), object(), 100, |_| false);
+        assert_eq!(clean.insert(3, (), object(), 100, |ino| ino != 2), 1);
+        assert!(clean.get(1).is_some());
+        assert!(clean.get(2).is_none());
+
+        // Going over budget beats evicting something pinned
+        assert_eq!(clean.insert(4, (), object(), 100, |_| true), 0);
+        assert_eq!(clean.objects.len(), 3);
+    }
+
+    #[test]
+    fn test_insert_if_room() {
+        let mut clean = CleanObjects::new(Budget {
+            max_bytes: Some(150),
+            max_objects: None,
+        });
+        assert!(clean.insert_if_room(1, (), object(), 100));
+        assert!(!clean.insert_if_room(2, (), object(), 100));
+        // Replacing an object only needs room for the difference
+        assert!(clean.insert_if_room(1, (), object(), 150));
+        assert!(clean.contains(1, &()));
+        assert!(!clean.contains(2, &()));
+    }
+}
@@ -0,0 +1,359 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+use tracing::{debug, info, trace, warn};
+
+#[derive(Clone, Debug)]
+pub struct DiskCacheConfig {
+    /// Directory holding the cached objects of one calendar
+    pub dir: PathBuf,
+    /// Delete least recently used objects once the directory holds more than this
+    pub max_bytes: u64,
+}
+
+impl DiskCacheConfig {
+    pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+    /// `$XDG_CACHE_HOME/whenfs/<calendar-id>`, falling back to `~/.cache` like the XDG spec
+    pub fn default_dir(calendar_id: &str) -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(base.join("whenfs").join(calendar_id))
+    }
+}
+
+/// Downloaded objects kept on disk between mounts, keyed by the entry they are stored under.
+///
+/// Each file starts with a JSON header line holding the full entry and a checksum of the
+/// object that follows it. A file is only used when its entry matches the requested one
+/// exactly (so the same event IDs) and the checksum still matches, anything else is a miss.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Index>,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    files: HashMap<String, Indexed>,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Indexed {
+    size: u64,
+    /// Last use during this mount, or when the file was written for older files
+    used: SystemTime,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Header<TEntry> {
+    entry: TEntry,
+    checksum: String,
+}
+
+impl DiskCache {
+    const TEMP_EXTENSION: &'static str = "tmp";
+
+    pub fn open(config: DiskCacheConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        let mut index = Index::default();
+        for file in std::fs::read_dir(&config.dir)? {
+            let file = file?;
+            let path = file.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == Self::TEMP_EXTENSION)
+            {
+                // Left behind by a write that never finished
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let metadata = file.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let Some(key) = file.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            index.bytes += metadata.len();
+            index.files.insert(
+                key,
+                Indexed {
+                    size: metadata.len(),
+                    used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+        info!(
+            dir = %config.dir.display(),
+            number_of_objects = index.files.len(),
+            bytes = index.bytes,
+            "Opened disk cache"
+        );
+        let cache = Self {
+            dir: config.dir,
+            max_bytes: config.max_bytes,
+            index: Mutex::new(index),
+        };
+        cache.evict(None);
+        Ok(cache)
+    }
+
+    /// Reads the object cached for an entry, if there is a valid one
+    pub async fn get<TEntry, T>(&self, entry: &TEntry) -> Option<T>
+    where
+        TEntry: Serialize + DeserializeOwned + Eq,
+        T: DeserializeOwned,
+    {
+        let key = Self::key(entry)?;
+        if !self.index().files.contains_key(&key) {
+            return None;
+        }
+        let contents = match tokio::fs::read(self.dir.join(&key)).await {
+            Ok(contents) => contents,
+            Err(error) => {
+                debug!(%key, %error, "Couldn't read cached object");
+                self.forget(&key);
+                return None;
+            }
+        };
+        match Self::decode(entry, &contents) {
+            Some(object) => {
+                trace!(%key, "Found object in disk cache");
+                if let Some(indexed) = self.index().files.get_mut(&key) {
+                    indexed.used = SystemTime::now();
+                }
+                Some(object)
+            }
+            None => {
+                warn!(%key, "Discarding invalid object from disk cache");
+                self.remove_key(&key).await;
+                None
+            }
+        }
+    }
+
+    /// Caches an object under its entry, evicting older objects if that goes over budget
+    pub async fn put<TEntry: Serialize, T: Serialize>(&self, entry: &TEntry, object: &T) {
+        let Some(key) = Self::key(entry) else {
+            return;
+        };
+        let contents = match Self::encode(entry, object) {
+            Ok(contents) => contents,
+            Err(error) => {
+                warn!(%key, %error, "Couldn't encode object for disk cache");
+                return;
+            }
+        };
+        let size = contents.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+        // Written under a temporary name first, so a crash never leaves a partial object
+        let temp = self.dir.join(&key).with_extension(Self::TEMP_EXTENSION);
+        let written = async {
+            tokio::fs::write(&temp, &contents).await?;
+            tokio::fs::rename(&temp, self.dir.join(&key)).await
+        };
+        if let Err(error) = written.await {
+            warn!(%key, %error, "Couldn't write object to disk cache");
+            let _ = tokio::fs::remove_file(&temp).await;
+            return;
+        }
+        {
+            let mut index = self.index();
+            let replaced = index.files.insert(
+                key.clone(),
+                Indexed {
+                    size,
+                    used: SystemTime::now(),
+                },
+            );
+            index.bytes += size;
+            index.bytes -= replaced.map_or(0, |replaced| replaced.size);
+        }
+        self.evict(Some(&key));
+    }
+
+    /// Drops the object cached for an entry, e.g. because nothing refers to it anymore
+    pub async fn remove<TEntry: Serialize>(&self, entry: &TEntry) {
+        if let Some(key) = Self::key(entry) {
+            self.remove_key(&key).await;
+        }
+    }
+
+    async fn remove_key(&self, key: &str) {
+        if self.forget(key) {
+            if let Err(error) = tokio::fs::remove_file(self.dir.join(key)).await {
+                debug!(%key, %error, "Couldn't delete cached object");
+            }
+        }
+    }
+
+    fn forget(&self, key: &str) -> bool {
+        let mut index = self.index();
+        match index.files.remove(key) {
+            Some(removed) => {
+                index.bytes -= removed.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Deletes least recently used files until the cache is within budget, except `keep`
+    fn evict(&self, keep: Option<&str>) {
+        let victims = {
+            let mut index = self.index();
+            if index.bytes <= self.max_bytes {
+                return;
+            }
+            let mut candidates: Vec<_> = index
+                .files
+                .iter()
+                .filter(|(key, _)| Some(key.as_str()) != keep)
+                .map(|(key, indexed)| (indexed.used, key.clone()))
+                .collect();
+            candidates.sort();
+            let mut victims = Vec::new();
+            for (_, key) in candidates {
+                if index.bytes <= self.max_bytes {
+                    break;
+                }
+                if let Some(removed) = index.files.remove(&key) {
+                    index.bytes -= removed.size;
+                    victims.push(key);
+                }
+            }
+            victims
+        };
+        debug!(evicted = victims.len(), "Evicting objects from disk cache");
+        for key in victims {
+            if let Err(error) = std::fs::remove_file(self.dir.join(&key)) {
+                debug!(%key, %error, "Couldn't delete evicted object");
+            }
+        }
+    }
+
+    fn index(&self) -> std::sync::MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// File name for an entry
+    fn key<TEntry: Serialize>(entry: &TEntry) -> Option<String> {
+        match serde_json::to_vec(entry) {
+            Ok(json) => Some(blake3::hash(&json).to_hex().to_string()),
+            Err(error) => {
+                warn!(%error, "Couldn't encode entry for disk cache");
+                None
+            }
+        }
+    }
+
+    fn encode<TEntry: Serialize, T: Serialize>(
+        entry: &TEntry,
+        object: &T,
+    ) -> serde_json::Result<Vec<u8>> {
+        let object = serde_json::to_vec(object)?;
+        let header = Header {
+            entry,
+            checksum: blake3::hash(&object).to_hex().to_string(),
+        };
+        let mut contents = serde_json::to_vec(&header)?;
+        contents.push(b'\n');
+        contents.extend(object);
+        Ok(contents)
+    }
+
+    fn decode<TEntry, T>(entry: &TEntry, contents: &[u8]) -> Option<T>
+    where
+        TEntry: DeserializeOwned + Eq,
+        T: DeserializeOwned,
+    {
+        let split = contents.iter().position(|&byte| byte == b'\n')?;
+        let (header, object) = (&contents[..split], &contents[split + 1..]);
+        let header: Header<TEntry> = serde_json::from_slice(header).ok()?;
+        if header.entry != *entry || header.checksum != blake3::hash(object).to_hex().as_str() {
+            return None;
+        }
+        serde_json::from_slice(object).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskCache, DiskCacheConfig};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("whenfs-{name}-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_objects_survive_reopening() {
+        let dir = temp_dir("disk-cache");
+        let config = DiskCacheConfig {
+            dir: dir.clone(),
+            max_bytes: DiskCacheConfig::DEFAULT_MAX_BYTES,
+        };
+        let cache = DiskCache::open(config.clone()).unwrap();
+        cache.put(&vec![String::from("event1")], &"object").await;
+        drop(cache);
+
+        let cache = DiskCache::open(config).unwrap();
+        assert_eq!(
+            cache
+                .get::<_, String>(&vec![String::from("event1")])
+                .await
+                .as_deref(),
+            Some("object")
+        );
+        assert_eq!(
+            cache.get::<_, String>(&vec![String::from("event2")]).await,
+            None
+        );
+
+        // A file whose contents don't match its header is discarded
+        let key = DiskCache::key(&vec![String::from("event1")]).unwrap();
+        let mut contents = std::fs::read(dir.join(&key)).unwrap();
+        *contents.last_mut().unwrap() = b'X';
+        std::fs::write(dir.join(&key), contents).unwrap();
+        assert_eq!(
+            cache.get::<_, String>(&vec![String::from("event1")]).await,
+            None
+        );
+        assert!(!dir.join(&key).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let dir = temp_dir("disk-cache");
+        let one_object = DiskCache::encode(&1, &"object").unwrap().len() as u64;
+        let cache = DiskCache::open(DiskCacheConfig {
+            dir: dir.clone(),
+            max_bytes: 2 * one_object,
+        })
+        .unwrap();
+        cache.put(&1, &"object").await;
+        cache.put(&2, &"object").await;
+        assert!(cache.get::<_, String>(&1).await.is_some());
+        cache.put(&3, &"object").await;
+        assert!(cache.get::<_, String>(&2).await.is_none());
+        assert!(cache.get::<_, String>(&1).await.is_some());
+        assert!(cache.get::<_, String>(&3).await.is_some());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,504 @@
+use super::{Cache, Inode, WhenFSCache};
+use crate::object::{DirectoryEntry, DirectoryObject, FileSystemObject};
+use crate::store::Store;
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::SystemTime;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+/// Directory in the root that orphaned inodes are moved to
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// Inconsistency found by `fsck`
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum Problem {
+    #[error("Inode {ino} can't be read, its chain or manifest may be incomplete: {error}")]
+    Unreadable { ino: Inode, error: String },
+    #[error("Inode {ino} doesn't hold a valid object: {error}")]
+    Undecodable { ino: Inode, error: String },
+    #[error("The root directory is missing")]
+    MissingRoot,
+    #[error("Entry {name:?} of directory {parent} points at missing inode {ino}")]
+    Dangling {
+        parent: Inode,
+        name: String,
+        ino: Inode,
+    },
+    #[error("Inode {ino} isn't in any directory")]
+    Orphan { ino: Inode },
+    #[error("Inode {ino} has a link count of {found}, but {expected} links")]
+    LinkCount {
+        ino: Inode,
+        found: u32,
+        expected: u32,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of inodes in the inode table
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+    /// Number of inodes changed to fix problems
+    pub repaired: usize,
+}
+
+/// Every readable object of the filesystem, which repairs are made to before any of them
+/// is stored
+struct Objects {
+    objects: BTreeMap<Inode, FileSystemObject>,
+    /// Inodes in the table, whether or not their objects could be read
+    known: BTreeSet<Inode>,
+    changed: BTreeSet<Inode>,
+}
+
+impl Objects {
+    fn dir(&self, ino: Inode) -> Option<&DirectoryObject> {
+        match self.objects.get(&ino) {
+            Some(FileSystemObject::Dir(dir)) => Some(dir),
+            _ => None,
+        }
+    }
+
+    fn dir_mut(&mut self, ino: Inode) -> Option<&mut DirectoryObject> {
+        self.changed.insert(ino);
+        match self.objects.get_mut(&ino) {
+            Some(FileSystemObject::Dir(dir)) => Some(dir),
+            _ => None,
+        }
+    }
+
+    /// Entries of a directory other than `.` and `..`
+    fn children(dir: &DirectoryObject) -> impl Iterator<Item = &DirectoryEntry> {
+        dir.entries
+            .iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+    }
+
+    fn dangling(&self) -> Vec<Problem> {
+        self.objects
+            .iter()
+            .filter_map(|(ino, object)| match object {
+                FileSystemObject::Dir(dir) => Some((*ino, dir)),
+                FileSystemObject::File(_) => None,
+            })
+            .flat_map(|(parent, dir)| {
+                Self::children(dir)
+                    .filter(|entry| !self.known.contains(&entry.ino))
+                    .map(move |entry| Problem::Dangling {
+                        parent,
+                        name: entry.name.clone(),
+                        ino: entry.ino,
+                    })
+            })
+            .collect()
+    }
+
+    /// Inodes reachable from the given directories, including themselves
+    fn reachable_from(&self, starts: impl IntoIterator<Item = Inode>) -> BTreeSet<Inode> {
+        let mut reached = BTreeSet::new();
+        let mut queue: Vec<Inode> = starts.into_iter().collect();
+        while let Some(ino) = queue.pop() {
+            if !self.known.contains(&ino) || !reached.insert(ino) {
+                continue;
+            }
+            if let Some(dir) = self.dir(ino) {
+                queue.extend(Self::children(dir).map(|entry| entry.ino));
+            }
+        }
+        reached
+    }
+
+    /// Link counts every object should have: one per directory entry for files, and two
+    /// plus one per subdirectory for directories
+    fn link_counts(&self) -> HashMap<Inode, u32> {
+        let mut links: HashMap<Inode, u32> = HashMap::new();
+        for (ino, object) in &self.objects {
+            if let FileSystemObject::Dir(dir) = object {
+                let subdirs = Self::children(dir)
+                    .filter(|entry| entry.file_type == FileType::Directory)
+                    .count() as u32;
+                *links.entry(*ino).or_default() += 2 + subdirs;
+                for entry in Self::children(dir) {
+                    if entry.file_type != FileType::Directory {
+                        *links.entry(entry.ino).or_default() += 1;
+                    }
+                }
+            }
+        }
+        links
+    }
+
+    fn fix_link_counts(&mut self) {
+        let links = self.link_counts();
+        for (ino, object) in &mut self.objects {
+            let expected = links.get(ino).copied().unwrap_or(0);
+            if object.get_attr().nlink != expected {
+                object.mut_attr().nlink = expected;
+                self.changed.insert(*ino);
+            }
+        }
+    }
+
+    fn wrong_link_counts(&self, only: &BTreeSet<Inode>) -> Vec<Problem> {
+        let links = self.link_counts();
+        only.iter()
+            .filter_map(|ino| {
+                let found = self.objects.get(ino)?.get_attr().nlink;
+                let expected = links.get(ino).copied().unwrap_or(0);
+                (found != expected).then_some(Problem::LinkCount {
+                    ino: *ino,
+                    found,
+                    expected,
+                })
+            })
+            .collect()
+    }
+}
+
+impl<TStore: Store + 'static> WhenFSCache<TStore> {
+    /// Checks that every object in the inode table can be read, that directories only
+    /// point at existing inodes, that every inode is in some directory, and that link
+    /// counts match. With `repair`, dangling entries are dropped, orphans are moved to
+    /// `lost+found` and link counts are corrected, all in one transaction. Nothing else may
+    /// be using the filesystem while this runs.
+    pub async fn fsck(&self, repair: bool) -> Result<Report, TStore::Error> {
+        self.flush(None).await?;
+        let entries: Vec<(Inode, TStore::Entry)> = {
+            let table = self.shared.table.read().await;
+            table
+                .iter()
+                .map(|(ino, entry)| (ino, entry.clone()))
+                .collect()
+        };
+        let mut report = Report {
+            checked: entries.len(),
+            ..Default::default()
+        };
+        let mut objects = Objects {
+            objects: BTreeMap::new(),
+            known: entries.iter().map(|(ino, _)| *ino).collect(),
+            changed: BTreeSet::new(),
+        };
+        for (ino, entry) in entries {
+            let value = match self.shared.store.retrieve::<serde_json::Value>(entry).await {
+                Ok(value) => value,
+                Err(error) => {
+                    report.problems.push(Problem::Unreadable {
+                        ino,
+                        error: error.to_string(),
+                    });
+                    continue;
+                }
+            };
+            match serde_json::from_value(value) {
+                Ok(object) => {
+                    objects.objects.insert(ino, object);
+                }
+                Err(error) => report.problems.push(Problem::Undecodable {
+                    ino,
+                    error: error.to_string(),
+                }),
+            }
+        }
+        debug!(
+            number_of_objects = objects.objects.len(),
+            "Read every object"
+        );
+
+        let dangling = objects.dangling();
+        report.problems.extend(dangling.iter().cloned());
+        if objects.dir(FUSE_ROOT_ID).is_none() {
+            // Without a root everything would be an orphan, mounting creates a new one
+            report.problems.push(Problem::MissingRoot);
+            return Ok(report);
+        }
+        let reachable = objects.reachable_from([FUSE_ROOT_ID]);
+        let orphans: BTreeSet<Inode> = objects.known.difference(&reachable).copied().collect();
+        report
+            .problems
+            .extend(orphans.iter().map(|ino| Problem::Orphan { ino: *ino }));
+        report
+            .problems
+            .extend(objects.wrong_link_counts(&reachable));
+        for problem in &report.problems {
+            warn!(%problem, "Found problem");
+        }
+        if !repair || report.problems.is_empty() {
+            return Ok(report);
+        }
+
+        for problem in dangling {
+            if let Problem::Dangling { parent, ino, .. } = problem {
+                if let Some(dir) = objects.dir_mut(parent) {
+                    dir.entries.remove(&DirectoryEntry {
+                        ino,
+                        file_type: FileType::RegularFile,
+                        name: String::new(),
+                    });
+                }
+            }
+        }
+        let unreadable = report.problems.iter().any(|problem| {
+            matches!(
+                problem,
+                Problem::Unreadable { .. } | Problem::Undecodable { .. }
+            )
+        });
+        if unreadable {
+            // Whatever an unreadable directory holds would look orphaned, and its link
+            // counts would look wrong
+            warn!("Some objects can't be read, only dropping dangling entries");
+        } else {
+            self.move_to_lost_and_found(&mut objects, orphans);
+            objects.fix_link_counts();
+        }
+
+        let changed: Vec<(Inode, FileSystemObject)> = objects
+            .changed
+            .iter()
+            .filter_map(|ino| Some((*ino, objects.objects.get(ino)?.clone())))
+            .collect();
+        report.repaired = changed.len();
+        self.insert_all(changed).await?;
+        info!(repaired = report.repaired, "Repaired filesystem");
+        Ok(report)
+    }
+
+    /// Links every readable orphan that isn't below another orphan into `lost+found`,
+    /// named after its inode
+    fn move_to_lost_and_found(&self, objects: &mut Objects, orphans: BTreeSet<Inode>) {
+        // Orphans below other orphans come along with them
+        let mut below_orphans = BTreeSet::new();
+        for orphan in &orphans {
+            if let Some(dir) = objects.dir(*orphan) {
+                below_orphans.extend(
+                    Objects::children(dir)
+                        .map(|entry| entry.ino)
+                        .filter(|ino| ino != orphan),
+                );
+            }
+        }
+        // Orphans only reachable through a cycle of each other are taken in inode order
+        let tops = orphans
+            .iter()
+            .filter(|ino| !below_orphans.contains(ino))
+            .chain(&orphans);
+        let mut moved = BTreeSet::new();
+        let mut lost_and_found = None;
+        for &ino in tops {
+            if moved.contains(&ino) || !objects.objects.contains_key(&ino) {
+                continue;
+            }
+            let lost_and_found = match lost_and_found {
+                Some(lost_and_found) => lost_and_found,
+                None => match self.lost_and_found(objects) {
+                    Some(found) => *lost_and_found.insert(found),
+                    None => return,
+                },
+            };
+            moved.extend(objects.reachable_from([ino]));
+            let name = format!("#{ino}");
+            let Some(object) = objects.objects.get_mut(&ino) else {
+                continue;
+            };
+            let file_type = object.get_attr().kind;
+            match object {
+                FileSystemObject::File(file) => file.name.clone_from(&name),
+                FileSystemObject::Dir(dir) => {
+                    dir.name.clone_from(&name);
+                    dir.entries.retain(|entry| entry.name != "..");
+                    dir.entries.insert(DirectoryEntry {
+                        ino: lost_and_found,
+                        file_type: FileType::Directory,
+                        name: String::from(".."),
+                    });
+                }
+            }
+            objects.changed.insert(ino);
+            if let Some(dir) = objects.dir_mut(lost_and_found) {
+                dir.entries.insert(DirectoryEntry {
+                    ino,
+                    file_type,
+                    name,
+                });
+            }
+            info!(%ino, "Moved orphan to {LOST_AND_FOUND}");
+        }
+    }
+
+    /// Inode of `lost+found`, which is created if the root doesn't have it yet
+    fn lost_and_found(&self, objects: &mut Objects) -> Option<Inode> {
+        let root = objects.dir(FUSE_ROOT_ID)?;
+        if let Some(entry) = Objects::children(root).find(|entry| entry.name == LOST_AND_FOUND) {
+            if objects.dir(entry.ino).is_some() {
+                return Some(entry.ino);
+            }
+            warn!("{LOST_AND_FOUND} isn't a directory, leaving orphans where they are");
+            return None;
+        }
+        let ino = self.new_inode();
+        let now = SystemTime::now();
+        let dir = DirectoryObject {
+            attr: FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::Directory,
+                perm: 0o700,
+                nlink: 2,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: 512,
+                flags: 0,
+            },
+            entries: [
+                DirectoryEntry {
+                    ino,
+                    file_type: FileType::Directory,
+                    name: String::from("."),
+                },
+                DirectoryEntry {
+                    ino: FUSE_ROOT_ID,
+                    file_type: FileType::Directory,
+                    name: String::from(".."),
+                },
+            ]
+            .into_iter()
+            .collect(),
+            name: String::from(LOST_AND_FOUND),
+        };
+        objects.objects.insert(ino, FileSystemObject::Dir(dir));
+        objects.known.insert(ino);
+        objects.changed.insert(ino);
+        objects
+            .dir_mut(FUSE_ROOT_ID)?
+            .entries
+            .insert(DirectoryEntry {
+                ino,
+                file_type: FileType::Directory,
+                name: String::from(LOST_AND_FOUND),
+            });
+        Some(ino)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Problem, LOST_AND_FOUND};
+    use crate::cache::{memory_cache, Cache, CacheConfig, WhenFSCache};
+    use crate::calendar::memory::MemoryClient;
+    use crate::object::{DirectoryEntry, DirectoryObject, FileObject, FileSystemObject};
+    use crate::store::CalStore;
+    use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+    use std::time::SystemTime;
+
+    fn attr(ino: u64, kind: FileType, nlink: u32) -> FileAttr {
+        let now = SystemTime::now();
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm: 0o755,
+            nlink,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+
+    fn entry(ino: u64, file_type: FileType, name: &str) -> DirectoryEntry {
+        DirectoryEntry {
+            ino,
+            file_type,
+            name: String::from(name),
+        }
+    }
+
+    fn file(ino: u64, nlink: u32) -> FileSystemObject {
+        FileSystemObject::File(FileObject {
+            attr: attr(ino, FileType::RegularFile, nlink),
+            name: format!("file{ino}"),
+            data: Vec::new(),
+        })
+    }
+
+    async fn dir(cache: &WhenFSCache<CalStore<MemoryClient>>, ino: u64) -> DirectoryObject {
+        match &*cache.get(ino).await.unwrap().unwrap().read().unwrap() {
+            FileSystemObject::Dir(dir) => dir.clone(),
+            FileSystemObject::File(_) => panic!("expected a directory"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fsck_finds_and_repairs_problems() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        for _ in 0..4 {
+            cache.new_inode();
+        }
+        let root = DirectoryObject {
+            attr: attr(FUSE_ROOT_ID, FileType::Directory, 2),
+            entries: [
+                entry(FUSE_ROOT_ID, FileType::Directory, "."),
+                entry(2, FileType::RegularFile, "linked"),
+                entry(9, FileType::RegularFile, "dangling"),
+            ]
+            .into_iter()
+            .collect(),
+            name: String::from("root"),
+        };
+        cache
+            .insert_all(vec![
+                (FUSE_ROOT_ID, FileSystemObject::Dir(root)),
+                (2, file(2, 3)),
+                (3, file(3, 1)),
+            ])
+            .await
+            .unwrap();
+
+        let report = cache.fsck(false).await.unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::Dangling {
+                    parent: FUSE_ROOT_ID,
+                    name: String::from("dangling"),
+                    ino: 9,
+                },
+                Problem::Orphan { ino: 3 },
+                Problem::LinkCount {
+                    ino: 2,
+                    found: 3,
+                    expected: 1,
+                },
+            ]
+        );
+        assert_eq!(report.repaired, 0);
+
+        let report = cache.fsck(true).await.unwrap();
+        assert_eq!(report.problems.len(), 3);
+        assert!(cache.fsck(false).await.unwrap().problems.is_empty());
+        let root = dir(&cache, FUSE_ROOT_ID).await;
+        assert!(root.get_entry_by_name("dangling".as_ref()).is_none());
+        let lost_and_found = root.get_entry_by_name(LOST_AND_FOUND.as_ref()).unwrap();
+        let lost_and_found = dir(&cache, lost_and_found.ino).await;
+        assert_eq!(
+            lost_and_found.get_entry_by_name("#3".as_ref()).unwrap().ino,
+            3
+        );
+    }
+}
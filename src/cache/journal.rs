@@ -0,0 +1,290 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tracing::{debug, warn};
+
+/// Local write-ahead log of inode table transactions.
+///
+/// Switching the superblock to a new inode table root is what makes a transaction take
+/// effect, so a crash can't leave half a transaction in the table. The journal records the
+/// objects written and superseded by each transaction, so that whatever a crash left behind
+/// can be deleted.
+///
+/// The journal is locked for as long as it's open, since recovering it deletes objects that
+/// another process holding it may still be committing.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    next_txn: AtomicU64,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Record<TEntry> {
+    /// Objects of the transaction are stored, the table isn't switched yet
+    Prepared { txn: u64, written: Vec<TEntry> },
+    /// The table root is being switched, after which superseded objects can go
+    Committed {
+        txn: u64,
+        root: TEntry,
+        garbage: Vec<TEntry>,
+    },
+    /// Everything the transaction left behind is deleted
+    Done { txn: u64 },
+    /// The transaction failed and everything it wrote is deleted
+    Aborted { txn: u64 },
+}
+
+/// Entries to delete to finish what the journal's transactions started
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recovery<TEntry> {
+    /// Superseded by transactions the recovered root includes
+    pub replay: Vec<TEntry>,
+    /// Written by transactions the recovered root doesn't include
+    pub rollback: Vec<TEntry>,
+    /// Transactions after the recovered root were already cleaned up, so the root is stale
+    pub stale: bool,
+}
+
+impl<TEntry> Record<TEntry> {
+    fn txn(&self) -> u64 {
+        match self {
+            Self::Prepared { txn, .. }
+            | Self::Committed { txn, .. }
+            | Self::Done { txn }
+            | Self::Aborted { txn } => *txn,
+        }
+    }
+}
+
+impl Journal {
+    /// `$XDG_STATE_HOME/whenfs/<calendar-id>/journal`, falling back to `~/.local/state` like
+    /// the XDG spec
+    pub fn default_path(calendar_id: &str) -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_STATE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state"))
+            })?;
+        Some(base.join("whenfs").join(calendar_id).join("journal"))
+    }
+
+    /// Opens and locks a journal, returning the records already in it. Fails with
+    /// `WouldBlock` if another process has it open.
+    pub async fn open<TEntry: DeserializeOwned>(
+        path: PathBuf,
+    ) -> io::Result<(Self, Vec<Record<TEntry>>)> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        // Released when the file is closed, so a crashed process never keeps it
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let records: Vec<_> = tokio::fs::read_to_string(&path)
+            .await?
+            .lines()
+            // A crash while appending may leave the last line cut off
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        debug!(path = %path.display(), number_of_records = records.len(), "Opened journal");
+        Ok((
+            Self {
+                path,
+                file: Mutex::new(file),
+                next_txn: AtomicU64::new(1),
+            },
+            records,
+        ))
+    }
+
+    /// Appends a record and waits until it's on disk
+    pub async fn append<TEntry: Serialize>(&self, record: &Record<TEntry>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.sync_data().await
+    }
+
+    /// Forgets every record once nothing is left to recover, and starts over from `root`
+    pub async fn restart<TEntry: Serialize>(&self, root: TEntry) -> io::Result<()> {
+        {
+            let file = self.file.lock().await;
+            file.set_len(0).await?;
+            file.sync_all().await?;
+        }
+        self.next_txn.store(1, Ordering::SeqCst);
+        debug!(path = %self.path.display(), "Restarted journal");
+        self.append(&Record::Committed {
+            txn: 0,
+            root,
+            garbage: Vec::new(),
+        })
+        .await
+    }
+
+    /// Number for a new transaction
+    pub fn begin(&self) -> u64 {
+        self.next_txn.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Works out what to delete after mounting the root `is_root` matches, given the
+    /// records of the journal.
+    /// Commits are recorded in the order the table root was switched, so every transaction
+    /// committed up to the one that switched to `root` is part of it, and no other is.
+    ///
+    /// Returns `None` if no transaction switched to that root, in which case the journal
+    /// belongs to some other filesystem or version and mustn't be acted on.
+    pub fn recover<TEntry>(
+        records: Vec<Record<TEntry>>,
+        is_root: impl Fn(&TEntry) -> bool,
+    ) -> Option<Recovery<TEntry>> {
+        let mounted = records
+            .iter()
+            .position(|record| matches!(record, Record::Committed { root, .. } if is_root(root)))?;
+        let included: Vec<u64> = records[..=mounted]
+            .iter()
+            .filter(|record| matches!(record, Record::Committed { .. }))
+            .map(Record::txn)
+            .collect();
+        let done: Vec<u64> = records
+            .iter()
+            .filter(|record| matches!(record, Record::Done { .. }))
+            .map(Record::txn)
+            .collect();
+        let aborted: Vec<u64> = records
+            .iter()
+            .filter(|record| matches!(record, Record::Aborted { .. }))
+            .map(Record::txn)
+            .collect();
+        let mut recovery = Recovery {
+            replay: Vec::new(),
+            rollback: Vec::new(),
+            stale: false,
+        };
+        for (position, record) in records.into_iter().enumerate() {
+            let txn = record.txn();
+            let is_done = done.contains(&txn);
+            let is_finished = is_done || aborted.contains(&txn);
+            match record {
+                Record::Committed { garbage, .. } if position <= mounted && !is_finished => {
+                    recovery.replay.extend(garbage)
+                }
+                Record::Committed { .. } if position > mounted && is_done => recovery.stale = true,
+                Record::Prepared { written, .. } if !included.contains(&txn) && !is_finished => {
+                    recovery.rollback.extend(written)
+                }
+                _ => (),
+            }
+        }
+        if recovery.stale {
+            warn!("Mounted root is older than the last finished transaction");
+        }
+        Some(recovery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, Record, Recovery};
+
+    #[tokio::test]
+    async fn test_records_survive_reopening() {
+        let path = std::env::temp_dir()
+            .join(format!("whenfs-journal-{}", uuid::Uuid::new_v4()))
+            .join("journal");
+        let (journal, records) = Journal::open::<String>(path.clone()).await.unwrap();
+        assert!(records.is_empty());
+        let prepared = Record::Prepared {
+            txn: 1,
+            written: vec![String::from("object")],
+        };
+        journal.append(&prepared).await.unwrap();
+        drop(journal);
+
+        let (journal, records) = Journal::open::<String>(path.clone()).await.unwrap();
+        assert_eq!(records, vec![prepared]);
+        journal.restart(String::from("root")).await.unwrap();
+        drop(journal);
+        let (_, records) = Journal::open::<String>(path.clone()).await.unwrap();
+        assert_eq!(
+            records,
+            vec![Record::Committed {
+                txn: 0,
+                root: String::from("root"),
+                garbage: Vec::new(),
+            }]
+        );
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_open_journal_is_locked() {
+        let path = std::env::temp_dir()
+            .join(format!("whenfs-journal-{}", uuid::Uuid::new_v4()))
+            .join("journal");
+        let (journal, _) = Journal::open::<String>(path.clone()).await.unwrap();
+        let error = Journal::open::<String>(path.clone()).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        drop(journal);
+        Journal::open::<String>(path.clone()).await.unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_recover_replays_and_rolls_back() {
+        let records = vec![
+            Record::Prepared {
+                txn: 1,
+                written: vec!["a"],
+            },
+            Record::Committed {
+                txn: 1,
+                root: "root1",
+                garbage: vec!["old"],
+            },
+            Record::Prepared {
+                txn: 2,
+                written: vec!["b"],
+            },
+            Record::Committed {
+                txn: 2,
+                root: "root2",
+                garbage: vec!["a"],
+            },
+            Record::Prepared {
+                txn: 3,
+                written: vec!["c"],
+            },
+            Record::Committed {
+                txn: 3,
+                root: "root3",
+                garbage: vec!["root1"],
+            },
+            Record::Aborted { txn: 3 },
+        ];
+        // Crashed before transaction 2 was mounted, so its objects are dropped
+        assert_eq!(
+            Journal::recover(records, |root| *root == "root1"),
+            Some(Recovery {
+                replay: vec!["old"],
+                rollback: vec!["b"],
+                stale: false,
+            })
+        );
+        assert_eq!(Journal::recover(Vec::<Record<&str>>::new(), |_| true), None);
+    }
+}
@@ -0,0 +1,70 @@
+use super::Inode;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// One lock per inode, so changes to an inode happen one at a time while other inodes
+/// are changed concurrently. Locks only exist while someone holds or waits for them.
+#[derive(Debug, Default)]
+pub struct InodeLocks {
+    locks: DashMap<Inode, Arc<Mutex<()>>>,
+}
+
+#[derive(Debug)]
+pub struct InodeGuard<'a> {
+    ino: Inode,
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: &'a InodeLocks,
+}
+
+impl InodeLocks {
+    pub async fn lock(&self, ino: Inode) -> InodeGuard<'_> {
+        let lock = Arc::clone(&self.locks.entry(ino).or_default());
+        InodeGuard {
+            ino,
+            guard: Some(lock.lock_owned().await),
+            locks: self,
+        }
+    }
+
+    /// Locks several inodes, always in ascending order so that two callers can't deadlock
+    pub async fn lock_all(&self, mut inos: Vec<Inode>) -> Vec<InodeGuard<'_>> {
+        inos.sort_unstable();
+        inos.dedup();
+        let mut guards = Vec::with_capacity(inos.len());
+        for ino in inos {
+            guards.push(self.lock(ino).await);
+        }
+        guards
+    }
+}
+
+impl Drop for InodeGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        // Nobody else holds or waits for the lock if the map has the only reference
+        self.locks
+            .locks
+            .remove_if(&self.ino, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InodeLocks;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_locks_are_per_inode() {
+        let locks = InodeLocks::default();
+        let first = locks.lock(1).await;
+        // Another inode can be locked while 1 is held, but 1 itself can't
+        let _other = locks.lock(2).await;
+        let blocked = tokio::time::timeout(Duration::from_millis(10), locks.lock(1)).await;
+        assert!(blocked.is_err());
+
+        drop(first);
+        drop(locks.lock(1).await);
+        assert_eq!(locks.locks.len(), 1);
+    }
+}
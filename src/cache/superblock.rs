@@ -0,0 +1,242 @@
+use crate::store::Layout;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeSet;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Name of the superblock, which is also the sentinel of its event
+pub const SUPERBLOCK_NAME: &str = "WhenFS superblock";
+
+/// Newest format version this version of WhenFS reads and writes
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &str = "whenfs";
+
+/// Objects list their chunks in a manifest instead of chaining them
+const CHUNK_MANIFESTS: &str = "chunk-manifests";
+/// The inode table is split into shards below its root
+const SHARDED_INODE_TABLE: &str = "sharded-inode-table";
+/// Some objects have parity chunks
+const ERASURE_CODING: &str = "erasure-coding";
+/// Snapshots keep old objects alive, which a version that drops them would delete
+const SNAPSHOTS: &str = "snapshots";
+
+/// Features a filesystem can't be read without. A filesystem using any feature that isn't
+/// listed here is refused.
+const INCOMPAT_FEATURES: &[&str] = &[
+    CHUNK_MANIFESTS,
+    SHARDED_INODE_TABLE,
+    ERASURE_CODING,
+    SNAPSHOTS,
+];
+
+/// Describes a filesystem and points at the current root of its inode table.
+///
+/// The superblock is stored once when the filesystem is created and then overwritten in
+/// place, so it can always be found at the same event. Switching its root is what makes a
+/// transaction take effect.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Superblock<TEntry> {
+    magic: String,
+    pub version: u32,
+    /// Features older versions may ignore
+    pub compat: BTreeSet<String>,
+    /// Features every reader must understand
+    pub incompat: BTreeSet<String>,
+    pub uuid: Uuid,
+    pub created: DateTime<Utc>,
+    /// How new objects are written. Every chunk records how it was written, so objects
+    /// written with an older layout stay readable.
+    pub layout: Layout,
+    pub root: TEntry,
+    /// Earlier roots kept under a name, oldest first
+    #[serde(default = "Vec::new")]
+    pub snapshots: Vec<Snapshot<TEntry>>,
+}
+
+/// Root of the inode table at some point in time
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Snapshot<TEntry> {
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub root: TEntry,
+}
+
+/// Fields every format version keeps, read before anything else is trusted
+#[derive(Deserialize)]
+struct Header {
+    magic: String,
+    version: u32,
+    #[serde(default)]
+    incompat: BTreeSet<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum SuperblockError<E: std::error::Error> {
+    #[error("Storage error: {0}")]
+    Store(E),
+    #[error("Filesystem has format version {0}, but this version of WhenFS only reads up to {FORMAT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("Filesystem uses features this version of WhenFS doesn't support: {}", .0.join(", "))]
+    UnsupportedFeatures(Vec<String>),
+    #[error("Unreadable superblock: {0}")]
+    Corrupt(serde_json::Error),
+    #[error("Found several filesystems, with superblocks {}", .0.join(", "))]
+    Ambiguous(Vec<String>),
+    #[error("There already is a snapshot named {0:?}")]
+    SnapshotExists(String),
+    #[error("There is no snapshot named {0:?}")]
+    NoSuchSnapshot(String),
+}
+
+impl<TEntry> Superblock<TEntry> {
+    pub fn new(layout: Layout, root: TEntry) -> Self {
+        let mut superblock = Self {
+            magic: String::from(MAGIC),
+            version: FORMAT_VERSION,
+            compat: BTreeSet::new(),
+            incompat: [CHUNK_MANIFESTS, SHARDED_INODE_TABLE]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            uuid: Uuid::new_v4(),
+            created: Utc::now(),
+            layout,
+            root,
+            snapshots: Vec::new(),
+        };
+        superblock.set_layout(layout);
+        superblock
+    }
+
+    /// Records the layout new objects are written with, along with the features reading
+    /// them takes. Features are never dropped, as older objects may still use them.
+    pub fn set_layout(&mut self, layout: Layout) {
+        if layout.erasure.is_some() {
+            self.incompat.insert(String::from(ERASURE_CODING));
+        }
+        self.layout = layout;
+    }
+
+    pub fn snapshot(&self, name: &str) -> Option<&Snapshot<TEntry>> {
+        self.snapshots.iter().find(|snapshot| snapshot.name == name)
+    }
+
+    /// Records the current root as a snapshot, unless one already has that name
+    pub fn add_snapshot(&mut self, name: String) -> Option<&Snapshot<TEntry>>
+    where
+        TEntry: Clone,
+    {
+        if self.snapshot(&name).is_some() {
+            return None;
+        }
+        self.incompat.insert(String::from(SNAPSHOTS));
+        self.snapshots.push(Snapshot {
+            name,
+            created: Utc::now(),
+            root: self.root.clone(),
+        });
+        self.snapshots.last()
+    }
+
+    /// Adds the snapshots of `stored` this superblock doesn't have, e.g. because another
+    /// process created them, returning the ones added
+    pub fn merge_snapshots(&mut self, stored: Self) -> Vec<Snapshot<TEntry>>
+    where
+        TEntry: Clone,
+    {
+        let added: Vec<Snapshot<TEntry>> = stored
+            .snapshots
+            .into_iter()
+            .filter(|snapshot| self.snapshot(&snapshot.name).is_none())
+            .collect();
+        if !added.is_empty() {
+            self.incompat.insert(String::from(SNAPSHOTS));
+            self.snapshots.extend(added.iter().cloned());
+        }
+        added
+    }
+
+    /// Reads a superblock, refusing filesystems this version of WhenFS can't work with.
+    /// Returns `None` if `value` isn't a superblock at all.
+    pub fn parse<E: std::error::Error>(
+        value: serde_json::Value,
+    ) -> Result<Option<Self>, SuperblockError<E>>
+    where
+        TEntry: DeserializeOwned,
+    {
+        let Ok(header) = Header::deserialize(&value) else {
+            return Ok(None);
+        };
+        if header.magic != MAGIC {
+            return Ok(None);
+        }
+        if header.version > FORMAT_VERSION {
+            return Err(SuperblockError::UnsupportedVersion(header.version));
+        }
+        let unsupported: Vec<String> = header
+            .incompat
+            .into_iter()
+            .filter(|feature| !INCOMPAT_FEATURES.contains(&feature.as_str()))
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(SuperblockError::UnsupportedFeatures(unsupported));
+        }
+        serde_json::from_value(value)
+            .map(Some)
+            .map_err(SuperblockError::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Superblock, SuperblockError};
+    use crate::store::{Compression, Encoding, Encryption, Layout, Packing};
+
+    type Parsed = Result<Option<Superblock<String>>, SuperblockError<std::io::Error>>;
+
+    fn parse(value: serde_json::Value) -> Parsed {
+        Superblock::parse(value)
+    }
+
+    #[test]
+    fn test_parse() {
+        let superblock = Superblock::new(
+            Layout {
+                encoding: Encoding::JsonBase64,
+                compression: Compression::None,
+                encryption: Encryption::None,
+                packing: Packing::Description,
+                erasure: None,
+                chunk_size: 4096,
+            },
+            String::from("root"),
+        );
+        let value = serde_json::to_value(&superblock).unwrap();
+        assert_eq!(parse(value.clone()).unwrap(), Some(superblock));
+
+        // An inode table root from before superblocks existed
+        assert!(parse(serde_json::json!({ "shards": [] }))
+            .unwrap()
+            .is_none());
+
+        let mut newer = value.clone();
+        newer["version"] = 2.into();
+        assert!(matches!(
+            parse(newer),
+            Err(SuperblockError::UnsupportedVersion(2))
+        ));
+        let mut unknown = value;
+        unknown["incompat"]
+            .as_array_mut()
+            .unwrap()
+            .push("encryption".into());
+        match parse(unknown) {
+            Err(SuperblockError::UnsupportedFeatures(features)) => {
+                assert_eq!(features, vec![String::from("encryption")])
+            }
+            other => panic!("expected unsupported features, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use super::Inode;
+use crate::store::Store;
+use futures::future::try_join_all;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, info, warn};
+
+/// Number of shards new inode tables are split into. A write re-uploads one shard plus a
+/// root listing the shards, instead of the whole table.
+const SHARD_COUNT: usize = 64;
+
+/// Name of the root object, which is also the sentinel ending its event chain
+pub const ROOT_NAME: &str = "root event";
+
+/// Maps inodes to the entries their objects are stored under.
+///
+/// Inodes are spread over shards by `ino % shards.len()`, and every shard is stored as its
+/// own object. The root object only lists where each shard is stored.
+#[derive(Debug)]
+pub struct InodeTable<TStore: Store> {
+    shards: Vec<Shard<TStore::Entry>>,
+    root: TStore::Entry,
+}
+
+#[derive(Debug)]
+struct Shard<TEntry> {
+    inodes: BTreeMap<Inode, TEntry>,
+    /// Where the shard is stored, unless it has never been written
+    stored: Option<TEntry>,
+}
+
+/// A change to the table whose objects are stored, but that hasn't taken effect yet
+#[derive(Debug)]
+pub struct Pending<TEntry> {
+    changed: BTreeMap<usize, BTreeMap<Inode, TEntry>>,
+    stored: Vec<(usize, TEntry)>,
+    root: TEntry,
+    /// What applying the change replaces
+    pub switch: Switch<TEntry>,
+}
+
+/// What a change to the table replaced
+#[derive(Debug)]
+pub struct Switch<TEntry> {
+    /// Objects the changed inodes pointed at before
+    pub superseded: Vec<TEntry>,
+    /// The table's own objects that the new root no longer refers to
+    pub garbage: Vec<TEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct TableRoot<TEntry> {
+    shards: Vec<Option<TEntry>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, bound = "TEntry: DeserializeOwned")]
+enum StoredRoot<TEntry> {
+    Sharded(TableRoot<TEntry>),
+    /// The whole table in one object, as written by older versions of WhenFS. Keys are
+    /// strings because untagged enums can't parse JSON object keys as numbers.
+    Flat(HashMap<String, TEntry>),
+}
+
+impl<TEntry: Clone> Pending<TEntry> {
+    /// Root the table will have once the change is applied
+    pub fn root(&self) -> &TEntry {
+        &self.root
+    }
+
+    /// Every object stored for the change, to delete if it's abandoned
+    pub fn written(&self) -> Vec<TEntry> {
+        self.stored
+            .iter()
+            .map(|(_, entry)| entry)
+            .chain([&self.root])
+            .cloned()
+            .collect()
+    }
+}
+
+impl<TStore: Store> InodeTable<TStore> {
+    pub async fn create(store: &TStore) -> Result<Self, TStore::Error> {
+        let shards: Vec<Shard<TStore::Entry>> = (0..SHARD_COUNT)
+            .map(|_| Shard {
+                inodes: BTreeMap::new(),
+                stored: None,
+            })
+            .collect();
+        let root = store
+            .store(&Self::table_root(&shards), ROOT_NAME.to_string())
+            .await?;
+        Ok(Self { shards, root })
+    }
+
+    pub async fn load(store: &TStore, root: TStore::Entry) -> Result<Self, TStore::Error> {
+        let shards = match store.retrieve(root.clone()).await? {
+            StoredRoot::Sharded(table_root) => {
+                debug!(
+                    number_of_shards = table_root.shards.len(),
+                    "Downloading inode table shards"
+                );
+                try_join_all(table_root.shards.into_iter().map(
+                    |stored: Option<TStore::Entry>| async move {
+                        let inodes = match &stored {
+                            Some(entry) => store.retrieve(entry.clone()).await?,
+                            None => BTreeMap::new(),
+                        };
+                        Ok(Shard { inodes, stored })
+                    },
+                ))
+                .await?
+            }
+            StoredRoot::Flat(flat) => {
+                info!(
+                    number_of_inodes = flat.len(),
+                    "Migrating flat inode table, shards are written with the next change"
+                );
+                let mut shards: Vec<Shard<TStore::Entry>> = (0..SHARD_COUNT)
+                    .map(|_| Shard {
+                        inodes: BTreeMap::new(),
+                        stored: None,
+                    })
+                    .collect();
+                for (ino, entry) in flat {
+                    match ino.parse::<Inode>() {
+                        Ok(ino) => {
+                            shards[Self::shard_of(ino, SHARD_COUNT)]
+                                .inodes
+                                .insert(ino, entry);
+                        }
+                        Err(error) => warn!(%ino, %error, "Skipping unreadable inode"),
+                    }
+                }
+                shards
+            }
+        };
+        Ok(Self { shards, root })
+    }
+
+    pub fn get(&self, ino: Inode) -> Option<&TStore::Entry> {
+        self.shards[Self::shard_of(ino, self.shards.len())]
+            .inodes
+            .get(&ino)
+    }
+
+    /// Points an inode at a new entry and stores the affected shard and root, returning the
+    /// entry the inode pointed at before.
+    #[cfg(test)]
+    pub async fn insert(
+        &mut self,
+        store: &TStore,
+        ino: Inode,
+        entry: TStore::Entry,
+    ) -> Result<Option<TStore::Entry>, TStore::Error> {
+        let pending = self.prepare(store, vec![(ino, Some(entry))]).await?;
+        Ok(self.apply(pending).superseded.into_iter().next())
+    }
+
+    /// Stores what pointing inodes at new entries, or dropping them for `None`, takes: the
+    /// affected shards as new objects, then a new root listing them. The table doesn't
+    /// change until the result is applied, and on failure nothing is left behind.
+    pub async fn prepare(
+        &self,
+        store: &TStore,
+        changes: Vec<(Inode, Option<TStore::Entry>)>,
+    ) -> Result<Pending<TStore::Entry>, TStore::Error> {
+        let mut changed: BTreeMap<usize, BTreeMap<Inode, TStore::Entry>> = BTreeMap::new();
+        let mut superseded = Vec::new();
+        for (ino, entry) in changes {
+            let shard = Self::shard_of(ino, self.shards.len());
+            let inodes = changed
+                .entry(shard)
+                .or_insert_with(|| self.shards[shard].inodes.clone());
+            superseded.extend(match entry {
+                Some(entry) => inodes.insert(ino, entry),
+                None => inodes.remove(&ino),
+            });
+        }
+        // Shards of a migrated flat table are written out with the first change
+        for (i, shard) in self.shards.iter().enumerate() {
+            if shard.stored.is_none() && !shard.inodes.is_empty() {
+                changed.entry(i).or_insert_with(|| shard.inodes.clone());
+            }
+        }
+
+        let mut written = Vec::new();
+        let stored = self.store_shards(store, &changed, &mut written).await;
+        let root = match stored {
+            Ok(stored) => {
+                let mut shards: Vec<Option<&TStore::Entry>> = self
+                    .shards
+                    .iter()
+                    .map(|shard| shard.stored.as_ref())
+                    .collect();
+                for (i, entry) in &stored {
+                    shards[*i] = Some(entry);
+                }
+                store
+                    .store(&TableRoot { shards }, ROOT_NAME.to_string())
+                    .await
+                    .map(|root| (stored, root))
+            }
+            Err(error) => Err(error),
+        };
+        let (stored, root) = match root {
+            Ok(root) => root,
+            Err(error) => {
+                for entry in written {
+                    if let Err(error) = store.delete(entry).await {
+                        warn!(%error, "Failed to delete shard of failed change");
+                    }
+                }
+                return Err(error);
+            }
+        };
+
+        let garbage = std::iter::once(&self.root)
+            .chain(changed.keys().flat_map(|&i| &self.shards[i].stored))
+            .cloned()
+            .collect();
+        Ok(Pending {
+            changed,
+            stored,
+            root,
+            switch: Switch {
+                superseded,
+                garbage,
+            },
+        })
+    }
+
+    /// Switches the table over to a prepared change, which must be the only change prepared
+    /// since the last one was applied
+    pub fn apply(&mut self, pending: Pending<TStore::Entry>) -> Switch<TStore::Entry> {
+        self.root = pending.root;
+        for ((i, inodes), (_, entry)) in pending.changed.into_iter().zip(pending.stored) {
+            let shard = &mut self.shards[i];
+            shard.inodes = inodes;
+            shard.stored = Some(entry);
+            debug!(shard = i, "Switched inode table shard");
+        }
+        pending.switch
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Inode, &TStore::Entry)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.inodes.iter().map(|(ino, entry)| (*ino, entry)))
+    }
+
+    pub fn max_inode(&self) -> Option<Inode> {
+        self.iter().map(|(ino, _)| ino).max()
+    }
+
+    pub fn root(&self) -> &TStore::Entry {
+        &self.root
+    }
+
+    /// Entries of the table's own objects: the root and every stored shard
+    pub fn metadata(&self) -> impl Iterator<Item = &TStore::Entry> {
+        std::iter::once(&self.root).chain(self.shards.iter().flat_map(|shard| &shard.stored))
+    }
+
+    /// Entries of the table's own objects and of every object in it
+    pub fn reachable(&self) -> impl Iterator<Item = &TStore::Entry> {
+        self.metadata().chain(self.iter().map(|(_, entry)| entry))
+    }
+
+    async fn store_shards(
+        &self,
+        store: &TStore,
+        changed: &BTreeMap<usize, BTreeMap<Inode, TStore::Entry>>,
+        written: &mut Vec<TStore::Entry>,
+    ) -> Result<Vec<(usize, TStore::Entry)>, TStore::Error> {
+        let mut stored = Vec::with_capacity(changed.len());
+        for (i, inodes) in changed {
+            let entry = store.store(inodes, format!("inode shard {i}")).await?;
+            written.push(entry.clone());
+            stored.push((*i, entry));
+        }
+        Ok(stored)
+    }
+
+    fn table_root(shards: &[Shard<TStore::Entry>]) -> TableRoot<&TStore::Entry> {
+        TableRoot {
+            shards: shards.iter().map(|shard| shard.stored.as_ref()).collect(),
+        }
+    }
+
+    fn shard_of(ino: Inode, shard_count: usize) -> usize {
+        (ino % shard_count as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InodeTable, ROOT_NAME, SHARD_COUNT};
+    use crate::{
+        calendar::{memory::MemoryClient, CalendarClient},
+        store::{CalStore, Store},
+    };
+    use std::collections::HashMap;
+
+    async fn memory_store() -> CalStore<MemoryClient> {
+        let client = MemoryClient::default();
+        let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+        CalStore::new(client, calendar)
+    }
+
+    #[tokio::test]
+    async fn test_insert_rewrites_one_shard() {
+        let store = memory_store().await;
+        let mut table = InodeTable::create(&store).await.unwrap();
+        for ino in 1..=SHARD_COUNT as u64 {
+            let entry = store.store(&ino, format!("file {ino}")).await.unwrap();
+            table.insert(&store, ino, entry).await.unwrap();
+        }
+        let before: Vec<_> = table.shards.iter().map(|s| s.stored.clone()).collect();
+        let entry = store.store(&"changed", "file 3".into()).await.unwrap();
+        table.insert(&store, 3, entry.clone()).await.unwrap();
+        let changed: Vec<usize> = table
+            .shards
+            .iter()
+            .zip(&before)
+            .enumerate()
+            .filter(|(_, (shard, before))| shard.stored != **before)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(changed, vec![3]);
+
+        let loaded = InodeTable::load(&store, table.root().clone())
+            .await
+            .unwrap();
+        assert_eq!(loaded.get(3), Some(&entry));
+        assert_eq!(loaded.max_inode(), Some(SHARD_COUNT as u64));
+    }
+
+    #[tokio::test]
+    async fn test_load_flat_table() {
+        let store = memory_store().await;
+        let mut flat = HashMap::new();
+        for ino in [1u64, 2, 70] {
+            let entry = store.store(&ino, format!("file {ino}")).await.unwrap();
+            flat.insert(ino, entry);
+        }
+        let root = store.store(&flat, ROOT_NAME.into()).await.unwrap();
+        let mut table = InodeTable::load(&store, root).await.unwrap();
+        assert_eq!(table.get(70), flat.get(&70));
+        assert!(table.shards.iter().all(|shard| shard.stored.is_none()));
+
+        // The first change writes out every shard that holds inodes
+        let entry = store.store(&"new", "file 3".into()).await.unwrap();
+        table.insert(&store, 3, entry).await.unwrap();
+        assert_eq!(table.metadata().count(), 1 + 4);
+        let loaded = InodeTable::load(&store, table.root().clone())
+            .await
+            .unwrap();
+        assert_eq!(loaded.iter().count(), 4);
+    }
+}
@@ -1,17 +1,19 @@
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{error::Error, fmt::Debug, hash::Hash, str::FromStr};
+use std::{collections::BTreeMap, error::Error, fmt::Debug, hash::Hash, str::FromStr};
 
 pub mod gcal;
+#[cfg(test)]
+pub mod memory;
 
-#[async_trait(?Send)]
+#[async_trait]
 pub trait CalendarClient
 where
-    Self: Debug,
+    Self: Debug + Send + Sync,
 {
-    type Calendar: Calendar;
-    type Event: Event + DeserializeOwned + Serialize;
+    type Calendar: Calendar + Send + Sync;
+    type Event: Event + DeserializeOwned + Serialize + Send + Sync;
     type Error: Debug + Error + Sync + Send;
 
     async fn create_calendar(&self, name: String) -> Result<Self::Calendar, Self::Error>;
@@ -39,6 +41,18 @@ where
         event_id: &<Self::Event as Event>::Id,
     ) -> Result<Self::Event, Self::Error>;
 
+    /// Fetches every event in the calendar
+    async fn list_events(&self, calendar: &Self::Calendar)
+        -> Result<Vec<Self::Event>, Self::Error>;
+
+    /// Fetches the events whose private extended property `key` is set to `value`
+    async fn search_events(
+        &self,
+        calendar: &Self::Calendar,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<Self::Event>, Self::Error>;
+
     async fn update_event(
         &self,
         calendar: &Self::Calendar,
@@ -60,7 +74,7 @@ pub trait Event
 where
     Self: Clone + Hash + Eq + Debug,
 {
-    type Id: From<String> + ToString + Debug + Clone;
+    type Id: From<String> + ToString + Debug + Clone + Send + Sync;
 
     fn id(&self) -> &Self::Id;
 
@@ -83,10 +97,17 @@ pub struct CalendarEventDetails {
     pub location: String,
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
+    /// Private key/value properties attached to the event
+    #[serde(default)]
+    pub extended_properties: BTreeMap<String, String>,
 }
 
 pub struct CalendarLimits {
     pub summary: usize,
     pub description: usize,
     pub location: usize,
+    /// Maximum length of a single extended property value
+    pub extended_property: usize,
+    /// Maximum number of extended properties per event
+    pub extended_properties: usize,
 }
@@ -1,6 +1,9 @@
 use self::{
     api::ApiAction,
-    types::{CreateCalendar, CreateEvent, DeleteEvent, Endpoint, GCal, GCalEvent, UpdateEvent},
+    types::{
+        CreateCalendar, CreateEvent, DeleteEvent, Endpoint, GCal, GCalEvent, ListEvents,
+        UpdateEvent,
+    },
 };
 use super::{Calendar, CalendarClient, CalendarEventDetails, CalendarLimits, Event};
 use crate::calendar::gcal::types::GetEvent;
@@ -39,6 +42,9 @@ static LIMITS: CalendarLimits = CalendarLimits {
     summary: 512,
     description: 4096,
     location: 512,
+    extended_property: 1024,
+    // Google allows up to 32kB of properties per event; stay well clear of that
+    extended_properties: 8,
 };
 
 impl GCalClient {
@@ -102,9 +108,31 @@ impl GCalClient {
         .await;
         Ok(handled)
     }
+
+    /// Fetches the calendar's events one page at a time, optionally only those with a
+    /// private extended property given as `key=value`
+    async fn list_pages(
+        &self,
+        calendar: &GCal,
+        private_property: Option<String>,
+    ) -> Result<Vec<GCalEvent>, GCalError> {
+        let mut events = Vec::new();
+        let mut page_token = None;
+        loop {
+            let action = ListEvents::new(calendar.id.clone(), page_token, private_property.clone());
+            let (page, next_page_token) =
+                ListEvents::to_abstract(self.execute_api_action(action).await?);
+            trace!(number_of_events = page.len(), "Listed page of events");
+            events.extend(page);
+            match next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(events),
+            }
+        }
+    }
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl CalendarClient for GCalClient {
     type Calendar = GCal;
     type Event = GCalEvent;
@@ -135,6 +163,7 @@ impl CalendarClient for GCalClient {
             event.location,
             event.start,
             event.end,
+            event.extended_properties,
         );
         let event = self.execute_api_action(action).await?;
         Ok(CreateEvent::to_abstract(event))
@@ -145,15 +174,14 @@ impl CalendarClient for GCalClient {
         calendar: &Self::Calendar,
         events: Vec<CalendarEventDetails>,
     ) -> Result<Vec<Self::Event>, Self::Error> {
-        Ok(join_all(
+        join_all(
             events
                 .into_iter()
                 .map(|event| self.create_event(calendar, event)),
         )
         .await
         .into_iter()
-        .map(|result| result.unwrap())
-        .collect())
+        .collect()
     }
 
     /// Fetches the CalendarEvent with the given CalendarEventID
@@ -167,21 +195,40 @@ impl CalendarClient for GCalClient {
         Ok(GetEvent::to_abstract(event))
     }
 
+    /// Fetches every event in the calendar, one page at a time
+    async fn list_events(
+        &self,
+        calendar: &Self::Calendar,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        self.list_pages(calendar, None).await
+    }
+
+    async fn search_events(
+        &self,
+        calendar: &Self::Calendar,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        self.list_pages(calendar, Some(format!("{key}={value}")))
+            .await
+    }
+
     async fn update_event(
         &self,
         calendar: &Self::Calendar,
         event_id: &<Self::Event as Event>::Id,
         details: CalendarEventDetails,
     ) -> Result<Self::Event, Self::Error> {
-        let action = UpdateEvent::new(
-            calendar.id.clone(),
-            event_id.clone(),
-            details.summary,
-            details.description,
-            details.location,
-            details.start,
-            details.end,
-        );
+        let action = UpdateEvent {
+            calendar_id: calendar.id.clone(),
+            event_id: event_id.clone(),
+            summary: details.summary,
+            description: details.description,
+            location: details.location,
+            start: details.start,
+            end: details.end,
+            extended_properties: details.extended_properties,
+        };
         let updated = self.execute_api_action(action).await?;
         Ok(UpdateEvent::to_abstract(updated))
     }
@@ -192,8 +239,8 @@ impl CalendarClient for GCalClient {
         event_id: &<Self::Event as Event>::Id,
     ) -> Result<(), Self::Error> {
         let action = DeleteEvent::new(calendar.id.clone(), event_id.clone());
-        let deleted = self.execute_api_action(action).await?;
-        Ok(DeleteEvent::to_abstract(deleted))
+        self.execute_api_action(action).await?;
+        Ok(())
     }
 
     async fn close(&self) {}
@@ -260,6 +307,7 @@ mod tests {
             location: "location".to_string(),
             start: now,
             end: later,
+            extended_properties: Default::default(),
         };
         let created = client
             .create_event(&calendar, details.clone())
@@ -1,22 +1,22 @@
 use super::types::{
     CreateCalendar, CreateCalendarBody, CreateCalendarResponse, CreateEvent, CreateEventBody,
-    CreateEventResponse, DeleteEvent, Endpoint, GCal, GCalEvent, GetEvent, GetEventResponse,
-    UpdateEvent, UpdateEventBody, UpdateEventResponse,
+    CreateEventResponse, DeleteEvent, Endpoint, ExtendedProperties, GCal, GCalEvent, GetEvent,
+    GetEventResponse, ListEvents, ListEventsResponse, UpdateEvent, UpdateEventBody,
+    UpdateEventResponse,
 };
 use crate::calendar::CalendarEventDetails;
 use async_trait::async_trait;
 use reqwest::Method;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
-use tracing::trace;
 
-#[async_trait(?Send)]
+#[async_trait]
 pub trait ApiAction
 where
-    Self: Display,
+    Self: Display + Send,
 {
     type BodyType: Serialize;
-    type ResponseType: for<'de> Deserialize<'de>;
+    type ResponseType: for<'de> Deserialize<'de> + Send;
     type CalendarReturnType;
 
     fn endpoint(&self) -> Endpoint;
@@ -34,7 +34,7 @@ where
     fn to_abstract(response: Self::ResponseType) -> Self::CalendarReturnType;
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl ApiAction for CreateCalendar {
     type BodyType = CreateCalendarBody;
     type ResponseType = CreateCalendarResponse;
@@ -59,7 +59,7 @@ impl ApiAction for CreateCalendar {
     }
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl ApiAction for CreateEvent {
     type BodyType = CreateEventBody;
     type ResponseType = CreateEventResponse;
@@ -80,6 +80,7 @@ impl ApiAction for CreateEvent {
             location: self.location,
             start: self.start.into(),
             end: self.end.into(),
+            extended_properties: ExtendedProperties::from_private(self.extended_properties),
         })
     }
 
@@ -92,12 +93,16 @@ impl ApiAction for CreateEvent {
                 location: response.location,
                 start: response.start.into(),
                 end: response.end.into(),
+                extended_properties: response
+                    .extended_properties
+                    .map(|properties| properties.private)
+                    .unwrap_or_default(),
             },
         }
     }
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl ApiAction for GetEvent {
     type BodyType = ();
     type ResponseType = GetEventResponse;
@@ -124,12 +129,48 @@ impl ApiAction for GetEvent {
                 location: response.location,
                 start: response.start.into(),
                 end: response.end.into(),
+                extended_properties: response
+                    .extended_properties
+                    .map(|properties| properties.private)
+                    .unwrap_or_default(),
             },
         }
     }
 }
 
-#[async_trait(?Send)]
+#[async_trait]
+impl ApiAction for ListEvents {
+    type BodyType = ();
+    type ResponseType = ListEventsResponse;
+    type CalendarReturnType = (Vec<GCalEvent>, Option<String>);
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::events_page(
+            &self.calendar_id,
+            self.page_token.as_ref(),
+            self.private_property.as_ref(),
+        )
+    }
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn body(self) -> Option<Self::BodyType> {
+        None
+    }
+
+    fn to_abstract(response: Self::ResponseType) -> Self::CalendarReturnType {
+        let events = response
+            .items
+            .into_iter()
+            .map(GetEvent::to_abstract)
+            .collect();
+        (events, response.next_page_token)
+    }
+}
+
+#[async_trait]
 impl ApiAction for DeleteEvent {
     type BodyType = ();
 
@@ -154,7 +195,7 @@ impl ApiAction for DeleteEvent {
     fn to_abstract(_response: Self::ResponseType) -> Self::CalendarReturnType {}
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl ApiAction for UpdateEvent {
     type BodyType = UpdateEventBody;
 
@@ -177,6 +218,7 @@ impl ApiAction for UpdateEvent {
             location: self.location,
             start: self.start.into(),
             end: self.end.into(),
+            extended_properties: ExtendedProperties::from_private(self.extended_properties),
         })
     }
 
@@ -189,6 +231,10 @@ impl ApiAction for UpdateEvent {
                 location: response.location,
                 start: response.start.into(),
                 end: response.end.into(),
+                extended_properties: response
+                    .extended_properties
+                    .map(|properties| properties.private)
+                    .unwrap_or_default(),
             },
         }
     }
@@ -2,6 +2,7 @@ use crate::calendar::CalendarEventDetails;
 use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
 use derive_more::{Constructor, Display};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Constructor, Display)]
 #[display(fmt = "CreateCalendar {summary}")]
@@ -29,6 +30,7 @@ pub struct CreateEvent {
     pub location: String,
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
+    pub extended_properties: BTreeMap<String, String>,
 }
 
 #[derive(Serialize)]
@@ -38,16 +40,24 @@ pub struct CreateEventBody {
     pub location: String,
     pub start: EventDateTime,
     pub end: EventDateTime,
+    #[serde(rename = "extendedProperties", skip_serializing_if = "Option::is_none")]
+    pub extended_properties: Option<ExtendedProperties>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct CreateEventResponse {
     pub id: String,
+    // Google leaves out text fields that are empty
+    #[serde(default)]
     pub summary: String,
+    #[serde(default)]
     pub description: String,
+    #[serde(default)]
     pub location: String,
     pub start: EventDateTime,
     pub end: EventDateTime,
+    #[serde(rename = "extendedProperties", default)]
+    pub extended_properties: Option<ExtendedProperties>,
 }
 
 #[derive(Constructor, Display)]
@@ -60,11 +70,34 @@ pub struct GetEvent {
 #[derive(Deserialize, Debug)]
 pub struct GetEventResponse {
     pub id: String,
+    // Google leaves out text fields that are empty
+    #[serde(default)]
     pub summary: String,
+    #[serde(default)]
     pub description: String,
+    #[serde(default)]
     pub location: String,
     pub start: EventDateTime,
     pub end: EventDateTime,
+    #[serde(rename = "extendedProperties", default)]
+    pub extended_properties: Option<ExtendedProperties>,
+}
+
+#[derive(Constructor, Display)]
+#[display(fmt = "ListEvents {calendar_id}")]
+pub struct ListEvents {
+    pub calendar_id: String,
+    pub page_token: Option<String>,
+    /// Only list events with this private extended property, as `key=value`
+    pub private_property: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListEventsResponse {
+    #[serde(default)]
+    pub items: Vec<GetEventResponse>,
+    #[serde(rename = "nextPageToken")]
+    pub next_page_token: Option<String>,
 }
 
 #[derive(Constructor, Display)]
@@ -74,7 +107,7 @@ pub struct DeleteEvent {
     pub event_id: String,
 }
 
-#[derive(Constructor, Display)]
+#[derive(Display)]
 #[display(fmt = "UpdateEvent {event_id} {location}")]
 pub struct UpdateEvent {
     pub calendar_id: String,
@@ -84,6 +117,7 @@ pub struct UpdateEvent {
     pub location: String,
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
+    pub extended_properties: BTreeMap<String, String>,
 }
 
 #[derive(Serialize)]
@@ -93,16 +127,24 @@ pub struct UpdateEventBody {
     pub location: String,
     pub start: EventDateTime,
     pub end: EventDateTime,
+    #[serde(rename = "extendedProperties", skip_serializing_if = "Option::is_none")]
+    pub extended_properties: Option<ExtendedProperties>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct UpdateEventResponse {
     pub id: String,
+    // Google leaves out text fields that are empty
+    #[serde(default)]
     pub summary: String,
+    #[serde(default)]
     pub description: String,
+    #[serde(default)]
     pub location: String,
     pub start: EventDateTime,
     pub end: EventDateTime,
+    #[serde(rename = "extendedProperties", default)]
+    pub extended_properties: Option<ExtendedProperties>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -117,6 +159,18 @@ pub struct EventDateTime {
     pub time_zone: Option<String>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedProperties {
+    #[serde(default)]
+    pub private: BTreeMap<String, String>,
+}
+
+impl ExtendedProperties {
+    pub fn from_private(private: BTreeMap<String, String>) -> Option<Self> {
+        (!private.is_empty()).then_some(Self { private })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct GCal {
     pub id: String,
@@ -142,6 +196,25 @@ impl Endpoint {
         Self(format!("{}/{}/events", Self::BASE_URL, id))
     }
 
+    pub fn events_page(
+        calendar_id: &String,
+        page_token: Option<&String>,
+        private_property: Option<&String>,
+    ) -> Self {
+        let mut endpoint = format!("{}/{}/events?maxResults=2500", Self::BASE_URL, calendar_id);
+        if let Some(page_token) = page_token {
+            let page_token: String =
+                url::form_urlencoded::byte_serialize(page_token.as_bytes()).collect();
+            endpoint.push_str(&format!("&pageToken={page_token}"));
+        }
+        if let Some(property) = private_property {
+            let property: String =
+                url::form_urlencoded::byte_serialize(property.as_bytes()).collect();
+            endpoint.push_str(&format!("&privateExtendedProperty={property}"));
+        }
+        Self(endpoint)
+    }
+
     pub fn event(calendar_id: &String, event_id: &String) -> Self {
         Self(format!(
             "{}/{}/events/{}",
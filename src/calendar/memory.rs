@@ -0,0 +1,245 @@
+use super::{Calendar, CalendarClient, CalendarEventDetails, CalendarLimits, Event};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use thiserror::Error;
+
+/// In-memory calendar used to exercise the layers above `CalendarClient` in tests
+#[derive(Debug, Default)]
+pub struct MemoryClient {
+    events: Mutex<HashMap<String, MemoryEvent>>,
+    next_id: AtomicU64,
+    gets: AtomicU64,
+    lose_update_responses: AtomicBool,
+    offline: AtomicBool,
+    refused: AtomicU64,
+    creates_left: Mutex<Option<u64>>,
+}
+
+#[derive(Debug, Error)]
+pub enum MemoryError {
+    #[error("Event {0} not found")]
+    NotFound(String),
+    #[error("Response to updating event {0} was lost")]
+    LostResponse(String),
+    #[error("Calendar is offline")]
+    Offline,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryCalendar {
+    pub id: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct MemoryEvent {
+    pub id: String,
+    pub details: CalendarEventDetails,
+}
+
+// Small enough that modest test objects span several events
+static LIMITS: CalendarLimits = CalendarLimits {
+    summary: 64,
+    description: 128,
+    location: 256,
+    extended_property: 32,
+    extended_properties: 2,
+};
+
+impl MemoryClient {
+    pub fn event_ids(&self) -> Vec<String> {
+        self.events.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// How many events have been fetched by ID
+    pub fn gets(&self) -> u64 {
+        self.gets.load(Ordering::SeqCst)
+    }
+
+    /// Makes updates fail after they were applied, as when the response to them is lost
+    pub fn lose_update_responses(&self, lose: bool) {
+        self.lose_update_responses.store(lose, Ordering::SeqCst);
+    }
+
+    /// Makes creating events fail, as during an outage
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::SeqCst);
+    }
+
+    /// Makes creating events fail as if offline once `n` more have been created
+    pub fn fail_creates_after(&self, n: u64) {
+        *self.creates_left.lock().unwrap() = Some(n);
+    }
+
+    /// How many requests failed because the calendar was offline
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::SeqCst)
+    }
+
+    /// Edits a stored event behind the store's back
+    pub fn tamper(&self, id: &str, f: impl FnOnce(&mut CalendarEventDetails)) {
+        f(&mut self.events.lock().unwrap().get_mut(id).unwrap().details)
+    }
+}
+
+#[async_trait]
+impl CalendarClient for MemoryClient {
+    type Calendar = MemoryCalendar;
+    type Event = MemoryEvent;
+    type Error = MemoryError;
+
+    async fn create_calendar(&self, name: String) -> Result<Self::Calendar, Self::Error> {
+        Ok(MemoryCalendar { id: name })
+    }
+
+    async fn calendar_from_id(
+        &self,
+        id: <Self::Calendar as Calendar>::Id,
+    ) -> Result<Self::Calendar, Self::Error> {
+        Ok(MemoryCalendar { id })
+    }
+
+    async fn create_event(
+        &self,
+        _calendar: &Self::Calendar,
+        details: CalendarEventDetails,
+    ) -> Result<Self::Event, Self::Error> {
+        let out_of_creates = match self.creates_left.lock().unwrap().as_mut() {
+            Some(0) => true,
+            Some(left) => {
+                *left -= 1;
+                false
+            }
+            None => false,
+        };
+        if out_of_creates || self.offline.load(Ordering::SeqCst) {
+            self.refused.fetch_add(1, Ordering::SeqCst);
+            return Err(MemoryError::Offline);
+        }
+        let id = format!("event{}", self.next_id.fetch_add(1, Ordering::SeqCst));
+        let event = MemoryEvent { id, details };
+        self.events
+            .lock()
+            .unwrap()
+            .insert(event.id.clone(), event.clone());
+        Ok(event)
+    }
+
+    async fn create_events(
+        &self,
+        calendar: &Self::Calendar,
+        events: Vec<CalendarEventDetails>,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        let mut created = Vec::with_capacity(events.len());
+        for details in events {
+            created.push(self.create_event(calendar, details).await?);
+        }
+        Ok(created)
+    }
+
+    async fn get_event_by_id(
+        &self,
+        _calendar: &Self::Calendar,
+        event_id: &<Self::Event as Event>::Id,
+    ) -> Result<Self::Event, Self::Error> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        self.events
+            .lock()
+            .unwrap()
+            .get(event_id)
+            .cloned()
+            .ok_or_else(|| MemoryError::NotFound(event_id.clone()))
+    }
+
+    async fn list_events(
+        &self,
+        _calendar: &Self::Calendar,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        Ok(self.events.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn search_events(
+        &self,
+        _calendar: &Self::Calendar,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        Ok(self
+            .events
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|event| {
+                event
+                    .details
+                    .extended_properties
+                    .get(key)
+                    .map(String::as_str)
+                    == Some(value)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn update_event(
+        &self,
+        _calendar: &Self::Calendar,
+        event_id: &<Self::Event as Event>::Id,
+        details: CalendarEventDetails,
+    ) -> Result<Self::Event, Self::Error> {
+        let mut events = self.events.lock().unwrap();
+        let event = events
+            .get_mut(event_id)
+            .ok_or_else(|| MemoryError::NotFound(event_id.clone()))?;
+        event.details = details;
+        if self.lose_update_responses.load(Ordering::SeqCst) {
+            return Err(MemoryError::LostResponse(event_id.clone()));
+        }
+        Ok(event.clone())
+    }
+
+    async fn delete_event(
+        &self,
+        _calendar: &Self::Calendar,
+        event_id: &<Self::Event as Event>::Id,
+    ) -> Result<(), Self::Error> {
+        self.events
+            .lock()
+            .unwrap()
+            .remove(event_id)
+            .map(|_| ())
+            .ok_or_else(|| MemoryError::NotFound(event_id.clone()))
+    }
+
+    async fn close(&self) {}
+
+    fn limits(&self) -> &'static CalendarLimits {
+        &LIMITS
+    }
+}
+
+impl Event for MemoryEvent {
+    type Id = String;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn details(&self) -> &CalendarEventDetails {
+        &self.details
+    }
+}
+
+impl Calendar for MemoryCalendar {
+    type Id = String;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+}
@@ -1,129 +1,448 @@
-use crate::cache::{BlockingCache, Cache};
+use crate::cache::{locks::InodeLocks, Cache, CacheStats, CachedWhenFSObject};
 use crate::object::{DirectoryEntry, DirectoryObject, FileObject, FileSystemObject};
 use crate::store::RecoveryDetails;
 
 use std::collections::HashSet;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::future::Future;
+use std::io;
 use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyDirectory, Request, FUSE_ROOT_ID};
+use futures::future::try_join_all;
 use thiserror::Error;
 use tracing::{debug, error, info, warn};
 
+mod snapshots;
+
 #[derive(Debug, Error)]
-pub enum WhenFSError<TCache: BlockingCache> {
+pub enum WhenFSError<TCache: Cache> {
     #[error("Cache error: {0}")]
     Cache(<TCache as Cache>::Error),
 }
 
-pub struct WhenFS<TCache: BlockingCache> {
-    cache: TCache,
+/// How long the kernel may cache what the filesystem tells it.
+///
+/// The kernel's caches are never invalidated explicitly, as fuser 0.13 has no way to send
+/// invalidation notices. Changes made through the mount keep them up to date anyway, but
+/// snapshots another process created only show up in `.snapshots` once these TTLs run out.
+#[derive(Clone, Copy, Debug)]
+pub struct FsConfig {
+    /// How long file attributes are cached
+    pub attr_ttl: Duration,
+    /// How long names found by `lookup` are cached
+    pub entry_ttl: Duration,
+    /// How long names `lookup` didn't find are cached, zero turns negative caching off
+    pub negative_ttl: Duration,
+    /// Refuse every change with `EROFS`, as when a snapshot is mounted or with `-o ro`.
+    /// Nothing is written to the store then, not even a root for an empty filesystem.
+    pub read_only: bool,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            negative_ttl: Duration::from_secs(1),
+            read_only: false,
+        }
+    }
+}
+
+/// FUSE frontend. Requests are handed to the tokio runtime and replied to from there, so
+/// the FUSE thread never waits on the calendar.
+pub struct WhenFS<TCache: Cache> {
+    inner: Arc<Inner<TCache>>,
     rt: tokio::runtime::Handle,
+}
+
+/// Block size reported for every object
+pub const BLOCK_SIZE: u32 = 512;
+
+/// The recovery file changes whenever anything is written, without the kernel knowing
+/// about it, so neither its attributes nor its contents may be cached
+pub const RECOVERY_FILE: u64 = FUSE_ROOT_ID + 1;
+
+/// Error of an operation that failed with `errno`
+fn errno(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+/// Filesystem state shared by the tasks serving requests
+struct Inner<TCache: Cache> {
+    cache: TCache,
+    /// Requests that change an object hold its inode's lock from reading it until the new
+    /// version is in the cache, so concurrent changes can't undo each other
+    locks: InodeLocks,
     file_handle_count: AtomicU64,
+    config: FsConfig,
 }
 
-impl<TCache: BlockingCache> WhenFS<TCache> {
-    const BLOCK_SIZE: u32 = 512;
-    const MAX_NAME_LENGTH: usize = 255;
-    // const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
-    const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
-    const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
+impl<TCache> WhenFS<TCache>
+where
+    TCache: Cache + Send + Sync + 'static,
+{
+    pub async fn new(
+        cache: TCache,
+        rt: tokio::runtime::Handle,
+    ) -> Result<Self, WhenFSError<TCache>> {
+        Self::with_config(cache, rt, FsConfig::default()).await
+    }
+
+    pub async fn with_config(
+        cache: TCache,
+        rt: tokio::runtime::Handle,
+        config: FsConfig,
+    ) -> Result<Self, WhenFSError<TCache>> {
+        info!(?config, "Initializing filesystem");
+        if !config.read_only {
+            Self::create_root(&cache).await?;
+        }
 
-    pub fn new(mut cache: TCache, rt: tokio::runtime::Handle) -> Result<Self, WhenFSError<TCache>> {
-        info!("Initializing filesystem");
+        Ok(Self {
+            inner: Arc::new(Inner {
+                cache,
+                locks: InodeLocks::default(),
+                config,
+                file_handle_count: AtomicU64::new(0),
+            }),
+            rt,
+        })
+    }
+
+    /// Creates the root directory and the welcome file of a new filesystem, unless it
+    /// already has a root. Returns whether it created them.
+    pub async fn create_root(cache: &TCache) -> Result<bool, WhenFSError<TCache>> {
         if cache
-            .get_blocking(FUSE_ROOT_ID)
+            .get(FUSE_ROOT_ID)
+            .await
             .map_err(|e| WhenFSError::<TCache>::Cache(e))?
-            .is_none()
+            .is_some()
         {
-            info!("Could not find object for root inode");
-            const WELCOME: &str = "WelcomeToWhenFS";
-            let now = SystemTime::now();
-            let mut entries = HashSet::with_capacity(1);
-            entries.insert(DirectoryEntry {
+            return Ok(false);
+        }
+        info!("Could not find object for root inode");
+        const WELCOME: &str = "WelcomeToWhenFS";
+        let now = SystemTime::now();
+        let mut entries = HashSet::with_capacity(1);
+        entries.insert(DirectoryEntry {
+            ino: FUSE_ROOT_ID,
+            file_type: FileType::Directory,
+            name: String::from("."),
+        });
+        entries.insert(DirectoryEntry {
+            ino: FUSE_ROOT_ID + 1,
+            file_type: FileType::RegularFile,
+            name: WELCOME.to_string(),
+        });
+        let root_dir_obj = DirectoryObject {
+            attr: FileAttr {
                 ino: FUSE_ROOT_ID,
-                file_type: FileType::Directory,
-                name: String::from("."),
-            });
-            entries.insert(DirectoryEntry {
-                ino: FUSE_ROOT_ID + 1,
-                file_type: FileType::RegularFile,
-                name: WELCOME.to_string(),
-            });
-            let root_dir_obj = DirectoryObject {
-                attr: FileAttr {
-                    ino: FUSE_ROOT_ID,
-                    size: 0,
-                    blocks: 0,
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    crtime: now,
-                    kind: FileType::Directory,
-                    perm: 0o777,
-                    nlink: 2, // Parent directory + self (".")
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                    blksize: Self::BLOCK_SIZE,
-                    flags: 0,
-                },
-                entries,
-                name: String::from("root event"),
-            };
-            let ino = cache
-                .insert_blocking(FUSE_ROOT_ID, FileSystemObject::Dir(root_dir_obj))
-                .map_err(|e| WhenFSError::<TCache>::Cache(e))?;
-            assert_eq!(ino, FUSE_ROOT_ID);
-            let next_ino = cache.new_inode();
-            let recovery_file = FileObject {
-                attr: FileAttr {
-                    ino: next_ino,
-                    size: 1024,
-                    blocks: 1,
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    crtime: now,
-                    kind: FileType::RegularFile,
-                    perm: 0o444,
-                    nlink: 1,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                    blksize: Self::BLOCK_SIZE,
-                    flags: 0,
-                },
-                name: String::from(WELCOME),
-                data: Vec::new(),
-            };
-            let ino = cache
-                .insert_blocking(next_ino, FileSystemObject::File(recovery_file))
-                .map_err(|e| WhenFSError::<TCache>::Cache(e))?;
-            assert_eq!(next_ino, ino);
-            assert_eq!(ino, FUSE_ROOT_ID + 1);
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::Directory,
+                perm: 0o777,
+                nlink: 2, // Parent directory + self (".")
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: Inner::<TCache>::BLOCK_SIZE,
+                flags: 0,
+            },
+            entries,
+            name: String::from("root event"),
+        };
+        let ino = cache
+            .insert(FUSE_ROOT_ID, FileSystemObject::Dir(root_dir_obj))
+            .await
+            .map_err(|e| WhenFSError::<TCache>::Cache(e))?;
+        assert_eq!(ino, FUSE_ROOT_ID);
+        let next_ino = cache.new_inode();
+        let recovery_file = FileObject {
+            attr: FileAttr {
+                ino: next_ino,
+                size: 1024,
+                blocks: 1,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::RegularFile,
+                perm: 0o444,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: Inner::<TCache>::BLOCK_SIZE,
+                flags: 0,
+            },
+            name: String::from(WELCOME),
+            data: Vec::new(),
+        };
+        let ino = cache
+            .insert(next_ino, FileSystemObject::File(recovery_file))
+            .await
+            .map_err(|e| WhenFSError::<TCache>::Cache(e))?;
+        assert_eq!(next_ino, ino);
+        assert_eq!(ino, FUSE_ROOT_ID + 1);
+        Ok(true)
+    }
+
+    /// Inode `path` names, starting at the root, for use without the kernel. These methods
+    /// check permissions for `uid` and `gid` the way the kernel would on a mount.
+    pub async fn resolve(&self, uid: u32, gid: u32, path: &Path) -> io::Result<u64> {
+        self.inner.resolve(uid, gid, path).await.map_err(errno)
+    }
+
+    pub async fn stat(&self, ino: u64) -> io::Result<FileAttr> {
+        self.inner.attr(ino).await.map_err(errno)
+    }
+
+    /// Names and attributes of the entries of a directory, without `.` and `..`
+    pub async fn list(&self, uid: u32, gid: u32, ino: u64) -> io::Result<Vec<(String, FileAttr)>> {
+        let inner = &self.inner;
+        inner
+            .permit(uid, gid, ino, libc::R_OK)
+            .await
+            .map_err(errno)?;
+        let entries = inner.entries(ino).await.map_err(errno)?;
+        let children = entries
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| async move { Ok((entry.name, inner.attr(entry.ino).await?)) });
+        try_join_all(children).await.map_err(errno)
+    }
+
+    /// Whole contents of a file
+    pub async fn read(&self, uid: u32, gid: u32, ino: u64) -> io::Result<Vec<u8>> {
+        let inner = &self.inner;
+        inner
+            .permit(uid, gid, ino, libc::R_OK)
+            .await
+            .map_err(errno)?;
+        inner.read_at(ino, 0, usize::MAX).await.map_err(errno)
+    }
+
+    /// Replaces the contents of the file at `path` with `data`, creating it with `mode` if
+    /// it doesn't exist
+    pub async fn write(
+        &self,
+        uid: u32,
+        gid: u32,
+        path: &Path,
+        data: &[u8],
+        mode: u32,
+    ) -> io::Result<FileAttr> {
+        let inner = &self.inner;
+        let (parent, name) = inner.resolve_parent(uid, gid, path).await.map_err(errno)?;
+        let ino = match inner.find(parent, name).await.map_err(errno)? {
+            Some(ino) => {
+                let attr = inner
+                    .permit(uid, gid, ino, libc::W_OK)
+                    .await
+                    .map_err(errno)?;
+                if attr.kind == FileType::Directory {
+                    return Err(errno(libc::EISDIR));
+                }
+                ino
+            }
+            None => {
+                inner
+                    .create_child(uid, gid, parent, name, libc::S_IFREG | mode)
+                    .await
+                    .map_err(errno)?
+                    .ino
+            }
+        };
+        inner.write_at(ino, 0, data).await.map_err(errno)?;
+        if inner.attr(ino).await.map_err(errno)?.size > data.len() as u64 {
+            inner
+                .truncate(ino, data.len() as u64)
+                .await
+                .map_err(errno)?;
         }
+        inner.attr(ino).await.map_err(errno)
+    }
 
-        Ok(Self {
-            cache,
-            rt,
-            file_handle_count: AtomicU64::new(0),
+    /// Unlinks the file at `path`
+    pub async fn remove(&self, uid: u32, gid: u32, path: &Path) -> io::Result<()> {
+        let inner = &self.inner;
+        let (parent, name) = inner.resolve_parent(uid, gid, path).await.map_err(errno)?;
+        inner
+            .unlink_child(uid, gid, parent, name)
+            .await
+            .map_err(errno)
+    }
+
+    /// Serves a request on the runtime, leaving the FUSE thread free to read the next one
+    fn spawn<Fut>(&self, serve: impl FnOnce(Arc<Inner<TCache>>) -> Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.rt.spawn(serve(Arc::clone(&self.inner)));
+    }
+}
+
+impl<TCache: Cache> Inner<TCache> {
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
+    const MAX_NAME_LENGTH: usize = 255;
+    // const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+    const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
+    const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
+
+    const RECOVERY_FILE: u64 = RECOVERY_FILE;
+
+    fn attr_ttl(&self, ino: u64) -> Duration {
+        if ino == Self::RECOVERY_FILE {
+            Duration::ZERO
+        } else {
+            self.config.attr_ttl
+        }
+    }
+
+    fn entry_ttl(&self, ino: u64) -> Duration {
+        if ino == Self::RECOVERY_FILE {
+            Duration::ZERO
+        } else {
+            self.config.entry_ttl
+        }
+    }
+
+    /// Whether an inode may not be changed, either because the whole mount is read-only or
+    /// because it belongs to `.snapshots`
+    fn is_read_only(&self, ino: u64) -> bool {
+        self.config.read_only || ino == snapshots::DIR || snapshots::decode(ino).is_some()
+    }
+
+    /// Inode the kernel sees for the entry `child` of the directory `parent`. Directories of
+    /// a snapshot list inodes within that snapshot.
+    fn child_ino(parent: u64, child: u64) -> u64 {
+        match snapshots::decode(parent) {
+            Some((snapshot, _)) => snapshots::encode(snapshot, child).unwrap_or(child),
+            None => child,
+        }
+    }
+
+    /// Attributes the kernel sees for an object stored under `ino`. Objects of a snapshot
+    /// carry their inode number within it, and can't be written.
+    fn exposed_attr(ino: u64, mut attr: FileAttr) -> FileAttr {
+        if snapshots::decode(ino).is_some() {
+            attr.ino = ino;
+            attr.perm &= !0o222;
+        }
+        attr
+    }
+
+    /// Entry of `.snapshots` in the root directory, which only exists once there are
+    /// snapshots
+    fn snapshots_entry(cache: &TCache, parent: u64) -> Option<DirectoryEntry> {
+        (parent == FUSE_ROOT_ID && !cache.snapshots().is_empty()).then(|| DirectoryEntry {
+            ino: snapshots::DIR,
+            file_type: FileType::Directory,
+            name: String::from(snapshots::DIR_NAME),
         })
     }
 
-    fn get_recovery_file_contents(&self) -> String {
-        let RecoveryDetails { cal_id, root_id } = self.cache.get_recovery_id();
+    /// `.snapshots`, listing the root of every snapshot
+    fn snapshots_dir(cache: &TCache) -> DirectoryObject {
+        let list = cache.snapshots();
+        let mut entries: HashSet<DirectoryEntry> = list
+            .iter()
+            .enumerate()
+            .filter_map(|(position, snapshot)| {
+                Some(DirectoryEntry {
+                    ino: snapshots::encode(position, FUSE_ROOT_ID)?,
+                    file_type: FileType::Directory,
+                    name: snapshots::entry_name(snapshot),
+                })
+            })
+            .collect();
+        entries.insert(DirectoryEntry {
+            ino: snapshots::DIR,
+            file_type: FileType::Directory,
+            name: String::from("."),
+        });
+        entries.insert(DirectoryEntry {
+            ino: FUSE_ROOT_ID,
+            file_type: FileType::Directory,
+            name: String::from(".."),
+        });
+        let changed = list
+            .last()
+            .map_or(SystemTime::UNIX_EPOCH, |snapshot| snapshot.created.into());
+        DirectoryObject {
+            attr: FileAttr {
+                ino: snapshots::DIR,
+                size: 0,
+                blocks: 0,
+                atime: changed,
+                mtime: changed,
+                ctime: changed,
+                crtime: changed,
+                kind: FileType::Directory,
+                perm: 0o555,
+                nlink: 2 + list.len() as u32,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: Self::BLOCK_SIZE,
+                flags: 0,
+            },
+            entries,
+            name: String::from(snapshots::DIR_NAME),
+        }
+    }
+
+    fn open_flags(ino: u64) -> u32 {
+        if ino == Self::RECOVERY_FILE {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    /// Entry for a name that doesn't exist. The kernel caches an entry without an inode
+    /// as a negative entry.
+    fn negative_entry() -> FileAttr {
+        FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: Self::BLOCK_SIZE,
+            flags: 0,
+        }
+    }
 
+    fn get_recovery_file_contents(cache: &TCache) -> String {
+        let RecoveryDetails { cal_id, root_id } = cache.get_recovery_id();
         format!(
             r#"Welcome to WhenFS!
 If you're reading this, then you've successfully turned your Google calendar into a FUSE filesystem.
-To recover this filesystem, run WhenFS with the following arguments.
-The --root-event ID in this file changes after write operations, so don't copy these arguments too early or some of your data may become inaccessible.
+To recover this filesystem, mount it with the following arguments.
+
+mount --calendar {cal_id}
+
+WhenFS finds the filesystem in the calendar on its own. If the calendar ever holds more than one, pick this one by adding:
 
---calendar {cal_id}
 --root-event {root_id}
 
 If you poke around enough, you'll likely run into bugs, edge cases, and completely unimplemented features.
@@ -134,9 +453,20 @@ contributions be accompanied by a lighthearted meme that makes the author chuckl
         )
     }
 
-    fn get_filesystem_object_by_ino(&self, ino: u64) -> Result<Arc<RwLock<FileSystemObject>>, i32> {
-        self.cache
-            .get_blocking(ino)
+    async fn get_filesystem_object_by_ino(
+        cache: &TCache,
+        ino: u64,
+    ) -> Result<CachedWhenFSObject, i32> {
+        if ino == snapshots::DIR {
+            return Ok(Arc::new(std::sync::RwLock::new(FileSystemObject::Dir(
+                Self::snapshots_dir(cache),
+            ))));
+        }
+        let found = match snapshots::decode(ino) {
+            Some((snapshot, ino)) => cache.get_snapshot(snapshot, ino).await,
+            None => cache.get(ino).await,
+        };
+        found
             .map_err(|error| {
                 error!(%error);
                 libc::EIO
@@ -172,8 +502,7 @@ contributions be accompanied by a lighthearted meme that makes the author chuckl
         if uid == 0 {
             // root is allowed to read or write anything
             // root is only allowed to exec if one of the exec bits is set
-            // todo: remove  experiment
-            return (access_mask & libc::X_OK == 0) != (file_mode & 0o111 != 0);
+            return access_mask & libc::X_OK == 0 || file_mode & 0o111 != 0;
         }
 
         // this is the same though
@@ -211,23 +540,25 @@ contributions be accompanied by a lighthearted meme that makes the author chuckl
         (file_handle & Self::FILE_HANDLE_WRITE_BIT) != 0
     }
 
-    fn write_inode(&mut self, ino: u64, attr: FileAttr) -> Result<(), i32> {
-        let obj = match self.get_filesystem_object_by_ino(ino) {
+    async fn write_inode(cache: &TCache, ino: u64, attr: FileAttr) -> Result<(), i32> {
+        let obj = match Self::get_filesystem_object_by_ino(cache, ino).await {
             Ok(obj) => obj,
             Err(errno) => {
                 return Err(errno);
             }
         };
-        let mut handle = match obj.write() {
-            Ok(obj) => obj,
-            Err(error) => {
-                error!(%error);
-                return Err(libc::EIO);
-            }
+        let new = {
+            let mut handle = match obj.write() {
+                Ok(obj) => obj,
+                Err(error) => {
+                    error!(%error);
+                    return Err(libc::EIO);
+                }
+            };
+            *handle.mut_attr() = attr;
+            handle.clone()
         };
-        *handle.mut_attr() = attr;
-        let new = handle.clone();
-        match self.cache.insert_blocking(ino, new) {
+        match cache.insert(ino, new).await {
             Ok(_ino) => (),
             Err(error) => {
                 error!(%error);
@@ -235,229 +566,178 @@ contributions be accompanied by a lighthearted meme that makes the author chuckl
         }
         Ok(())
     }
-}
 
-impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
-    fn init(
-        &mut self,
-        _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        Ok(())
+    /// Reads an object, failing with `EIO` if a panic left its lock poisoned
+    fn read_object<T>(
+        obj: &CachedWhenFSObject,
+        read: impl FnOnce(&FileSystemObject) -> Result<T, i32>,
+    ) -> Result<T, i32> {
+        let obj = obj.read().map_err(|error| {
+            error!(%error);
+            libc::EIO
+        })?;
+        read(&obj)
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let obj = match self.get_filesystem_object_by_ino(ino) {
-            Ok(obj) => obj,
-            Err(errno) => {
-                reply.error(errno);
-                return;
-            }
-        };
-
-        let obj = match obj.read() {
-            Ok(obj) => obj,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        reply.attr(&Duration::new(0, 0), &obj.get_attr())
+    fn cache_error(error: impl std::fmt::Display) -> i32 {
+        error!(%error);
+        libc::EIO
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        debug!(%ino, "readdir() called");
-        assert!(offset >= 0);
-        let obj = match self.get_filesystem_object_by_ino(ino) {
-            Ok(obj) => obj,
-            Err(errno) => {
-                reply.error(errno);
-                return;
-            }
-        };
-
-        let obj = match obj.read() {
-            Ok(obj) => obj,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        match &*obj {
-            FileSystemObject::Dir(dir) => {
-                for (i, entry) in dir.entries.iter().skip(offset as usize).enumerate() {
-                    let reply_buffer_full = reply.add(
-                        entry.ino,
-                        offset + i as i64 + 1,
-                        entry.file_type,
-                        OsStr::from_bytes(entry.name.as_bytes()),
-                    );
-
-                    if reply_buffer_full {
-                        break;
-                    }
-                }
-            }
-            _ => {
-                reply.error(libc::ENOTDIR);
-                return;
-            }
-        };
+    /// Attributes of an inode as the kernel sees them
+    async fn attr(&self, ino: u64) -> Result<FileAttr, i32> {
+        let obj = Self::get_filesystem_object_by_ino(&self.cache, ino).await?;
+        Self::read_object(&obj, |obj| Ok(Self::exposed_attr(ino, obj.get_attr())))
+    }
 
-        reply.ok()
+    /// Entries of a directory with the inodes the kernel sees, including `.snapshots`
+    async fn entries(&self, ino: u64) -> Result<Vec<DirectoryEntry>, i32> {
+        let obj = Self::get_filesystem_object_by_ino(&self.cache, ino).await?;
+        let snapshots_entry = Self::snapshots_entry(&self.cache, ino);
+        Self::read_object(&obj, |obj| match obj {
+            FileSystemObject::Dir(dir) => Ok(dir
+                .entries
+                .iter()
+                // `.snapshots` hides anything stored under the same name
+                .filter(|entry| {
+                    snapshots_entry
+                        .as_ref()
+                        .is_none_or(|snapshots| entry.name != snapshots.name)
+                })
+                .map(|entry| DirectoryEntry {
+                    ino: Self::child_ino(ino, entry.ino),
+                    ..entry.clone()
+                })
+                .chain(snapshots_entry.clone())
+                .collect()),
+            FileSystemObject::File(_) => Err(libc::ENOTDIR),
+        })
     }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
-        if name.len() > Self::MAX_NAME_LENGTH {
-            reply.error(libc::ENAMETOOLONG);
-            return;
+    /// Inode of the entry `name` of the directory `parent`, or `None` if there is none
+    async fn find(&self, parent: u64, name: &OsStr) -> Result<Option<u64>, i32> {
+        if let Some(entry) =
+            Self::snapshots_entry(&self.cache, parent).filter(|entry| *entry.name == *name)
+        {
+            return Ok(Some(entry.ino));
         }
+        let obj = Self::get_filesystem_object_by_ino(&self.cache, parent).await?;
+        Self::read_object(&obj, |obj| match obj {
+            FileSystemObject::Dir(dir) => Ok(dir
+                .get_entry_by_name(name)
+                .map(|entry| Self::child_ino(parent, entry.ino))),
+            FileSystemObject::File(_) => Err(libc::ENOTDIR),
+        })
+    }
 
-        let parent_obj = match self.get_filesystem_object_by_ino(parent) {
-            Ok(obj) => obj,
-            Err(errno) => {
-                reply.error(errno);
-                return;
-            }
-        };
-
-        let parent_obj = match parent_obj.read() {
-            Ok(obj) => obj,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        let parent_dir = match &*parent_obj {
-            FileSystemObject::Dir(dir) => dir,
-            _not_directory => {
-                reply.error(libc::ENOTDIR);
-                return;
-            }
-        };
-
-        let found = match parent_dir.get_entry_by_name(name) {
-            Some(found) => found,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
+    /// Checks that `uid` and `gid` may access an inode as `mask` asks, returning its
+    /// attributes. Writing to a read-only inode fails with `EROFS` whatever its mode.
+    async fn permit(&self, uid: u32, gid: u32, ino: u64, mask: i32) -> Result<FileAttr, i32> {
+        if mask & libc::W_OK != 0 && self.is_read_only(ino) {
+            return Err(libc::EROFS);
+        }
+        let attr = self.attr(ino).await?;
+        if Self::check_access(attr.uid, attr.gid, attr.perm, uid, gid, mask) {
+            Ok(attr)
+        } else {
+            Err(libc::EACCES)
+        }
+    }
 
-        let maybe_found_handle = match self.cache.get_blocking(found.ino) {
-            Ok(maybe_handle) => maybe_handle,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
+    /// Inode `path` names, starting at the root. Every directory on the way has to be
+    /// searchable.
+    async fn resolve(&self, uid: u32, gid: u32, path: &Path) -> Result<u64, i32> {
+        let mut ino = FUSE_ROOT_ID;
+        for component in path.components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::ParentDir => OsStr::new(".."),
+                Component::Normal(name) => name,
+                Component::Prefix(_) => return Err(libc::EINVAL),
+            };
+            self.permit(uid, gid, ino, libc::X_OK).await?;
+            ino = match self.find(ino, name).await? {
+                Some(found) => found,
+                // The root is its own parent, and snapshots are found in `.snapshots`
+                None if component == Component::ParentDir && ino == FUSE_ROOT_ID => ino,
+                None if component == Component::ParentDir
+                    && snapshots::decode(ino).is_some_and(|(_, ino)| ino == FUSE_ROOT_ID) =>
+                {
+                    snapshots::DIR
+                }
+                None => return Err(libc::ENOENT),
+            };
+        }
+        Ok(ino)
+    }
 
-        let found_handle = match maybe_found_handle {
-            Some(obj) => obj,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
+    /// Directory `path` is in and its last component
+    async fn resolve_parent<'a>(
+        &self,
+        uid: u32,
+        gid: u32,
+        path: &'a Path,
+    ) -> Result<(u64, &'a OsStr), i32> {
+        let name = path.file_name().ok_or(libc::EINVAL)?;
+        let parent = self
+            .resolve(uid, gid, path.parent().unwrap_or(Path::new("")))
+            .await?;
+        Ok((parent, name))
+    }
 
-        let found_obj = match found_handle.read() {
-            Ok(obj) => obj,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
+    /// Up to `size` bytes of a file, starting at `offset`
+    async fn read_at(&self, ino: u64, offset: u64, size: usize) -> Result<Vec<u8>, i32> {
+        let range = |len: usize| {
+            let lower_bound = (offset as usize).min(len);
+            lower_bound..lower_bound.saturating_add(size).min(len)
         };
-
-        reply.entry(&Duration::new(0, 0), &found_obj.get_attr(), 0);
+        if ino == Self::RECOVERY_FILE {
+            let data = Self::get_recovery_file_contents(&self.cache);
+            return Ok(data.as_bytes()[range(data.len())].to_vec());
+        }
+        let obj = Self::get_filesystem_object_by_ino(&self.cache, ino).await?;
+        Self::read_object(&obj, |obj| match obj {
+            FileSystemObject::Dir(_) => Err(libc::EISDIR),
+            FileSystemObject::File(file) => Ok(file.data[range(file.data.len())].to_vec()),
+        })
     }
 
-    fn create(
-        &mut self,
-        req: &Request<'_>,
+    /// Creates a file or directory named `name` in `parent`, owned by `uid` and `gid`
+    async fn create_child(
+        &self,
+        uid: u32,
+        gid: u32,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
-        flags: i32,
-        reply: fuser::ReplyCreate,
-    ) {
-        debug!("create() called with {:?} {:?}", parent, name);
-        let (read, write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => (true, false),
-            libc::O_WRONLY => (false, true),
-            libc::O_RDWR => (true, true),
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
-            }
-        };
-
-        let maybe_parent_handle = match self.cache.get_blocking(parent) {
-            Ok(maybe_handle) => maybe_handle,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        let parent_handle = match maybe_parent_handle {
-            Some(obj) => obj,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
-
-        let parent_obj = match parent_handle.read() {
-            Ok(obj) => obj,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        let parent_dir = match &*parent_obj {
-            FileSystemObject::Dir(dir) => dir,
-            _not_directory => {
-                reply.error(libc::ENOTDIR);
-                return;
-            }
-        };
-        let mut new_parent_dir = parent_dir.clone();
-
-        if parent_dir.get_entry_by_name(name).is_some() {
-            reply.error(libc::EEXIST);
-            return;
-        };
-
-        let kind = match Self::as_file_type(mode) {
-            Ok(kind) => kind,
-            Err(error) => {
-                reply.error(error);
-                return;
+    ) -> Result<FileAttr, i32> {
+        if name.len() > Self::MAX_NAME_LENGTH {
+            return Err(libc::ENAMETOOLONG);
+        }
+        self.permit(uid, gid, parent, libc::W_OK | libc::X_OK)
+            .await?;
+        if Self::snapshots_entry(&self.cache, parent).is_some_and(|entry| *entry.name == *name) {
+            return Err(libc::EEXIST);
+        }
+        let kind = Self::as_file_type(mode)?;
+        let _parent = self.locks.lock(parent).await;
+        let cache = &self.cache;
+        let parent_handle = cache
+            .get(parent)
+            .await
+            .map_err(Self::cache_error)?
+            .ok_or(libc::ENOENT)?;
+        let mut new_parent_dir = Self::read_object(&parent_handle, |obj| match obj {
+            FileSystemObject::Dir(dir) if dir.get_entry_by_name(name).is_some() => {
+                Err(libc::EEXIST)
             }
-        };
+            FileSystemObject::Dir(dir) => Ok(dir.clone()),
+            FileSystemObject::File(_) => Err(libc::ENOTDIR),
+        })?;
 
         let name = name.to_string_lossy().to_string();
         let now = SystemTime::now();
-        let ino = self.cache.new_inode();
+        let ino = cache.new_inode();
         let attr = FileAttr {
             ino,
             size: 0,
@@ -468,14 +748,18 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
             crtime: now,
             kind,
             perm: mode as u16,
-            nlink: 1,
-            uid: req.uid(),
-            gid: req.gid(),
+            // A directory is also linked from its own `.`
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid,
+            gid,
             rdev: 0,
             blksize: Self::BLOCK_SIZE,
             flags: 0,
         };
-        let attr_copy = attr;
+        if kind == FileType::Directory {
+            // The new directory's `..`
+            new_parent_dir.attr.nlink += 1;
+        }
         new_parent_dir.entries.insert(DirectoryEntry {
             ino,
             file_type: kind,
@@ -507,64 +791,226 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
             }),
             kind => {
                 warn!(?kind, "Unimplemented file kind");
-                reply.error(libc::ENOSYS);
-                return;
+                return Err(libc::ENOSYS);
             }
         };
 
-        match self.cache.insert_blocking(ino, obj) {
-            Ok(ino) => ino,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
+        // The child and its entry in the parent become visible together or not at all
+        let new_parent = FileSystemObject::Dir(new_parent_dir);
+        cache
+            .insert_all(vec![(ino, obj), (parent, new_parent)])
+            .await
+            .map_err(Self::cache_error)?;
+        Ok(attr)
+    }
+
+    /// Writes `data` into a file at `offset`, growing the file if it ends past it
+    async fn write_at(&self, ino: u64, offset: u64, data: &[u8]) -> Result<(), i32> {
+        if self.is_read_only(ino) {
+            return Err(libc::EROFS);
+        }
+        let _locked = self.locks.lock(ino).await;
+        let obj = Self::get_filesystem_object_by_ino(&self.cache, ino).await?;
+        let mut new_obj = Self::read_object(&obj, |obj| match obj {
+            FileSystemObject::Dir(_) => Err(libc::EISDIR),
+            FileSystemObject::File(file) => Ok(file.clone()),
+        })?;
+
+        let now = SystemTime::now();
+        new_obj.attr.ctime = now;
+        new_obj.attr.atime = now;
+        new_obj.attr.mtime = now;
+        let old_len = new_obj.data.len();
+        let end = offset as usize + data.len();
+        if end > old_len {
+            debug!(%old_len, new_len = %end, name = %new_obj.name, "write: resizing file buffer");
+            new_obj.data.resize(end, 0);
+            new_obj.attr.size = end as u64;
+        } else {
+            debug!(%old_len, name = %new_obj.name, "write: no need to resize file buffer");
+        }
+        new_obj.data[offset as usize..end].copy_from_slice(data);
+        self.cache
+            .insert(ino, FileSystemObject::File(new_obj))
+            .await
+            .map_err(Self::cache_error)?;
+        Ok(())
+    }
+
+    /// Cuts a file off after `size` bytes, or pads it with zeros up to them
+    async fn truncate(&self, ino: u64, size: u64) -> Result<FileAttr, i32> {
+        if self.is_read_only(ino) {
+            return Err(libc::EROFS);
+        }
+        let _locked = self.locks.lock(ino).await;
+        let obj = Self::get_filesystem_object_by_ino(&self.cache, ino).await?;
+        let mut new_obj = Self::read_object(&obj, |obj| match obj {
+            FileSystemObject::Dir(_) => Err(libc::EISDIR),
+            FileSystemObject::File(file) => Ok(file.clone()),
+        })?;
+        let now = SystemTime::now();
+        new_obj.attr.ctime = now;
+        new_obj.attr.mtime = now;
+        new_obj.data.resize(size as usize, 0);
+        new_obj.attr.size = size;
+        let attr = new_obj.attr;
+        self.cache
+            .insert(ino, FileSystemObject::File(new_obj))
+            .await
+            .map_err(Self::cache_error)?;
+        Ok(attr)
+    }
+
+    /// Removes the entry `name` of `parent`, and the file it links to once that has no
+    /// links left. Both happen in one transaction.
+    async fn unlink_child(&self, uid: u32, gid: u32, parent: u64, name: &OsStr) -> Result<(), i32> {
+        self.permit(uid, gid, parent, libc::W_OK | libc::X_OK)
+            .await?;
+        if Self::snapshots_entry(&self.cache, parent).is_some_and(|entry| *entry.name == *name) {
+            return Err(libc::EISDIR);
+        }
+        let _parent = self.locks.lock(parent).await;
+        let cache = &self.cache;
+        let parent_handle = cache
+            .get(parent)
+            .await
+            .map_err(Self::cache_error)?
+            .ok_or(libc::ENOENT)?;
+        let (mut new_parent_dir, entry) = Self::read_object(&parent_handle, |obj| match obj {
+            FileSystemObject::Dir(dir) => match dir.get_entry_by_name(name) {
+                Some(entry) if entry.file_type == FileType::Directory => Err(libc::EISDIR),
+                Some(entry) => Ok((dir.clone(), entry.clone())),
+                None => Err(libc::ENOENT),
+            },
+            FileSystemObject::File(_) => Err(libc::ENOTDIR),
+        })?;
+
+        let _child = self.locks.lock(entry.ino).await;
+        let now = SystemTime::now();
+        new_parent_dir
+            .entries
+            .retain(|child| child.name != entry.name);
+        new_parent_dir.attr.mtime = now;
+        new_parent_dir.attr.ctime = now;
+        let mut changed = vec![(parent, FileSystemObject::Dir(new_parent_dir))];
+        let mut removed = Vec::new();
+        // An entry whose object is already gone only has to be dropped from the parent
+        if let Some(child) = cache.get(entry.ino).await.map_err(Self::cache_error)? {
+            let mut child = Self::read_object(&child, |obj| Ok(obj.clone()))?;
+            let attr = child.mut_attr();
+            attr.nlink = attr.nlink.saturating_sub(1);
+            attr.ctime = now;
+            if attr.nlink == 0 {
+                removed.push(entry.ino);
+            } else {
+                changed.push((entry.ino, child));
             }
-        };
+        }
+        debug!(%parent, ino = %entry.ino, name = %entry.name, "Unlinking file");
+        cache
+            .remove_all(changed, removed)
+            .await
+            .map_err(Self::cache_error)
+    }
 
-        let new_parent = FileSystemObject::Dir(new_parent_dir);
-        match self.cache.insert_blocking(parent, new_parent) {
-            Ok(ino) => ino,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
+    async fn getattr(&self, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino).await {
+            Ok(attr) => reply.attr(&self.attr_ttl(ino), &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    async fn readdir(&self, ino: u64, offset: i64, mut reply: ReplyDirectory) {
+        let entries = match self.entries(ino).await {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
         };
+        for (i, entry) in entries.iter().skip(offset as usize).enumerate() {
+            let reply_buffer_full = reply.add(
+                entry.ino,
+                offset + i as i64 + 1,
+                entry.file_type,
+                OsStr::from_bytes(entry.name.as_bytes()),
+            );
+
+            if reply_buffer_full {
+                break;
+            }
+        }
+        // Whatever lists a directory tends to look at its children next. Snapshots aren't
+        // fetched ahead of time.
+        if offset == 0 && ino != snapshots::DIR && snapshots::decode(ino).is_none() {
+            let children = entries
+                .iter()
+                .filter(|entry| entry.name != "." && entry.name != "..")
+                .filter(|entry| entry.ino != snapshots::DIR)
+                .map(|entry| entry.ino)
+                .collect();
+            self.cache.prefetch(children);
+        }
 
-        let fh = self.new_file_handle(read, write);
-
-        reply.created(&Duration::new(0, 0), &attr_copy, 0, fh, 0)
+        reply.ok()
     }
 
-    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
-        debug!("access() {ino:?} {mask:?}");
-        let obj = match self.get_filesystem_object_by_ino(ino) {
-            Ok(obj) => obj,
+    async fn lookup(&self, parent: u64, name: OsString, reply: fuser::ReplyEntry) {
+        let found = match self.find(parent, &name).await {
+            Ok(Some(found)) => found,
+            Ok(None) if self.config.negative_ttl.is_zero() => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+            Ok(None) => {
+                reply.entry(&self.config.negative_ttl, &Self::negative_entry(), 0);
+                return;
+            }
             Err(errno) => {
                 reply.error(errno);
                 return;
             }
         };
+        match self.attr(found).await {
+            Ok(attr) => reply.entry(&self.entry_ttl(found), &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
 
-        let attr = match obj.read() {
-            Ok(handle) => handle.get_attr(),
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
+    #[allow(clippy::too_many_arguments)]
+    async fn create(
+        &self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: OsString,
+        mode: u32,
+        read: bool,
+        write: bool,
+        reply: fuser::ReplyCreate,
+    ) {
+        match self.create_child(uid, gid, parent, &name, mode).await {
+            Ok(attr) => {
+                let fh = self.new_file_handle(read, write);
+                self.cache.open(attr.ino);
+                reply.created(&self.config.entry_ttl, &attr, 0, fh, 0)
             }
-        };
+            Err(errno) => reply.error(errno),
+        }
+    }
 
-        if Self::check_access(attr.uid, attr.gid, attr.perm, req.uid(), req.gid(), mask) {
-            reply.ok();
-        } else {
-            reply.error(libc::EACCES);
+    async fn access(&self, uid: u32, gid: u32, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        match self.permit(uid, gid, ino, mask).await {
+            Ok(_attr) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn setattr(
-        &mut self,
-        req: &Request<'_>,
+    #[allow(clippy::too_many_arguments)]
+    async fn setattr(
+        &self,
+        req_uid: u32,
+        req_gid: u32,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -572,21 +1018,15 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         size: Option<u64>,
         atime: Option<fuser::TimeOrNow>,
         mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        debug!(
-            "setattr called with (ino: {:#x?}, mode: {:?}, uid: {:?}, \\
-            gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
-            ino, mode, uid, gid, size, fh, flags
-        );
-
-        let obj = match self.get_filesystem_object_by_ino(ino) {
+        if self.is_read_only(ino) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let locked = self.locks.lock(ino).await;
+        let cache = &self.cache;
+        let obj = match Self::get_filesystem_object_by_ino(cache, ino).await {
             Ok(obj) => obj,
             Err(errno) => {
                 reply.error(errno);
@@ -594,8 +1034,8 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
             }
         };
 
-        let obj = match obj.read() {
-            Ok(handle) => handle,
+        let mut attrs = match obj.read() {
+            Ok(handle) => handle.get_attr(),
             Err(error) => {
                 error!(%error);
                 reply.error(libc::EIO);
@@ -603,14 +1043,13 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
             }
         };
 
-        let mut attrs = obj.get_attr();
         if let Some(mode) = mode {
             debug!("chmod() called with {:?}, {:o}", ino, mode);
-            if req.uid() != 0 && req.uid() != attrs.uid {
+            if req_uid != 0 && req_uid != attrs.uid {
                 reply.error(libc::EPERM);
                 return;
             }
-            if req.uid() != 0 && req.gid() != attrs.gid {
+            if req_uid != 0 && req_gid != attrs.gid {
                 // If SGID is set and the file belongs to a group that the caller is not part of
                 // then the SGID bit is suppose to be cleared during chmod
                 attrs.perm = (mode & !libc::S_ISGID) as u16;
@@ -618,14 +1057,14 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
                 attrs.perm = mode as u16;
             }
             attrs.ctime = SystemTime::now();
-            match self.write_inode(attrs.ino, attrs) {
+            match Self::write_inode(cache, attrs.ino, attrs).await {
                 Ok(()) => (),
                 Err(e) => {
                     reply.error(e);
                     return;
                 }
             }
-            reply.attr(&Duration::new(0, 0), &attrs);
+            reply.attr(&self.attr_ttl(ino), &attrs);
             return;
         }
 
@@ -633,22 +1072,22 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
             debug!("chown() called with {:?} {:?} {:?}", ino, uid, gid);
             if let Some(_gid) = gid {
                 // Non-root users can only change gid to a group they're in
-                if req.uid() != 0 {
+                if req_uid != 0 {
                     reply.error(libc::EPERM);
                     return;
                 }
             }
             if let Some(uid) = uid {
-                if req.uid() != 0
+                if req_uid != 0
                     // but no-op changes by the owner are not an error
-                    && !(uid == attrs.uid && req.uid() == attrs.uid)
+                    && !(uid == attrs.uid && req_uid == attrs.uid)
                 {
                     reply.error(libc::EPERM);
                     return;
                 }
             }
             // Only owner may change the group
-            if gid.is_some() && req.uid() != 0 && req.uid() != attrs.uid {
+            if gid.is_some() && req_uid != 0 && req_uid != attrs.uid {
                 reply.error(libc::EPERM);
                 return;
             }
@@ -666,37 +1105,185 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
             if let Some(gid) = gid {
                 attrs.gid = gid;
                 // Clear SETGID unless user is root
-                if req.uid() != 0 {
+                if req_uid != 0 {
                     attrs.perm &= !libc::S_ISGID as u16;
                 }
             }
             attrs.ctime = SystemTime::now();
-            match self.write_inode(attrs.ino, attrs) {
+            match Self::write_inode(cache, attrs.ino, attrs).await {
                 Ok(()) => (),
                 Err(e) => {
                     reply.error(e);
                     return;
                 }
             }
-            reply.attr(&Duration::new(0, 0), &attrs);
+            reply.attr(&self.attr_ttl(ino), &attrs);
             return;
         }
 
         if let Some(size) = size {
             debug!("truncate() called with {ino:?} {size:?}");
-            reply.error(libc::ENOSYS);
+            drop(locked);
+            match self.truncate(ino, size).await {
+                Ok(attr) => reply.attr(&self.attr_ttl(ino), &attr),
+                Err(errno) => reply.error(errno),
+            }
             return;
         }
 
-        let now = SystemTime::now();
         if let Some(atime) = atime {
             debug!("utimens() called with {ino:?}, atime={atime:?}");
         }
         if let Some(mtime) = mtime {
             debug!("utimens() called with {ino:?}, mtime={mtime:?}");
         }
-        let attrs = obj.get_attr();
-        reply.attr(&Duration::new(0, 0), &attrs);
+        reply.attr(&self.attr_ttl(ino), &attrs);
+    }
+
+    async fn read(&self, ino: u64, offset: i64, size: u32, reply: fuser::ReplyData) {
+        match self.read_at(ino, offset as u64, size as usize).await {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    async fn write(&self, ino: u64, offset: u64, data: Vec<u8>, reply: fuser::ReplyWrite) {
+        match self.write_at(ino, offset, &data).await {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    async fn unlink(
+        &self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: OsString,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.unlink_child(uid, gid, parent, &name).await {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Flushes an inode's changes once a handle to it is closed or synced
+    async fn flush(&self, ino: u64, reply: fuser::ReplyEmpty) {
+        match self.cache.flush(Some(ino)).await {
+            Ok(()) => reply.ok(),
+            Err(error) => {
+                error!(%error);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+}
+
+impl<TCache> Filesystem for WhenFS<TCache>
+where
+    TCache: Cache + Send + Sync + 'static,
+{
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        _config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        Ok(())
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.spawn(move |fs| async move { fs.getattr(ino, reply).await });
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        debug!(%ino, "readdir() called");
+        assert!(offset >= 0);
+        self.spawn(move |fs| async move { fs.readdir(ino, offset, reply).await });
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+        if name.len() > Inner::<TCache>::MAX_NAME_LENGTH {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+
+        let name = name.to_owned();
+        self.spawn(move |fs| async move { fs.lookup(parent, name, reply).await });
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        debug!("create() called with {:?} {:?}", parent, name);
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let (uid, gid, name) = (req.uid(), req.gid(), name.to_owned());
+        self.spawn(move |fs| async move {
+            fs.create(uid, gid, parent, name, mode, read, write, reply)
+                .await
+        });
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        debug!("access() {ino:?} {mask:?}");
+        let (uid, gid) = (req.uid(), req.gid());
+        self.spawn(move |fs| async move { fs.access(uid, gid, ino, mask, reply).await });
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!(
+            "setattr called with (ino: {:#x?}, mode: {:?}, uid: {:?}, \\
+            gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
+            ino, mode, uid, gid, size, fh, flags
+        );
+
+        let (req_uid, req_gid) = (req.uid(), req.gid());
+        self.spawn(move |fs| async move {
+            fs.setattr(
+                req_uid, req_gid, ino, mode, uid, gid, size, atime, mtime, reply,
+            )
+            .await
+        });
     }
 
     fn read(
@@ -727,42 +1314,7 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         //     return;
         // }
 
-        if ino == FUSE_ROOT_ID + 1 {
-            let data = self.get_recovery_file_contents();
-            let data = data.as_bytes();
-            let lower_bound = offset as usize;
-            let upper_bound = (lower_bound + size as usize).min(data.len());
-            reply.data(&data[lower_bound..upper_bound]);
-            return;
-        }
-
-        let offset = offset as u64;
-        let obj = match self.get_filesystem_object_by_ino(ino) {
-            Ok(handle) => handle,
-            Err(errno) => {
-                reply.error(errno);
-                return;
-            }
-        };
-        let obj = match obj.read() {
-            Ok(handle) => handle,
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-        let obj = match &*obj {
-            FileSystemObject::Dir(_) => {
-                reply.error(libc::EISDIR);
-                return;
-            }
-            FileSystemObject::File(old_obj) => old_obj,
-        };
-
-        let lower_bound = offset as usize;
-        let upper_bound = (lower_bound + size as usize).min(obj.data.len());
-        reply.data(&obj.data[lower_bound..upper_bound]);
+        self.spawn(move |fs| async move { fs.read(ino, offset, size, reply).await });
     }
 
     fn write(
@@ -794,71 +1346,36 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         }
         let offset = offset as u64;
 
-        if !Self::check_file_handle_write(fh) {
+        if !Inner::<TCache>::check_file_handle_write(fh) {
             reply.error(libc::EACCES);
             return;
         }
 
-        let obj = match self.get_filesystem_object_by_ino(ino) {
-            Ok(handle) => handle,
-            Err(errno) => {
-                reply.error(errno);
-                return;
-            }
-        };
-
-        let (mut new_obj, old_attr) = {
-            let obj = match obj.write() {
-                Ok(handle) => handle,
-                Err(error) => {
-                    error!(%error);
-                    reply.error(libc::EIO);
-                    return;
-                }
-            };
-
-            let old_obj = match &*obj {
-                FileSystemObject::Dir(_) => {
-                    reply.error(libc::EISDIR);
-                    return;
-                }
-                FileSystemObject::File(old_obj) => old_obj,
-            };
-
-            (old_obj.clone(), old_obj.attr)
-        };
+        let data = data.to_vec();
+        self.spawn(move |fs| async move { fs.write(ino, offset, data, reply).await });
+    }
 
-        let now = SystemTime::now();
-        new_obj.attr.ctime = now;
-        new_obj.attr.atime = now;
-        new_obj.attr.mtime = now;
-        let old_len = old_attr.size as usize;
-        if data.len() + offset as usize > old_len {
-            let new_len = new_obj.attr.size as usize + data.len();
-            debug!(%old_len, %new_len, name = %new_obj.name, "read: resizing file buffer");
-            new_obj.data.resize(new_len, 0);
-            new_obj.attr.size = new_len as u64;
-        } else {
-            debug!(%old_len, name = %new_obj.name, "read: no need to resize file buffer");
+    fn destroy(&mut self) {
+        info!("Flushing changes before unmounting");
+        // Unmounting has to wait for the flush. This runs on the session's blocking thread,
+        // not on the runtime, so blocking on it is fine.
+        let cache = &self.inner.cache;
+        if let Err(error) = self.rt.block_on(cache.flush(None)) {
+            error!(%error, "Failed to flush changes, some writes were lost");
         }
-        new_obj.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
-        match self
-            .cache
-            .insert_blocking(new_obj.attr.ino, FileSystemObject::File(new_obj))
-        {
-            Ok(_ino) => (),
-            Err(error) => {
-                error!(%error);
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        reply.written(data.len() as u32);
+        let CacheStats {
+            hits,
+            misses,
+            evictions,
+            disk_hits,
+            prefetched,
+        } = cache.stats();
+        info!(
+            hits,
+            misses, evictions, disk_hits, prefetched, "Cache statistics"
+        );
     }
 
-    fn destroy(&mut self) {}
-
     fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyData) {
@@ -876,6 +1393,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         rdev: u32,
         reply: fuser::ReplyEntry,
     ) {
+        if self.inner.is_read_only(parent) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] mknod(parent: {:#x?}, name: {:?}, mode: {}, \\
             umask: {:#x?}, rdev: {})",
@@ -893,6 +1414,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         umask: u32,
         reply: fuser::ReplyEntry,
     ) {
+        if self.inner.is_read_only(parent) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] mkdir(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?})",
             parent, name, mode, umask
@@ -900,15 +1425,17 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         reply.error(libc::ENOSYS);
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        debug!(
-            "[Not Implemented] unlink(parent: {:#x?}, name: {:?})",
-            parent, name,
-        );
-        reply.error(libc::ENOSYS);
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        debug!("unlink() called with {:?} {:?}", parent, name);
+        let (uid, gid, name) = (req.uid(), req.gid(), name.to_owned());
+        self.spawn(move |fs| async move { fs.unlink(uid, gid, parent, name, reply).await });
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        if self.inner.is_read_only(parent) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] rmdir(parent: {:#x?}, name: {:?})",
             parent, name,
@@ -924,6 +1451,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         target: &std::path::Path,
         reply: fuser::ReplyEntry,
     ) {
+        if self.inner.is_read_only(parent) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] symlink(parent: {:#x?}, link_name: {:?}, target: {:?})",
             parent, link_name, target,
@@ -941,6 +1472,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        if self.inner.is_read_only(parent) || self.inner.is_read_only(newparent) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] rename(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \\
             newname: {:?}, flags: {})",
@@ -957,6 +1492,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         newname: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        if self.inner.is_read_only(newparent) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] link(ino: {:#x?}, newparent: {:#x?}, newname: {:?})",
             ino, newparent, newname
@@ -964,21 +1503,32 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         reply.error(libc::EPERM);
     }
 
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        reply.opened(0, 0);
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let writes = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        if writes && self.inner.is_read_only(ino) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        self.spawn(move |fs| async move {
+            fs.cache.open(ino);
+            reply.opened(0, Inner::<TCache>::open_flags(ino));
+        });
     }
 
     fn release(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        reply.ok();
+        self.spawn(move |fs| async move {
+            fs.cache.close(ino);
+            fs.flush(ino, reply).await
+        });
     }
 
     fn fsync(
@@ -990,14 +1540,17 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         reply: fuser::ReplyEmpty,
     ) {
         debug!(
-            "[Not Implemented] fsync(ino: {:#x?}, fh: {}, datasync: {})",
+            "fsync(ino: {:#x?}, fh: {}, datasync: {})",
             ino, fh, datasync
         );
-        reply.error(libc::ENOSYS);
+        self.spawn(move |fs| async move { fs.flush(ino, reply).await });
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        reply.opened(0, 0);
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        self.spawn(move |fs| async move {
+            fs.cache.open(ino);
+            reply.opened(0, Inner::<TCache>::open_flags(ino));
+        });
     }
 
     fn readdirplus(
@@ -1018,12 +1571,15 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
     fn releasedir(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        reply.ok();
+        self.spawn(move |fs| async move {
+            fs.cache.close(ino);
+            reply.ok();
+        });
     }
 
     fn fsyncdir(
@@ -1055,6 +1611,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         position: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        if self.inner.is_read_only(ino) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
             ino, name, flags, position
@@ -1092,6 +1652,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        if self.inner.is_read_only(ino) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
             ino, name
@@ -1189,6 +1753,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         mode: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        if self.inner.is_read_only(ino) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] fallocate(ino: {:#x?}, fh: {}, offset: {}, \\
             length: {}, mode: {})",
@@ -1226,6 +1794,10 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         flags: u32,
         reply: fuser::ReplyWrite,
     ) {
+        if self.inner.is_read_only(ino_out) {
+            reply.error(libc::EROFS);
+            return;
+        }
         debug!(
             "[Not Implemented] copy_file_range(ino_in: {:#x?}, fh_in: {}, \\
             offset_in: {}, ino_out: {:#x?}, fh_out: {}, offset_out: {}, \\
@@ -1235,3 +1807,103 @@ impl<TCache: BlockingCache> Filesystem for WhenFS<TCache> {
         reply.error(libc::ENOSYS);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FsConfig, WhenFS};
+    use crate::{
+        cache::{memory_cache, Cache, CacheConfig, WhenFSCache},
+        calendar::memory::MemoryClient,
+        store::CalStore,
+    };
+    use std::{ffi::OsStr, path::Path, sync::Arc};
+
+    type MemoryCache = WhenFSCache<CalStore<MemoryClient>>;
+    type MemoryFS = WhenFS<MemoryCache>;
+
+    async fn memory_fs() -> MemoryFS {
+        WhenFS::new(
+            memory_cache(CacheConfig::default()).await,
+            tokio::runtime::Handle::current(),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn errno(result: std::io::Result<impl std::fmt::Debug>) -> i32 {
+        result.unwrap_err().raw_os_error().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_files_by_path() {
+        let fs = memory_fs().await;
+        let dir = fs
+            .inner
+            .create_child(0, 0, 1, OsStr::new("dir"), libc::S_IFDIR | 0o755)
+            .await
+            .unwrap();
+        let path = Path::new("/dir/../dir/./file");
+        let attr = fs.write(0, 0, path, b"long contents", 0o640).await.unwrap();
+        fs.write(0, 0, path, b"short", 0o640).await.unwrap();
+
+        let ino = fs.resolve(0, 0, Path::new("dir/file")).await.unwrap();
+        assert_eq!(ino, attr.ino);
+        assert_eq!(fs.read(0, 0, ino).await.unwrap(), b"short");
+        assert_eq!(fs.stat(ino).await.unwrap().size, 5);
+        let listed = fs.list(0, 0, dir.ino).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, "file");
+        assert_eq!(fs.resolve(0, 0, Path::new("/..")).await.unwrap(), 1);
+
+        // Other users can't read the file, nor change the directory
+        assert_eq!(errno(fs.read(1000, 1000, ino).await), libc::EACCES);
+        assert_eq!(errno(fs.remove(1000, 1000, path).await), libc::EACCES);
+        assert_eq!(
+            errno(fs.remove(0, 0, Path::new("/dir")).await),
+            libc::EISDIR
+        );
+
+        fs.remove(0, 0, path).await.unwrap();
+        assert_eq!(errno(fs.resolve(0, 0, path).await), libc::ENOENT);
+        assert!(fs.list(0, 0, dir.ino).await.unwrap().is_empty());
+        assert_eq!(errno(fs.stat(ino).await), libc::ENOENT);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_refuses_changes() {
+        let cache = memory_cache(CacheConfig::default()).await;
+        // Not even a root is created
+        let config = FsConfig {
+            read_only: true,
+            ..Default::default()
+        };
+        let fs = WhenFS::with_config(cache, tokio::runtime::Handle::current(), config)
+            .await
+            .unwrap();
+        assert!(fs.inner.cache.get(1).await.unwrap().is_none());
+
+        let fs = memory_fs().await;
+        let fs = WhenFS::with_config(
+            Arc::into_inner(fs.inner).unwrap().cache,
+            tokio::runtime::Handle::current(),
+            config,
+        )
+        .await
+        .unwrap();
+        let welcome = Path::new("WelcomeToWhenFS");
+        let ino = fs.resolve(0, 0, welcome).await.unwrap();
+        assert_eq!(
+            errno(fs.write(0, 0, welcome, b"data", 0o644).await),
+            libc::EROFS
+        );
+        let new = Path::new("new");
+        assert_eq!(
+            errno(fs.write(0, 0, new, b"data", 0o644).await),
+            libc::EROFS
+        );
+        assert_eq!(errno(fs.remove(0, 0, welcome).await), libc::EROFS);
+        assert_eq!(fs.inner.truncate(ino, 0).await.unwrap_err(), libc::EROFS);
+        assert_eq!(errno(fs.resolve(0, 0, new).await), libc::ENOENT);
+        assert!(fs.read(0, 0, ino).await.is_ok());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::cache::{Inode, SnapshotInfo};
+
+/// Name of the read-only directory at the mount root that holds the snapshots
+pub const DIR_NAME: &str = ".snapshots";
+
+/// Inode of the `.snapshots` directory itself
+pub const DIR: u64 = u64::MAX;
+
+/// Inodes below `.snapshots` have the top bit set, followed by the position of their
+/// snapshot and their inode number within it
+const SNAPSHOT_BIT: u64 = 1 << 63;
+const INODE_BITS: u32 = 40;
+const INODE_MASK: u64 = (1 << INODE_BITS) - 1;
+/// The last position is left out, so that no inode below `.snapshots` is `DIR`
+const MAX_SNAPSHOTS: u64 = (1 << (63 - INODE_BITS)) - 1;
+
+/// Inode the kernel sees for `ino` of the snapshot at position `snapshot`, if it fits
+pub fn encode(snapshot: usize, ino: Inode) -> Option<u64> {
+    let snapshot = u64::try_from(snapshot).ok()?;
+    if ino > INODE_MASK || snapshot >= MAX_SNAPSHOTS {
+        return None;
+    }
+    Some(SNAPSHOT_BIT | snapshot << INODE_BITS | ino)
+}
+
+/// Position of the snapshot an inode belongs to and its inode number within it, or `None`
+/// for inodes of the mounted tree
+pub fn decode(ino: u64) -> Option<(usize, Inode)> {
+    if ino & SNAPSHOT_BIT == 0 || ino == DIR {
+        return None;
+    }
+    let snapshot = (ino & !SNAPSHOT_BIT) >> INODE_BITS;
+    Some((snapshot as usize, ino & INODE_MASK))
+}
+
+/// Name of a snapshot in `.snapshots`, which sorts by when it was taken
+pub fn entry_name(snapshot: &SnapshotInfo) -> String {
+    format!(
+        "{}_{}",
+        snapshot.created.format("%Y-%m-%dT%H:%M:%SZ"),
+        snapshot.name.replace('/', "_")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, DIR, INODE_MASK, MAX_SNAPSHOTS};
+
+    #[test]
+    fn test_inodes_round_trip() {
+        for (snapshot, ino) in [(0, 1), (3, 42), (MAX_SNAPSHOTS as usize - 1, INODE_MASK)] {
+            let encoded = encode(snapshot, ino).unwrap();
+            assert_ne!(encoded, DIR);
+            assert_eq!(decode(encoded), Some((snapshot, ino)));
+        }
+        assert_eq!(encode(0, INODE_MASK + 1), None);
+        assert_eq!(encode(MAX_SNAPSHOTS as usize, 1), None);
+        assert_eq!(decode(DIR), None);
+        assert_eq!(decode(1), None);
+    }
+}
@@ -1,25 +1,288 @@
-use std::path::PathBuf;
+use std::{
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
-use calendar::{gcal::types::GCalEvent, CalendarClient};
-use clap::Parser;
-use fuser::MountOption;
+use anyhow::Context;
+use cache::Cache;
+use calendar::{gcal::GCalClient, Calendar, CalendarClient};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use fuser::{FileAttr, FileType, MountOption};
 use once_cell::sync::Lazy;
-use store::CalStoreEntry;
+use store::{CalStoreEntry, Store};
 use tracing::info;
 use tracing_subscriber::{fmt, EnvFilter};
 
+pub mod archive;
 pub mod cache;
 pub mod calendar;
 pub mod fs;
 pub mod object;
 pub mod store;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    const FS_NAME: &str = "WhenFS";
+const FS_NAME: &str = "WhenFS";
+
+type GCalStore = store::CalStore<GCalClient>;
+type GCalCache = cache::WhenFSCache<GCalStore>;
+
+/// Exit status of a failed command, other than `fsck`
+const EXIT_FAILURE: u8 = 1;
+/// `fsck` exits like fsck(8): 1 if it fixed every problem, 4 if problems are left, and 8
+/// if it couldn't check the filesystem at all
+const EXIT_FSCK_CORRECTED: u8 = 1;
+const EXIT_FSCK_UNCORRECTED: u8 = 4;
+const EXIT_FSCK_FAILURE: u8 = 8;
+
+fn main() -> ExitCode {
     let _ = &*LOGGER;
-    let args = Args::parse();
-    let client = calendar::gcal::GCalClient::new(args.secret).await?;
+    let cli = Cli::parse();
+    let failure = match cli.command {
+        Command::Fsck { .. } => EXIT_FSCK_FAILURE,
+        _ => EXIT_FAILURE,
+    };
+    // FUSE requests are served by the runtime's worker threads
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = cli.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    let result = runtime
+        .enable_all()
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| runtime.block_on(run(cli.command)));
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            ExitCode::from(failure)
+        }
+    }
+}
+
+async fn run(command: Command) -> anyhow::Result<ExitCode> {
+    match command {
+        Command::Mkfs(args) => mkfs(args).await?,
+        Command::Mount(args) => mount(*args).await?,
+        Command::Info(args) => {
+            let calendar_id = args.calendar.clone();
+            let cache_config = cache::CacheConfig {
+                read_only: true,
+                ..Default::default()
+            };
+            let cache = open(args, cache_config).await?;
+            let superblock = cache.superblock();
+            println!("Calendar:        {calendar_id}");
+            println!("Root event:      {}", cache.get_recovery_id().root_id);
+            println!("UUID:            {}", superblock.uuid);
+            println!("Format version:  {}", superblock.version);
+            println!("Created:         {}", superblock.created.to_rfc3339());
+            let features: Vec<&str> = superblock
+                .incompat
+                .iter()
+                .chain(&superblock.compat)
+                .map(String::as_str)
+                .collect();
+            println!("Features:        {}", features.join(", "));
+            println!(
+                "Layout:          {}",
+                serde_json::to_string(&superblock.layout)?
+            );
+            println!("Inodes:          {}", cache.inode_count().await);
+            println!("Snapshots:       {}", superblock.snapshots.len());
+        }
+        Command::Fsck { open: args, repair } => {
+            let cache = open(args, cache::CacheConfig::default()).await?;
+            let report = cache.fsck(repair).await?;
+            for problem in &report.problems {
+                println!("{problem}");
+            }
+            info!(
+                checked = report.checked,
+                problems = report.problems.len(),
+                repaired = report.repaired,
+                "Finished checking filesystem"
+            );
+            if report.problems.is_empty() {
+                return Ok(ExitCode::SUCCESS);
+            }
+            if !repair {
+                eprintln!(
+                    "Found {} problems, run fsck --repair to fix them",
+                    report.problems.len()
+                );
+                return Ok(ExitCode::from(EXIT_FSCK_UNCORRECTED));
+            }
+            let left = cache.fsck(false).await?.problems;
+            if !left.is_empty() {
+                eprintln!("{} problems are left after repairing", left.len());
+                return Ok(ExitCode::from(EXIT_FSCK_UNCORRECTED));
+            }
+            return Ok(ExitCode::from(EXIT_FSCK_CORRECTED));
+        }
+        Command::Gc(args) => {
+            let cache = open(args, cache::CacheConfig::default()).await?;
+            let deleted = cache.sweep().await?;
+            info!(deleted, "Finished collecting garbage");
+        }
+        Command::Repair(args) => {
+            let cache = open(args, cache::CacheConfig::default()).await?;
+            let repaired = cache.repair().await?;
+            info!(repaired, "Finished repairing filesystem");
+        }
+        Command::Snapshot(SnapshotCommand::Create { open: args, name }) => {
+            let cache = open(args, cache::CacheConfig::default()).await?;
+            let snapshot = cache.snapshot(name).await?;
+            println!("{}\t{}", snapshot.name, snapshot.created.to_rfc3339());
+        }
+        Command::Snapshot(SnapshotCommand::List(args)) => {
+            let cache_config = cache::CacheConfig {
+                read_only: true,
+                ..Default::default()
+            };
+            let cache = open(args, cache_config).await?;
+            for snapshot in cache.snapshots() {
+                println!("{}\t{}", snapshot.name, snapshot.created.to_rfc3339());
+            }
+        }
+        Command::Ls { open: args, path } => {
+            let (fs, uid, gid) = open_offline(args, true).await?;
+            let ino = fs.resolve(uid, gid, &path).await.context(display(&path))?;
+            let attr = fs.stat(ino).await?;
+            let mut entries = match attr.kind {
+                FileType::Directory => fs.list(uid, gid, ino).await.context(display(&path))?,
+                _ => vec![(path.display().to_string(), attr)],
+            };
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (name, attr) in entries {
+                println!(
+                    "{} {:>3} {:>5} {:>5} {:>10} {} {name}",
+                    mode_string(&attr),
+                    attr.nlink,
+                    attr.uid,
+                    attr.gid,
+                    attr.size,
+                    DateTime::<Utc>::from(attr.mtime).format("%Y-%m-%d %H:%M")
+                );
+            }
+        }
+        Command::Stat { open: args, path } => {
+            let (fs, uid, gid) = open_offline(args, true).await?;
+            let ino = fs.resolve(uid, gid, &path).await.context(display(&path))?;
+            let attr = fs.stat(ino).await?;
+            let time = |time| DateTime::<Utc>::from(time).to_rfc3339();
+            println!("Path:            {}", path.display());
+            println!("Inode:           {}", attr.ino);
+            println!("Type:            {:?}", attr.kind);
+            println!("Size:            {}", attr.size);
+            println!("Links:           {}", attr.nlink);
+            println!(
+                "Mode:            {:04o} ({})",
+                attr.perm,
+                mode_string(&attr)
+            );
+            println!("Owner:           {}:{}", attr.uid, attr.gid);
+            println!("Accessed:        {}", time(attr.atime));
+            println!("Modified:        {}", time(attr.mtime));
+            println!("Changed:         {}", time(attr.ctime));
+            println!("Created:         {}", time(attr.crtime));
+        }
+        Command::Cat { open: args, path } => {
+            let (fs, uid, gid) = open_offline(args, true).await?;
+            let ino = fs.resolve(uid, gid, &path).await.context(display(&path))?;
+            let data = fs.read(uid, gid, ino).await.context(display(&path))?;
+            std::io::stdout().write_all(&data)?;
+        }
+        Command::Get {
+            open: args,
+            path,
+            local,
+        } => {
+            let (fs, uid, gid) = open_offline(args, true).await?;
+            let ino = fs.resolve(uid, gid, &path).await.context(display(&path))?;
+            let data = fs.read(uid, gid, ino).await.context(display(&path))?;
+            tokio::fs::write(&local, data)
+                .await
+                .context(display(&local))?;
+        }
+        Command::Put {
+            open: args,
+            local,
+            path,
+        } => {
+            let data = tokio::fs::read(&local).await.context(display(&local))?;
+            let mode = tokio::fs::metadata(&local).await?.permissions().mode() & 0o7777;
+            let (fs, uid, gid) = open_offline(args, false).await?;
+            // Like cp, putting a file into a directory keeps its name
+            let path = match fs.resolve(uid, gid, &path).await {
+                Ok(ino) if fs.stat(ino).await?.kind == FileType::Directory => path.join(
+                    local
+                        .file_name()
+                        .with_context(|| format!("{} has no file name", local.display()))?,
+                ),
+                _ => path,
+            };
+            let attr = fs
+                .write(uid, gid, &path, &data, mode)
+                .await
+                .context(display(&path))?;
+            info!(path = %path.display(), ino = attr.ino, size = attr.size, "Stored file");
+        }
+        Command::Rm { open: args, path } => {
+            let (fs, uid, gid) = open_offline(args, false).await?;
+            fs.remove(uid, gid, &path).await.context(display(&path))?;
+        }
+        Command::Export {
+            open: args,
+            archive,
+        } => {
+            let cache_config = cache::CacheConfig {
+                read_only: true,
+                ..Default::default()
+            };
+            let cache = open(args, cache_config).await?;
+            let stats = if archive == Path::new("-") {
+                archive::export(&cache, std::io::stdout().lock()).await?
+            } else {
+                let file = std::fs::File::create(&archive).context(display(&archive))?;
+                archive::export(&cache, std::io::BufWriter::new(file)).await?
+            };
+            eprintln!(
+                "Exported {} directories, {} files and {} links, {} bytes",
+                stats.directories, stats.files, stats.links, stats.bytes
+            );
+        }
+        Command::Import {
+            open: args,
+            archive,
+        } => {
+            let cache = open(args, cache::CacheConfig::default()).await?;
+            fs::WhenFS::create_root(&cache).await?;
+            let stats = if archive == Path::new("-") {
+                archive::import(&cache, std::io::stdin().lock()).await?
+            } else {
+                let file = std::fs::File::open(&archive).context(display(&archive))?;
+                archive::import(&cache, std::io::BufReader::new(file)).await?
+            };
+            eprintln!(
+                "Imported {} directories, {} files and {} links, {} bytes",
+                stats.directories, stats.files, stats.links, stats.bytes
+            );
+            if stats.skipped > 0 {
+                eprintln!("Skipped {} unsupported entries", stats.skipped);
+            }
+        }
+        Command::Unmount { mountpoint } => unmount(&mountpoint)?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Creates a filesystem, in a new calendar unless an existing one is named
+async fn mkfs(args: MkfsArgs) -> anyhow::Result<()> {
+    let client = GCalClient::new(args.store.secret.clone()).await?;
     let calendar = match args.calendar {
         Some(calendar_id) => {
             info!("Attempting to use existing calendar");
@@ -32,47 +295,531 @@ async fn main() -> anyhow::Result<()> {
                 .await?
         }
     };
-    let store = store::CalStore::new(client, calendar);
-    let cache = match args.root_event {
-        Some(root_event_id) => {
-            info!("Attempting to recover existing {FS_NAME} filesystem");
-            let root_event = CalStoreEntry {
-                name: String::from("root event"),
-                events: vec![GCalEvent {
-                    id: root_event_id,
-                    details: Default::default(),
-                }],
-            };
-            let cache = cache::WhenFSCache::recover(store, root_event).await?;
-            info!("Recovered filesystem cache");
-            cache
+    let calendar_id = calendar.id().to_string();
+    let layout = args.store.layout();
+    let mut store = args.store.into_store(client, calendar);
+    store.set_layout(&layout.apply(store.layout()));
+    if cache::WhenFSCache::find_superblock(&store).await?.is_some() {
+        anyhow::bail!("Calendar {calendar_id} already holds a {FS_NAME} filesystem");
+    }
+    info!("Creating a new filesystem");
+    let cache = cache::WhenFSCache::new(store, cache::CacheConfig::default()).await?;
+    fs::WhenFS::create_root(&cache).await?;
+    let root_id = cache.get_recovery_id().root_id;
+    println!("Calendar:        {calendar_id}");
+    println!("Root event:      {root_id}");
+    Ok(())
+}
+
+async fn mount(args: MountArgs) -> anyhow::Result<()> {
+    let calendar_id = args.open.calendar.clone();
+    let mut cache_config = cache::CacheConfig::default();
+    if let Some(delay) = args.write_back_delay_ms {
+        let mut write_back = cache::WriteBack::new(Duration::from_millis(delay));
+        if let Some(max_dirty_bytes) = args.write_back_max_dirty_bytes {
+            write_back.max_dirty_bytes = max_dirty_bytes;
         }
+        cache_config.write_back = Some(write_back);
+    }
+    if let Some(max_bytes) = args.cache_max_bytes {
+        cache_config.max_cached_bytes = Some(max_bytes);
+    }
+    cache_config.max_cached_objects = args.cache_max_objects;
+    if args.prefetch_concurrency == Some(0) {
+        cache_config.prefetch = None;
+    } else if let Some(prefetch) = &mut cache_config.prefetch {
+        if let Some(concurrency) = args.prefetch_concurrency {
+            prefetch.concurrency = concurrency;
+        }
+        if let Some(max_objects) = args.prefetch_max_objects {
+            prefetch.max_objects = max_objects;
+        }
+    }
+    if args.disk_cache || args.disk_cache_dir.is_some() {
+        let dir = args
+            .disk_cache_dir
+            .or_else(|| cache::disk::DiskCacheConfig::default_dir(&calendar_id))
+            .ok_or_else(|| anyhow::anyhow!("No cache directory found, use --disk-cache-dir"))?;
+        cache_config.disk = Some(cache::disk::DiskCacheConfig {
+            dir,
+            max_bytes: args
+                .disk_cache_max_bytes
+                .unwrap_or(cache::disk::DiskCacheConfig::DEFAULT_MAX_BYTES),
+        });
+    }
+    // Whichever of `ro` and `rw` comes last wins, as with mount(8)
+    let read_only = args.snapshot.is_some()
+        || args
+            .options
+            .iter()
+            .rev()
+            .find(|option| matches!(option, MountOption::RO | MountOption::RW))
+            == Some(&MountOption::RO);
+    let mut options = args.options;
+    if read_only && !options.contains(&MountOption::RO) {
+        options.push(MountOption::RO);
+    }
+    if !options
+        .iter()
+        .any(|option| matches!(option, MountOption::FSName(_)))
+    {
+        options.push(MountOption::FSName(FS_NAME.into()));
+    }
+    cache_config.snapshot = args.snapshot.clone();
+    cache_config.read_only = read_only;
+    let cache = open(args.open, cache_config).await?;
+
+    let handle = tokio::runtime::Handle::current();
+    let mut fs_config = fs::FsConfig::default();
+    if let Some(ttl) = args.attr_ttl_ms {
+        fs_config.attr_ttl = Duration::from_millis(ttl);
+    }
+    if let Some(ttl) = args.entry_ttl_ms {
+        fs_config.entry_ttl = Duration::from_millis(ttl);
+    }
+    if let Some(ttl) = args.negative_ttl_ms {
+        fs_config.negative_ttl = Duration::from_millis(ttl);
+    }
+    fs_config.read_only = read_only;
+    let fs = fs::WhenFS::with_config(cache, handle, fs_config).await?;
+    let mountpoint = args.mountpoint;
+    info!(mountpoint = %mountpoint.display(), ?options, "Mounting filesystem");
+    // The session blocks its thread, and hands requests back to the runtime
+    tokio::task::spawn_blocking(move || fuser::mount2(fs, mountpoint, &options)).await??;
+    Ok(())
+}
+
+/// Recovers the filesystem in an existing calendar
+async fn open(args: OpenArgs, mut cache_config: cache::CacheConfig) -> anyhow::Result<GCalCache> {
+    let client = GCalClient::new(args.store.secret.clone()).await?;
+    let calendar = client.calendar_from_id(args.calendar.clone()).await?;
+    cache_config.layout = args.store.layout();
+    let store = args.store.into_store(client, calendar);
+    if !args.no_journal {
+        cache_config.journal = args
+            .journal
+            .or_else(|| cache::journal::Journal::default_path(&args.calendar));
+    }
+    let superblock = match args.root_event {
+        Some(root_event_id) => root_entry(&store, root_event_id).await,
         None => {
-            info!("Creating a new filesystem");
-            cache::WhenFSCache::new(store).await?
+            info!("Looking for an existing {FS_NAME} filesystem in the calendar");
+            cache::WhenFSCache::find_superblock(&store)
+                .await
+                .context("Pick a filesystem with --root-event")?
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "No {FS_NAME} filesystem found in calendar {}, create one with mkfs or \
+                         name its root event with --root-event",
+                        args.calendar
+                    )
+                })?
         }
     };
+    info!("Attempting to recover existing {FS_NAME} filesystem");
+    let cache = cache::WhenFSCache::recover(store, superblock, cache_config).await?;
+    info!("Recovered filesystem cache");
+    Ok(cache)
+}
 
+/// Opens a filesystem for the commands that work on files without mounting it, along with
+/// the user and group whose permissions they have. Commands that only read open it
+/// read-only, so they never write to the journal or the calendar.
+async fn open_offline(
+    args: OpenArgs,
+    read_only: bool,
+) -> anyhow::Result<(fs::WhenFS<GCalCache>, u32, u32)> {
+    let cache_config = cache::CacheConfig {
+        read_only,
+        ..Default::default()
+    };
+    let cache = open(args, cache_config).await?;
     let handle = tokio::runtime::Handle::current();
-    let fs = fs::WhenFS::new(cache, handle)?;
-    let mount_point = args.mount.as_deref().unwrap_or("/mnt/whenfs");
-    info!("Mounting filesystem");
-    fuser::mount2(fs, mount_point, &[MountOption::FSName(FS_NAME.into())])?;
-    Ok(())
+    let fs_config = fs::FsConfig {
+        read_only,
+        ..Default::default()
+    };
+    let fs = fs::WhenFS::with_config(cache, handle, fs_config).await?;
+    // SAFETY: getuid and getgid always succeed and touch no memory
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    Ok((fs, uid, gid))
+}
+
+/// Parses an option of `mount -o`. Options WhenFS doesn't know are passed to the kernel
+/// as they are.
+fn mount_option(option: &str) -> Result<MountOption, String> {
+    if let Some((key, value)) = option.split_once('=') {
+        return Ok(match key {
+            "fsname" => MountOption::FSName(value.into()),
+            "subtype" => MountOption::Subtype(value.into()),
+            _ => MountOption::CUSTOM(option.into()),
+        });
+    }
+    Ok(match option {
+        "" => return Err(String::from("empty mount option")),
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        _ => MountOption::CUSTOM(option.into()),
+    })
+}
+
+fn display(path: &Path) -> String {
+    path.display().to_string()
 }
 
+/// Type and permission bits as `ls -l` shows them, e.g. `drwxr-xr-x`
+fn mode_string(attr: &FileAttr) -> String {
+    let kind = match attr.kind {
+        FileType::Directory => 'd',
+        _ => '-',
+    };
+    let permissions = "rwxrwxrwx".chars().enumerate().map(|(i, permission)| {
+        if attr.perm & (0o400 >> i) != 0 {
+            permission
+        } else {
+            '-'
+        }
+    });
+    std::iter::once(kind).chain(permissions).collect()
+}
+
+/// Entry of the event passed as `--root-event`, which holds either the superblock or, for
+/// filesystems from before superblocks, the root of the inode table
+async fn root_entry<C: CalendarClient>(
+    store: &store::CalStore<C>,
+    event_id: String,
+) -> CalStoreEntry {
+    let superblock = CalStoreEntry {
+        name: String::from(cache::superblock::SUPERBLOCK_NAME),
+        event_ids: vec![event_id.clone()],
+    };
+    match store
+        .retrieve::<serde_json::Value>(superblock.clone())
+        .await
+    {
+        Ok(_) => superblock,
+        Err(_) => CalStoreEntry {
+            name: String::from(cache::table::ROOT_NAME),
+            event_ids: vec![event_id],
+        },
+    }
+}
+
+/// Unmounts a mounted filesystem with whichever of the usual tools is installed
+fn unmount(mountpoint: &Path) -> anyhow::Result<()> {
+    let tools: [(&str, &[&str]); 3] = [
+        ("fusermount3", &["-u"]),
+        ("fusermount", &["-u"]),
+        ("umount", &[]),
+    ];
+    for (tool, args) in tools {
+        match std::process::Command::new(tool)
+            .args(args)
+            .arg(mountpoint)
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => anyhow::bail!("{tool} failed with {status}"),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).context(format!("Couldn't run {tool}")),
+        }
+    }
+    anyhow::bail!("Found neither fusermount nor umount")
+}
+
+/// Google Calendar as a FUSE filesystem
 #[derive(Parser, Debug)]
-struct Args {
-    #[arg(long)]
-    mount: Option<String>,
+#[command(version)]
+struct Cli {
+    /// Number of threads serving requests, one per CPU core by default
+    #[arg(long, global = true)]
+    worker_threads: Option<usize>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a new filesystem, in a new calendar unless one is named
+    Mkfs(MkfsArgs),
+    /// Mount an existing filesystem
+    Mount(Box<MountArgs>),
+    /// Show what the superblock says about a filesystem
+    Info(OpenArgs),
+    /// Check the filesystem for dangling entries, orphans, wrong link counts and unreadable
+    /// objects
+    ///
+    /// Exits with 0 if there are no problems, 1 if every problem was fixed, 4 if problems
+    /// are left and 8 if the check failed. The filesystem must not be mounted elsewhere
+    /// while this runs.
+    Fsck {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Drop dangling entries, move orphans to `lost+found` and correct link counts
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Delete calendar events unreachable from the superblock
+    ///
+    /// The filesystem must not be mounted elsewhere while this runs.
+    Gc(OpenArgs),
+    /// Recreate lost events of erasure-coded data
+    Repair(OpenArgs),
+    /// Manage named snapshots
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// List a directory, or show a file, like `ls -l`
+    ///
+    /// This and the other file commands work on the filesystem without mounting it, with
+    /// the permissions of the user running them. The filesystem must not be mounted
+    /// elsewhere while they change it.
+    Ls {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Path within the filesystem
+        #[arg(default_value = "/")]
+        path: PathBuf,
+    },
+    /// Show the attributes of a file or directory
+    Stat {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Path within the filesystem
+        path: PathBuf,
+    },
+    /// Write the contents of a file to standard output
+    Cat {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Path within the filesystem
+        path: PathBuf,
+    },
+    /// Copy a file out of the filesystem
+    Get {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Path within the filesystem
+        path: PathBuf,
+        /// Local file to write
+        local: PathBuf,
+    },
+    /// Copy a local file into the filesystem, replacing the file at the path if there is one
+    Put {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Local file to read
+        local: PathBuf,
+        /// Path within the filesystem, or a directory to put the file into
+        path: PathBuf,
+    },
+    /// Remove a file
+    Rm {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Path within the filesystem
+        path: PathBuf,
+    },
+    /// Write every directory and file to a tar archive
+    ///
+    /// Modes, owners and timestamps are kept. The filesystem must not be changed elsewhere
+    /// while this runs.
+    Export {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Archive to write, `-` for standard output
+        archive: PathBuf,
+    },
+    /// Add the directories and files of a tar archive, replacing files that exist already
+    ///
+    /// Symlinks and special files are skipped. The filesystem must not be mounted
+    /// elsewhere while this runs.
+    Import {
+        #[command(flatten)]
+        open: OpenArgs,
+        /// Archive to read, `-` for standard input
+        archive: PathBuf,
+    },
+    /// Unmount a mounted filesystem
+    Unmount {
+        /// Directory the filesystem is mounted on
+        mountpoint: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Record the current files under a name, keeping them from being deleted
+    ///
+    /// The filesystem must not be mounted elsewhere while this runs.
+    Create {
+        #[command(flatten)]
+        open: OpenArgs,
+        name: String,
+    },
+    /// List snapshots with the time they were taken, oldest first
+    List(OpenArgs),
+}
+
+/// Options of every command that talks to the calendar
+#[derive(clap::Args, Debug)]
+struct StoreArgs {
+    /// OAuth client secret of the Google Cloud app to access the calendar with
     #[arg(long)]
     secret: PathBuf,
+    /// Maximum number of calendar events uploaded or downloaded concurrently
+    #[arg(long)]
+    parallelism: Option<usize>,
+    /// Which calendar event fields new data is written to. Defaults to what the filesystem
+    /// was created with, or to the description only for a new one.
+    #[arg(long, value_enum)]
+    packing: Option<store::Packing>,
+    /// Add parity events to new data, as `<data>+<parity>` chunks per stripe (e.g. `4+2`),
+    /// or `none` to stop adding them. Defaults to what the filesystem was created with.
     #[arg(long)]
+    erasure: Option<ErasureArg>,
+}
+
+/// Value of `--erasure`
+#[derive(Clone, Copy, Debug)]
+struct ErasureArg(Option<store::erasure::Erasure>);
+
+impl std::str::FromStr for ErasureArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self(None)),
+            _ => s.parse().map(|erasure| Self(Some(erasure))),
+        }
+    }
+}
+
+impl StoreArgs {
+    fn into_store(
+        self,
+        client: GCalClient,
+        calendar: <GCalClient as CalendarClient>::Calendar,
+    ) -> GCalStore {
+        let mut config = store::CalStoreConfig::default();
+        if let Some(parallelism) = self.parallelism {
+            config.parallelism = parallelism;
+        }
+        store::CalStore::with_config(client, calendar, config)
+    }
+
+    /// Layout settings given on the command line. Any left out keep the filesystem's, or
+    /// the defaults for a new one.
+    fn layout(&self) -> store::LayoutOverrides {
+        store::LayoutOverrides {
+            packing: self.packing,
+            erasure: self.erasure.map(|ErasureArg(erasure)| erasure),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct MkfsArgs {
+    #[command(flatten)]
+    store: StoreArgs,
+    /// Name of the new calendar
+    #[arg(long, conflicts_with = "calendar")]
     name: Option<String>,
+    /// Create the filesystem in this existing calendar instead of a new one
     #[arg(long)]
     calendar: Option<String>,
+}
+
+/// Options of every command that works on an existing filesystem
+#[derive(clap::Args, Debug)]
+struct OpenArgs {
+    #[command(flatten)]
+    store: StoreArgs,
+    /// Calendar holding the filesystem
+    #[arg(long)]
+    calendar: String,
+    /// Event holding the superblock of the filesystem. Only needed if the calendar holds
+    /// several filesystems, or one from before superblocks.
     #[arg(long)]
     root_event: Option<String>,
+    /// Journal transactions in this file, `$XDG_STATE_HOME/whenfs/<calendar-id>/journal` by
+    /// default, to clean up after a crash the next time the filesystem is opened
+    #[arg(long, conflicts_with = "no_journal")]
+    journal: Option<PathBuf>,
+    /// Don't journal transactions, leaving anything a crash left behind to `gc`
+    #[arg(long)]
+    no_journal: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct MountArgs {
+    #[command(flatten)]
+    open: OpenArgs,
+    /// Directory to mount the filesystem on
+    mountpoint: PathBuf,
+    /// Comma-separated mount options, e.g. `ro,allow_other,auto_unmount,default_permissions`
+    ///
+    /// `ro` refuses every change and writes nothing to the calendar. `auto_unmount` needs
+    /// `allow_other` or `allow_root`, or `user_allow_other` in /etc/fuse.conf. Options not
+    /// listed in mount.fuse(8) are passed to the kernel as they are.
+    #[arg(short = 'o', value_delimiter = ',', value_parser = mount_option)]
+    options: Vec<MountOption>,
+    /// Mount the snapshot with this name, read-only, instead of the current files
+    #[arg(long)]
+    snapshot: Option<String>,
+    /// Buffer writes in memory and upload them this many milliseconds later, instead of
+    /// uploading every write before it returns
+    #[arg(long)]
+    write_back_delay_ms: Option<u64>,
+    /// With write-back, upload early once this many bytes of changes are buffered
+    #[arg(long, requires = "write_back_delay_ms")]
+    write_back_max_dirty_bytes: Option<usize>,
+    /// Evict unchanged objects from memory once they take up more than this many bytes
+    #[arg(long)]
+    cache_max_bytes: Option<usize>,
+    /// Evict unchanged objects from memory once more than this many are cached
+    #[arg(long)]
+    cache_max_objects: Option<usize>,
+    /// Maximum number of objects fetched ahead of time concurrently, 0 turns prefetching off
+    #[arg(long)]
+    prefetch_concurrency: Option<usize>,
+    /// Maximum number of a directory's children fetched ahead of time when it's listed
+    #[arg(long)]
+    prefetch_max_objects: Option<usize>,
+    /// Keep downloaded objects in `$XDG_CACHE_HOME/whenfs/<calendar-id>` across mounts
+    #[arg(long)]
+    disk_cache: bool,
+    /// Keep downloaded objects in this directory across mounts
+    #[arg(long)]
+    disk_cache_dir: Option<PathBuf>,
+    /// Delete the least recently used objects once the disk cache grows past this many bytes
+    #[arg(long)]
+    disk_cache_max_bytes: Option<u64>,
+    /// How many milliseconds the kernel may cache file attributes
+    #[arg(long)]
+    attr_ttl_ms: Option<u64>,
+    /// How many milliseconds the kernel may cache names it looked up. Snapshots created
+    /// outside the mount may go unnoticed in `.snapshots` for this long.
+    #[arg(long)]
+    entry_ttl_ms: Option<u64>,
+    /// How many milliseconds the kernel may remember that a name doesn't exist, 0 turns
+    /// negative caching off
+    #[arg(long)]
+    negative_ttl_ms: Option<u64>,
 }
 
 static LOGGER: Lazy<()> = Lazy::new(|| {
@@ -83,6 +830,8 @@ static LOGGER: Lazy<()> = Lazy::new(|| {
         .with_line_number(true)
         .with_level(true)
         .with_env_filter(EnvFilter::from_default_env())
+        // Standard output is for what commands print, like `cat` and `export -`
+        .with_writer(std::io::stderr)
         .init();
     info!("Initializing logging...");
 });
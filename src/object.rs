@@ -73,6 +73,21 @@ impl FileSystemObject {
             FileSystemObject::Dir(d) => &d.name,
         }
     }
+
+    /// Rough number of bytes the object takes up in memory
+    pub fn size(&self) -> usize {
+        let contents = match self {
+            FileSystemObject::File(f) => f.name.len() + f.data.len(),
+            FileSystemObject::Dir(d) => {
+                d.name.len()
+                    + d.entries
+                        .iter()
+                        .map(|entry| std::mem::size_of::<DirectoryEntry>() + entry.name.len())
+                        .sum::<usize>()
+            }
+        };
+        std::mem::size_of::<Self>() + contents
+    }
 }
 
 impl From<DirectoryObject> for FileSystemObject {
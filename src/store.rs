@@ -1,23 +1,38 @@
 use crate::calendar::{Calendar, CalendarClient, CalendarEventDetails, Event};
 use async_trait::async_trait;
+use erasure::{Erasure, ErasureManifest};
+use futures::{stream, StreamExt};
 use serde::Deserialize;
 use serde::{de::DeserializeOwned, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{fmt::Debug, hash::Hash};
 use thiserror::Error;
-use tracing::{debug, trace};
+use tracing::{debug, info, trace, warn};
 
-#[async_trait(?Send)]
-pub trait Store {
-    type Entry: Eq + Hash + Clone + DeserializeOwned + Serialize;
+pub mod erasure;
+
+/// Private extended property marking the tail event of a fixed item, set to the item's name
+const FIXED_PROPERTY: &str = "whenfs-fixed";
+
+#[async_trait]
+pub trait Store
+where
+    Self: Send + Sync,
+{
+    type Entry: Eq + Hash + Clone + Debug + DeserializeOwned + Serialize + Send + Sync;
     type Error: Debug + Send + Sync + std::error::Error;
 
-    async fn store<T: Serialize>(&self, item: &T, name: String)
-        -> Result<Self::Entry, Self::Error>;
+    async fn store<T: Serialize + Sync>(
+        &self,
+        item: &T,
+        name: String,
+    ) -> Result<Self::Entry, Self::Error>;
 
-    async fn retrieve<T: DeserializeOwned>(&self, id: Self::Entry) -> Result<T, Self::Error>;
+    async fn retrieve<T: DeserializeOwned + Send>(&self, id: Self::Entry)
+        -> Result<T, Self::Error>;
 
-    async fn update<T: Serialize>(
+    async fn update<T: Serialize + Sync>(
         &self,
         old: Self::Entry,
         new: &T,
@@ -25,13 +40,132 @@ pub trait Store {
 
     async fn delete(&self, item: Self::Entry) -> Result<(), Self::Error>;
 
+    /// Stores a small item so that `overwrite` can later replace it without its entry
+    /// changing, e.g. to keep a record at a location that is known in advance
+    async fn store_fixed<T: Serialize + Sync>(
+        &self,
+        item: &T,
+        name: String,
+    ) -> Result<Self::Entry, Self::Error>;
+
+    /// Replaces an item stored with `store_fixed` in place
+    async fn overwrite<T: Serialize + Sync>(
+        &self,
+        entry: &Self::Entry,
+        item: &T,
+    ) -> Result<(), Self::Error>;
+
+    /// Entries of every item stored with `store_fixed` under `name`
+    async fn find_fixed(&self, name: &str) -> Result<Vec<Self::Entry>, Self::Error>;
+
+    /// Recreates any lost redundant events of an item, returning how many were recreated
+    async fn repair(&self, item: Self::Entry) -> Result<usize, Self::Error>;
+
+    /// Deletes everything in the backing storage that doesn't belong to one of the `live`
+    /// items, returning how many events were deleted
+    async fn sweep(&self, live: Vec<Self::Entry>) -> Result<usize, Self::Error>;
+
     fn get_raw_id(&self, entry: &Self::Entry) -> RecoveryDetails;
+
+    /// How new items are written
+    fn layout(&self) -> Layout;
+
+    /// Writes new items with the packing and erasure coding of `layout`, e.g. the layout a
+    /// filesystem was created with
+    fn set_layout(&mut self, layout: &Layout);
 }
 
 #[derive(Debug)]
 pub struct CalStore<TCalendarClient: CalendarClient> {
     client: TCalendarClient,
     calendar: TCalendarClient::Calendar,
+    config: CalStoreConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct CalStoreConfig {
+    /// Maximum number of chunk events created or fetched at the same time
+    pub parallelism: usize,
+    /// Which event fields new chunks are written to
+    pub packing: Packing,
+    /// Parity chunks to add to new objects, if any
+    pub erasure: Option<Erasure>,
+}
+
+impl Default for CalStoreConfig {
+    fn default() -> Self {
+        Self {
+            parallelism: 8,
+            packing: Packing::Description,
+            erasure: None,
+        }
+    }
+}
+
+/// How chunk payload is laid out across an event's fields.
+///
+/// Chunks record the strategy they were written with, so a filesystem can be read no
+/// matter which strategy it is currently mounted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Packing {
+    /// Payload goes in the description only
+    Description,
+    /// Payload also fills the summary, the location after the chunk header, and
+    /// extended properties, for fewer events per object
+    AllFields,
+}
+
+/// How items are turned into calendar events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Layout {
+    pub encoding: Encoding,
+    pub compression: Compression,
+    pub encryption: Encryption,
+    pub packing: Packing,
+    pub erasure: Option<Erasure>,
+    /// Payload bytes per data chunk
+    pub chunk_size: usize,
+}
+
+/// Layout settings chosen for one run, which replace the ones a filesystem was written
+/// with. Settings left out keep the filesystem's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LayoutOverrides {
+    pub packing: Option<Packing>,
+    /// `Some(None)` turns erasure coding off
+    pub erasure: Option<Option<Erasure>>,
+}
+
+impl LayoutOverrides {
+    pub fn apply(&self, mut layout: Layout) -> Layout {
+        if let Some(packing) = self.packing {
+            layout.packing = packing;
+        }
+        if let Some(erasure) = self.erasure {
+            layout.erasure = erasure;
+        }
+        layout
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    /// URL-safe base64 of the item's JSON
+    JsonBase64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encryption {
+    None,
 }
 
 #[derive(Error, Debug)]
@@ -40,20 +174,86 @@ pub enum CalStoreError<T: CalendarClient> {
     EncodeDecode(#[from] encoding::EncodingError),
     #[error("Calendar error: {0}")]
     Calendar(<T as CalendarClient>::Error),
+    #[error("Corrupt calendar event {event_id}: {reason}")]
+    Corrupt { event_id: String, reason: String },
+    #[error("Erasure coding error: {0}")]
+    Erasure(#[from] erasure::ErasureError),
+    #[error("Repaired manifest ending at {0} no longer fits in its events")]
+    ManifestResized(String),
+    #[error("Corrupt entry for {0}: it lists no events")]
+    EmptyEntry(String),
 }
 
+/// Reference to a stored object. Only event IDs are kept, so references stay small no matter
+/// how large the object is.
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
-pub struct CalStoreEntry<TEvent: Event> {
+#[serde(from = "StoredEntry")]
+pub struct CalStoreEntry {
     pub name: String,
-    pub events: Vec<TEvent>,
+    /// IDs of the object's events, ending with the tail of its manifest chain
+    pub event_ids: Vec<String>,
+}
+
+/// Entries as persisted by any version of WhenFS. Older versions kept whole events.
+#[derive(Deserialize)]
+struct StoredEntry {
+    name: String,
+    #[serde(default)]
+    event_ids: Vec<String>,
+    #[serde(default)]
+    events: Vec<StoredEvent>,
+}
+
+#[derive(Deserialize)]
+struct StoredEvent {
+    id: String,
 }
 
-#[async_trait(?Send)]
+impl From<StoredEntry> for CalStoreEntry {
+    fn from(stored: StoredEntry) -> Self {
+        let event_ids = match stored.events.is_empty() {
+            true => stored.event_ids,
+            false => stored.events.into_iter().map(|event| event.id).collect(),
+        };
+        Self {
+            name: stored.name,
+            event_ids,
+        }
+    }
+}
+
+/// Lists the events holding an object's chunks, in chunk order.
+///
+/// Chunks don't point at each other, so they can be uploaded independently. The manifest
+/// itself is stored as a short chain of events whose tail identifies the whole object.
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    erasure: Option<ErasureManifest>,
+}
+
+/// Events fetched so far, by ID. Failed fetches are kept so that erasure-coded objects can
+/// be rebuilt around them.
+type Fetched<C> = HashMap<String, Result<<C as CalendarClient>::Event, CalStoreError<C>>>;
+
+/// Chunks of an erasure-coded object after rebuilding whatever was lost
+struct Rebuilt {
+    data: Vec<String>,
+    /// Parity chunks per stripe, where they were needed
+    parity: Vec<Vec<Option<String>>>,
+    /// Packing strategy the surviving chunks were written with
+    packing: Packing,
+    /// IDs of the events whose chunks had to be rebuilt
+    lost: Vec<String>,
+}
+
+#[async_trait]
 impl<TCalendarClient: CalendarClient> Store for CalStore<TCalendarClient> {
-    type Entry = CalStoreEntry<TCalendarClient::Event>;
+    type Entry = CalStoreEntry;
     type Error = CalStoreError<TCalendarClient>;
 
-    async fn store<T: Serialize>(
+    async fn store<T: Serialize + Sync>(
         &self,
         item: &T,
         name: String,
@@ -61,74 +261,375 @@ impl<TCalendarClient: CalendarClient> Store for CalStore<TCalendarClient> {
         debug!(%name, "Base64 encoding item for storage");
         let encoded = encoding::encode(item)?;
         debug!(%name, size_bytes = encoded.len(), "Base64 encoded item");
-        let split = zip::split(&encoded, self.client.limits().description);
+        let limits = self.client.limits();
+        let capacity = calendarize::capacity(self.config.packing, limits);
+        let chunk_size = match self.config.erasure {
+            // Parity is binary, so leave room for base64-encoding it
+            Some(_) => erasure::shard_size(capacity),
+            None => capacity,
+        };
+        let split = zip::split(&encoded, chunk_size);
         debug!(
             %name,
             number_of_chunks = split.len(),
-            chunk_size_bytes = self.client.limits().description,
+            chunk_size_bytes = chunk_size,
+            packing = ?self.config.packing,
             "Split encoded data up into chunks"
         );
+        let parity = match self.config.erasure {
+            Some(layout) => Some(erasure::encode(&split, layout, chunk_size)?),
+            None => None,
+        };
         debug!(%name, "Converting split encoded data into calendar events");
-        let calendarized = calendarize::calendarize(split);
-        debug!(%name, "Uploading calendar events");
-        let events = self.upload(calendarized, name.clone()).await?;
-        Ok(Self::Entry { name, events })
-    }
-
-    async fn retrieve<T: DeserializeOwned>(&self, entry: Self::Entry) -> Result<T, Self::Error> {
-        let CalStoreEntry { name, events } = entry;
-        let tail_event = events.last().unwrap();
-        debug!(
-            ?name,
-            tail_event_id = ?tail_event.id(),
-            "Downloading calendar events"
+        let calendarized = calendarize::calendarize(
+            split,
+            &name,
+            calendarize::ChunkKind::Data,
+            self.config.packing,
+            limits,
         );
-        let events = self.download(tail_event.id().clone(), name.clone()).await?;
-        debug!(
-            ?name,
-            number_of_events = events.len(),
-            "Downloaded calendar events"
-        );
-        let details = events
-            .iter()
-            .map(|event| event.details().clone())
-            .collect::<Vec<_>>();
-        debug!(?name, "Collected calendar event details");
-        let uncalendarized = calendarize::uncalendarize(details);
+        debug!(%name, "Uploading calendar events");
+        let mut events = self.upload_chunks(calendarized, name.clone()).await?;
+        let mut manifest = Manifest {
+            chunks: events.iter().map(|event| event.id().to_string()).collect(),
+            erasure: None,
+        };
+        if let (Some(layout), Some(parity)) = (self.config.erasure, parity) {
+            let stripe_sizes: Vec<usize> = parity.iter().map(Vec::len).collect();
+            let calendarized = calendarize::calendarize(
+                parity.into_iter().flatten().collect(),
+                &name,
+                calendarize::ChunkKind::Parity,
+                self.config.packing,
+                limits,
+            );
+            debug!(
+                %name,
+                number_of_parity_chunks = calendarized.len(),
+                %layout,
+                "Uploading parity"
+            );
+            let parity_events = self.upload_chunks(calendarized, name.clone()).await?;
+            let mut parity_ids = parity_events.iter().map(|event| event.id().to_string());
+            manifest.erasure = Some(ErasureManifest {
+                erasure: layout,
+                shard_size: chunk_size,
+                length: encoded.len(),
+                checksum: checksum::digest(&encoded),
+                parity: stripe_sizes
+                    .into_iter()
+                    .map(|size| parity_ids.by_ref().take(size).collect())
+                    .collect(),
+            });
+            events.extend(parity_events);
+        }
+        let calendarized = self.calendarize_manifest(&manifest, &name)?;
         debug!(
-            ?name,
-            number_of_chunks = uncalendarized.len(),
-            "Condensed calendar event details into workable data chunks"
+            %name,
+            number_of_manifest_events = calendarized.len(),
+            "Uploading manifest"
         );
-        let zipped = zip::zip(uncalendarized);
+        events.extend(self.upload(calendarized, name.clone()).await?);
+        let event_ids = events.iter().map(|event| event.id().to_string()).collect();
+        Ok(Self::Entry { name, event_ids })
+    }
+
+    async fn retrieve<T: DeserializeOwned + Send>(
+        &self,
+        entry: Self::Entry,
+    ) -> Result<T, Self::Error> {
+        let tail_id = &Self::tail_id(&entry)?.clone();
+        let CalStoreEntry { name, event_ids } = entry;
+        debug!(?name, %tail_id, "Downloading calendar events");
+        let tail = self
+            .client
+            .get_event_by_id(&self.calendar, &tail_id.clone().into())
+            .await
+            .map_err(CalStoreError::Calendar)?;
+        let mut fetched = HashMap::new();
+        if !Self::is_manifest(&tail) {
+            // Chained layout written by older versions of WhenFS
+            let events = if event_ids.len() > 1 {
+                // The entry already lists every event, so there's no chain to walk
+                fetched.insert(tail_id.clone(), Ok(tail));
+                self.collect_chunks(&event_ids, &mut fetched).await?
+            } else {
+                let mut events = self
+                    .download(tail.details().summary.clone().into(), name.clone())
+                    .await?;
+                events.push(tail);
+                events
+            };
+            return Ok(encoding::decode(&Self::reassemble(&events)?)?);
+        }
+        // Only the manifest is walked, so parity is left for `rebuild` to fetch if needed
+        let mut manifest_events = self
+            .download(tail.details().summary.clone().into(), name.clone())
+            .await?;
+        manifest_events.push(tail);
+        let manifest: Manifest = encoding::decode(&Self::reassemble(&manifest_events)?)?;
         debug!(
             ?name,
-            "Zipped event data chunks back into contiguous memory"
+            number_of_chunks = manifest.chunks.len(),
+            erasure = ?manifest.erasure.as_ref().map(|erasure| erasure.erasure),
+            "Downloading chunks listed in manifest"
         );
+        let zipped = match &manifest.erasure {
+            None => {
+                let events = self.collect_chunks(&manifest.chunks, &mut fetched).await?;
+                debug!(
+                    ?name,
+                    number_of_events = events.len(),
+                    "Downloaded calendar events"
+                );
+                Self::reassemble(&events)?
+            }
+            Some(erasure) => {
+                let rebuilt = self
+                    .rebuild(&manifest.chunks, erasure, &mut fetched, false)
+                    .await?;
+                if !rebuilt.lost.is_empty() {
+                    warn!(
+                        ?name,
+                        lost_events = ?rebuilt.lost,
+                        "Degraded read: rebuilt object from parity, repair to recreate lost events"
+                    );
+                }
+                let zipped = zip::zip(rebuilt.data);
+                if checksum::digest(&zipped) != erasure.checksum {
+                    return Err(CalStoreError::Corrupt {
+                        event_id: tail_id.clone(),
+                        reason: String::from("object checksum mismatch after rebuilding"),
+                    });
+                }
+                zipped
+            }
+        };
         let decoded: T = encoding::decode(&zipped)?;
         debug!(?name, "Base64-decoded data back into original item");
         Ok(decoded)
     }
 
-    async fn update<T: Serialize>(
+    async fn update<T: Serialize + Sync>(
         &self,
         old: Self::Entry,
         new: &T,
     ) -> Result<Self::Entry, Self::Error> {
-        let new = self.store(&new, old.name).await?;
+        let new = self.store(&new, old.name.clone()).await?;
+        // The new object is complete, so nothing needs the old one anymore
+        self.delete(old).await?;
         Ok(new)
     }
 
+    /// Deletes every event of an item. Events that can't be deleted are only logged, as
+    /// `sweep` will find them later.
     async fn delete(&self, item: Self::Entry) -> Result<(), Self::Error> {
-        todo!()
+        let mut ids: HashSet<String> = item.event_ids.iter().cloned().collect();
+        match self.object_event_ids(&item).await {
+            Ok(found) => ids.extend(found),
+            Err(error) => warn!(
+                name = ?item.name,
+                %error,
+                "Couldn't find every event of object, deleting the ones its entry lists"
+            ),
+        }
+        let deleted = self.delete_events(ids).await;
+        debug!(name = ?item.name, deleted, "Deleted object");
+        Ok(())
+    }
+
+    async fn repair(&self, entry: Self::Entry) -> Result<usize, Self::Error> {
+        let tail_id = &Self::tail_id(&entry)?.clone();
+        let name = entry.name;
+        let manifest_events = self.download(tail_id.clone().into(), name.clone()).await?;
+        if !manifest_events.first().is_some_and(Self::is_manifest) {
+            trace!(?name, "Chained object has no redundancy to repair");
+            return Ok(0);
+        }
+        let mut manifest: Manifest = encoding::decode(&Self::reassemble(&manifest_events)?)?;
+        let Some(mut erasure) = manifest.erasure.take() else {
+            trace!(?name, "Object has no redundancy to repair");
+            return Ok(0);
+        };
+        let rebuilt = self
+            .rebuild(&manifest.chunks, &erasure, &mut HashMap::new(), true)
+            .await?;
+        if rebuilt.lost.is_empty() {
+            return Ok(0);
+        }
+        info!(?name, lost_events = ?rebuilt.lost, "Recreating lost events");
+
+        // Calendarizing every chunk again reproduces the headers of the lost ones
+        let limits = self.client.limits();
+        let data = calendarize::calendarize(
+            rebuilt.data,
+            &name,
+            calendarize::ChunkKind::Data,
+            rebuilt.packing,
+            limits,
+        );
+        let parity = calendarize::calendarize(
+            rebuilt.parity.into_iter().flatten().flatten().collect(),
+            &name,
+            calendarize::ChunkKind::Parity,
+            rebuilt.packing,
+            limits,
+        );
+        let mut ids: Vec<&mut String> = manifest
+            .chunks
+            .iter_mut()
+            .chain(erasure.parity.iter_mut().flatten())
+            .collect();
+        let lost: Vec<usize> = ids
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| rebuilt.lost.contains(id))
+            .map(|(i, _)| i)
+            .collect();
+        let details: Vec<CalendarEventDetails> = data
+            .into_iter()
+            .chain(parity)
+            .enumerate()
+            .filter(|(i, _)| lost.contains(i))
+            .map(|(_, details)| details)
+            .collect();
+        let recreated = self.upload_chunks(details, name.clone()).await?;
+        for (&i, event) in lost.iter().zip(&recreated) {
+            *ids[i] = event.id().to_string();
+        }
+        manifest.erasure = Some(erasure);
+
+        // Rewrite the manifest in place so that references to the object stay valid
+        let calendarized = self.calendarize_manifest(&manifest, &name)?;
+        if calendarized.len() != manifest_events.len() {
+            return Err(CalStoreError::ManifestResized(tail_id.clone()));
+        }
+        let mut prev = name;
+        for (mut details, event) in calendarized.into_iter().zip(&manifest_events) {
+            details.summary = prev;
+            self.client
+                .update_event(&self.calendar, event.id(), details)
+                .await
+                .map_err(CalStoreError::Calendar)?;
+            prev = event.id().to_string();
+        }
+        Ok(recreated.len())
+    }
+
+    async fn sweep(&self, live: Vec<Self::Entry>) -> Result<usize, Self::Error> {
+        let mut reachable = HashSet::new();
+        for entry in &live {
+            // Deleting anything we can't account for would risk live data, so give up instead
+            reachable.extend(self.object_event_ids(entry).await?);
+        }
+        let events = self
+            .client
+            .list_events(&self.calendar)
+            .await
+            .map_err(CalStoreError::Calendar)?;
+        let unreachable: Vec<String> = events
+            .iter()
+            .map(|event| event.id().to_string())
+            .filter(|id| !reachable.contains(id))
+            .collect();
+        info!(
+            number_of_events = events.len(),
+            number_of_reachable_events = reachable.len(),
+            number_of_unreachable_events = unreachable.len(),
+            "Sweeping unreachable events"
+        );
+        Ok(self.delete_events(unreachable).await)
+    }
+
+    /// Fixed items are stored as a chain whose tail is updated in place, so the tail alone
+    /// identifies them. Overwriting uploads a new chain up to the tail first, so a crash
+    /// leaves either the old or the new item.
+    async fn store_fixed<T: Serialize + Sync>(
+        &self,
+        item: &T,
+        name: String,
+    ) -> Result<Self::Entry, Self::Error> {
+        let events = self
+            .upload(self.calendarize_fixed(item, &name)?, name.clone())
+            .await?;
+        let tail_id = events.last().unwrap().id().to_string();
+        debug!(%name, %tail_id, number_of_events = events.len(), "Stored fixed item");
+        Ok(Self::Entry {
+            name,
+            event_ids: vec![tail_id],
+        })
+    }
+
+    async fn overwrite<T: Serialize + Sync>(
+        &self,
+        entry: &Self::Entry,
+        item: &T,
+    ) -> Result<(), Self::Error> {
+        let tail_id = Self::tail_id(entry)?;
+        let superseded: Vec<String> = self
+            .download(tail_id.clone().into(), entry.name.clone())
+            .await?
+            .iter()
+            .map(|event| event.id().to_string())
+            .filter(|id| id != tail_id)
+            .collect();
+        let mut details = self.calendarize_fixed(item, &entry.name)?;
+        let mut tail = details.pop().unwrap();
+        let events = self.upload(details, entry.name.clone()).await?;
+        tail.summary = events
+            .last()
+            .map_or_else(|| entry.name.clone(), |event| event.id().to_string());
+        self.client
+            .update_event(&self.calendar, &tail_id.clone().into(), tail)
+            .await
+            .map_err(CalStoreError::Calendar)?;
+        self.delete_events(superseded).await;
+        trace!(name = %entry.name, %tail_id, "Overwrote fixed item");
+        Ok(())
+    }
+
+    async fn find_fixed(&self, name: &str) -> Result<Vec<Self::Entry>, Self::Error> {
+        let found = self
+            .client
+            .search_events(&self.calendar, FIXED_PROPERTY, name)
+            .await
+            .map_err(CalStoreError::Calendar)?;
+        debug!(%name, number_of_events = found.len(), "Searched for fixed item");
+        Ok(found
+            .iter()
+            .map(|event| Self::Entry {
+                name: name.to_string(),
+                event_ids: vec![event.id().to_string()],
+            })
+            .collect())
     }
 
     fn get_raw_id(&self, entry: &Self::Entry) -> RecoveryDetails {
-        let first = entry.events.last().unwrap();
-        let root_id = first.id().to_string();
+        // An entry without events can't be read anyway, so there's nothing to recover
+        let root_id = entry.event_ids.last().cloned().unwrap_or_default();
         let cal_id = self.calendar.id().to_string();
         RecoveryDetails { cal_id, root_id }
     }
+
+    fn layout(&self) -> Layout {
+        let capacity = calendarize::capacity(self.config.packing, self.client.limits());
+        Layout {
+            encoding: Encoding::JsonBase64,
+            compression: Compression::None,
+            encryption: Encryption::None,
+            packing: self.config.packing,
+            erasure: self.config.erasure,
+            chunk_size: match self.config.erasure {
+                Some(_) => erasure::shard_size(capacity),
+                None => capacity,
+            },
+        }
+    }
+
+    fn set_layout(&mut self, layout: &Layout) {
+        self.config.packing = layout.packing;
+        self.config.erasure = layout.erasure;
+    }
 }
 
 pub struct RecoveryDetails {
@@ -138,9 +639,35 @@ pub struct RecoveryDetails {
 
 impl<TCalendarClient: CalendarClient> CalStore<TCalendarClient> {
     pub fn new(client: TCalendarClient, calendar: TCalendarClient::Calendar) -> Self {
-        Self { client, calendar }
+        Self::with_config(client, calendar, CalStoreConfig::default())
+    }
+
+    pub fn with_config(
+        client: TCalendarClient,
+        calendar: TCalendarClient::Calendar,
+        config: CalStoreConfig,
+    ) -> Self {
+        Self {
+            client,
+            calendar,
+            config,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn client(&self) -> &TCalendarClient {
+        &self.client
+    }
+
+    /// The event identifying an entry's object: the tail of its manifest or chain.
+    fn tail_id(entry: &CalStoreEntry) -> Result<&String, CalStoreError<TCalendarClient>> {
+        entry
+            .event_ids
+            .last()
+            .ok_or_else(|| CalStoreError::EmptyEntry(entry.name.clone()))
     }
 
+    /// Uploads events as a chain, with each event's summary pointing at the previous event.
     async fn upload(
         &self,
         details: Vec<CalendarEventDetails>,
@@ -161,6 +688,134 @@ impl<TCalendarClient: CalendarClient> CalStore<TCalendarClient> {
         Ok(events)
     }
 
+    /// Uploads independent chunk events, up to `parallelism` at a time, in the order given.
+    ///
+    /// If any upload fails, no further ones are started and the events that were created are
+    /// deleted again, so that a failed store leaves nothing behind.
+    async fn upload_chunks(
+        &self,
+        details: Vec<CalendarEventDetails>,
+        name: String,
+    ) -> Result<Vec<TCalendarClient::Event>, CalStoreError<TCalendarClient>> {
+        let failed = AtomicBool::new(false);
+        let number_of_events = details.len();
+        let results: Vec<_> = stream::iter(details.into_iter().enumerate())
+            .map(|(i, details)| {
+                let failed = &failed;
+                async move {
+                    if failed.load(Ordering::SeqCst) {
+                        return (i, None);
+                    }
+                    let created = self.client.create_event(&self.calendar, details).await;
+                    if created.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    (i, Some(created))
+                }
+            })
+            .buffer_unordered(self.config.parallelism.max(1))
+            .collect()
+            .await;
+
+        let mut events = Vec::with_capacity(number_of_events);
+        let mut error = None;
+        for (i, created) in results {
+            match created {
+                Some(Ok(event)) => events.push((i, event)),
+                Some(Err(e)) => error = error.or(Some(e)),
+                None => {}
+            }
+        }
+        if let Some(error) = error {
+            let created = events.len();
+            let deleted = self
+                .delete_events(events.into_iter().map(|(_, event)| event.id().to_string()))
+                .await;
+            warn!(%name, %error, created, deleted, "Upload failed, deleted the chunks already uploaded");
+            return Err(CalStoreError::Calendar(error));
+        }
+        events.sort_by_key(|(i, _)| *i);
+        trace!(%name, number_of_events, "Uploaded chunks");
+        Ok(events.into_iter().map(|(_, event)| event).collect())
+    }
+
+    fn header(event: &TCalendarClient::Event) -> Option<calendarize::ChunkHeader> {
+        event.details().location.parse().ok()
+    }
+
+    fn is_manifest(event: &TCalendarClient::Event) -> bool {
+        Self::header(event).is_some_and(|header| header.kind == calendarize::ChunkKind::Manifest)
+    }
+
+    /// Verifies and joins chunk events back into the encoded object they were split from.
+    fn reassemble(
+        events: &[TCalendarClient::Event],
+    ) -> Result<String, CalStoreError<TCalendarClient>> {
+        let object_checksum = Self::verify_chunks(events)?;
+        let details = events
+            .iter()
+            .map(|event| event.details().clone())
+            .collect::<Vec<_>>();
+        let uncalendarized = calendarize::uncalendarize(details);
+        trace!(
+            number_of_chunks = uncalendarized.len(),
+            "Condensed calendar event details into workable data chunks"
+        );
+        let zipped = zip::zip(uncalendarized);
+        if let Some(expected) = object_checksum {
+            if checksum::digest(&zipped) != expected {
+                return Err(CalStoreError::Corrupt {
+                    event_id: events.last().unwrap().id().to_string(),
+                    reason: String::from("object checksum mismatch"),
+                });
+            }
+            trace!("Verified object checksum");
+        }
+        Ok(zipped)
+    }
+
+    /// Checks every chunk against the checksum in its header and returns the
+    /// checksum of the whole object, if the events carry one.
+    fn verify_chunks(
+        events: &[TCalendarClient::Event],
+    ) -> Result<Option<String>, CalStoreError<TCalendarClient>> {
+        let mut object_checksum = None;
+        for (i, event) in events.iter().enumerate() {
+            let corrupt = |reason: String| CalStoreError::Corrupt {
+                event_id: event.id().to_string(),
+                reason,
+            };
+            let details = event.details();
+            let header: calendarize::ChunkHeader = details
+                .location
+                .parse()
+                .map_err(|_| corrupt(format!("unreadable chunk header {:?}", details.location)))?;
+            if header.index != i {
+                return Err(corrupt(format!(
+                    "expected chunk {i} but found chunk {}",
+                    header.index
+                )));
+            }
+            if let Some(expected) = &header.checksum {
+                if checksum::digest(&calendarize::payload(details, &header)) != *expected {
+                    return Err(corrupt(String::from("chunk checksum mismatch")));
+                }
+            }
+            match (&object_checksum, header.object_checksum) {
+                (None, found) => object_checksum = found,
+                (Some(expected), Some(found)) if *expected != found => {
+                    return Err(corrupt(String::from(
+                        "object checksum disagrees with chunk 0",
+                    )));
+                }
+                _ => (),
+            }
+        }
+        trace!(number_of_chunks = events.len(), "Verified chunk checksums");
+        Ok(object_checksum)
+    }
+
+    /// Walks a chain of events backwards from its tail until the sentinel is reached.
     async fn download(
         &self,
         tail_event_id: <TCalendarClient::Event as Event>::Id,
@@ -185,47 +840,540 @@ impl<TCalendarClient: CalendarClient> CalStore<TCalendarClient> {
         }
         Ok(events.into())
     }
+
+    /// IDs of every event an object currently consists of, found by walking its chain from
+    /// the tail and reading its manifest, if it has one.
+    async fn object_event_ids(
+        &self,
+        entry: &CalStoreEntry,
+    ) -> Result<HashSet<String>, CalStoreError<TCalendarClient>> {
+        let tail_id = Self::tail_id(entry)?;
+        let chain = self
+            .download(tail_id.clone().into(), entry.name.clone())
+            .await?;
+        let mut ids: HashSet<String> = chain.iter().map(|event| event.id().to_string()).collect();
+        if chain.first().is_some_and(Self::is_manifest) {
+            let manifest: Manifest = encoding::decode(&Self::reassemble(&chain)?)?;
+            ids.extend(manifest.chunks);
+            if let Some(erasure) = manifest.erasure {
+                ids.extend(erasure.parity.into_iter().flatten());
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Deletes events by ID, up to `parallelism` at a time, returning how many were deleted.
+    async fn delete_events(&self, ids: impl IntoIterator<Item = String>) -> usize {
+        stream::iter(ids)
+            .map(|id| async move {
+                trace!(%id, "Deleting event");
+                let deleted = self
+                    .client
+                    .delete_event(&self.calendar, &id.clone().into())
+                    .await;
+                if let Err(error) = &deleted {
+                    warn!(%id, %error, "Failed to delete event");
+                }
+                deleted.is_ok()
+            })
+            .buffer_unordered(self.config.parallelism.max(1))
+            .filter(|deleted| std::future::ready(*deleted))
+            .count()
+            .await
+    }
+
+    /// Fetches events by ID, up to `parallelism` at a time, keeping failures per event.
+    async fn fetch_events(&self, ids: Vec<String>) -> Fetched<TCalendarClient> {
+        stream::iter(ids)
+            .map(|id| async move {
+                trace!(%id, "Downloading chunk");
+                let event = self
+                    .client
+                    .get_event_by_id(&self.calendar, &id.clone().into())
+                    .await
+                    .map_err(CalStoreError::Calendar);
+                (id, event)
+            })
+            .buffer_unordered(self.config.parallelism.max(1))
+            .collect()
+            .await
+    }
+
+    /// Takes the given events out of `fetched` in order, fetching any that are missing.
+    async fn take_fetched(
+        &self,
+        ids: &[String],
+        fetched: &mut Fetched<TCalendarClient>,
+    ) -> Vec<Result<TCalendarClient::Event, CalStoreError<TCalendarClient>>> {
+        let missing = ids
+            .iter()
+            .filter(|id| !fetched.contains_key(*id))
+            .cloned()
+            .collect();
+        fetched.extend(self.fetch_events(missing).await);
+        ids.iter().map(|id| fetched.remove(id).unwrap()).collect()
+    }
+
+    async fn collect_chunks(
+        &self,
+        ids: &[String],
+        fetched: &mut Fetched<TCalendarClient>,
+    ) -> Result<Vec<TCalendarClient::Event>, CalStoreError<TCalendarClient>> {
+        self.take_fetched(ids, fetched).await.into_iter().collect()
+    }
+
+    /// Reads the chunks of an erasure-coded object, rebuilding lost or corrupt chunks from
+    /// parity. Parity is only fetched for stripes that need it, unless `all_parity` is set.
+    async fn rebuild(
+        &self,
+        chunks: &[String],
+        erasure: &ErasureManifest,
+        fetched: &mut Fetched<TCalendarClient>,
+        all_parity: bool,
+    ) -> Result<Rebuilt, CalStoreError<TCalendarClient>> {
+        let mut lost = Vec::new();
+        let mut packing = self.config.packing;
+        let mut data: Vec<Option<String>> = Vec::with_capacity(chunks.len());
+        for (i, (id, event)) in chunks
+            .iter()
+            .zip(self.take_fetched(chunks, fetched).await)
+            .enumerate()
+        {
+            match Self::verified_payload(&event, i, calendarize::ChunkKind::Data) {
+                Ok((payload, header)) => {
+                    packing = header.packing;
+                    data.push(Some(payload));
+                }
+                Err(reason) => {
+                    warn!(%id, %reason, "Data chunk unavailable");
+                    lost.push(id.clone());
+                    data.push(None);
+                }
+            }
+        }
+
+        let lengths = erasure::chunk_lengths(erasure.length, erasure.shard_size);
+        let mut parity: Vec<Vec<Option<String>>> = Vec::with_capacity(erasure.parity.len());
+        let mut parity_index = 0;
+        for (i, (stripe, ids)) in erasure::stripes(chunks.len(), erasure.erasure)
+            .zip(&erasure.parity)
+            .enumerate()
+        {
+            let mut stripe_parity = vec![None; ids.len()];
+            let first_parity_index = parity_index;
+            parity_index += ids.len();
+            if !all_parity && data[stripe.clone()].iter().all(Option::is_some) {
+                parity.push(stripe_parity);
+                continue;
+            }
+            for (j, (id, event)) in ids
+                .iter()
+                .zip(self.take_fetched(ids, fetched).await)
+                .enumerate()
+            {
+                let index = first_parity_index + j;
+                match Self::verified_payload(&event, index, calendarize::ChunkKind::Parity) {
+                    Ok((payload, _)) => stripe_parity[j] = Some(payload),
+                    Err(reason) => {
+                        warn!(%id, %reason, "Parity chunk unavailable");
+                        lost.push(id.clone());
+                    }
+                }
+            }
+            let incomplete = data[stripe.clone()]
+                .iter()
+                .chain(&stripe_parity)
+                .any(Option::is_none);
+            if incomplete {
+                erasure::reconstruct(
+                    i,
+                    &mut data[stripe.clone()],
+                    &mut stripe_parity,
+                    &lengths[stripe],
+                    erasure.shard_size,
+                )?;
+            }
+            parity.push(stripe_parity);
+        }
+        Ok(Rebuilt {
+            data: data.into_iter().map(Option::unwrap).collect(),
+            parity,
+            packing,
+            lost,
+        })
+    }
+
+    /// Payload of a single chunk, if it is the chunk we expect and its checksum holds up
+    fn verified_payload(
+        event: &Result<TCalendarClient::Event, CalStoreError<TCalendarClient>>,
+        index: usize,
+        kind: calendarize::ChunkKind,
+    ) -> Result<(String, calendarize::ChunkHeader), String> {
+        let event = event.as_ref().map_err(ToString::to_string)?;
+        let header = Self::header(event).ok_or("unreadable chunk header")?;
+        if header.index != index || header.kind != kind {
+            return Err(format!(
+                "expected {kind:?} chunk {index} but found {:?} chunk {}",
+                header.kind, header.index
+            ));
+        }
+        let payload = calendarize::payload(event.details(), &header);
+        match &header.checksum {
+            Some(expected) if checksum::digest(&payload) != *expected => {
+                Err(String::from("chunk checksum mismatch"))
+            }
+            _ => Ok((payload, header)),
+        }
+    }
+
+    /// Fixed items are read back as chains, so they keep the description-only layout that
+    /// leaves the summary free for linking events. The tail is marked so `find_fixed` can
+    /// search for it.
+    fn calendarize_fixed<T: Serialize>(
+        &self,
+        item: &T,
+        name: &str,
+    ) -> Result<Vec<CalendarEventDetails>, CalStoreError<TCalendarClient>> {
+        let limits = self.client.limits();
+        let encoded = encoding::encode(item)?;
+        let mut calendarized = calendarize::calendarize(
+            zip::split(&encoded, limits.description),
+            name,
+            calendarize::ChunkKind::Data,
+            Packing::Description,
+            limits,
+        );
+        if let Some(tail) = calendarized.last_mut() {
+            tail.extended_properties
+                .insert(FIXED_PROPERTY.to_string(), name.to_string());
+        }
+        Ok(calendarized)
+    }
+
+    fn calendarize_manifest(
+        &self,
+        manifest: &Manifest,
+        name: &str,
+    ) -> Result<Vec<CalendarEventDetails>, CalStoreError<TCalendarClient>> {
+        let limits = self.client.limits();
+        let encoded = encoding::encode(manifest)?;
+        // Manifest events keep the description-only layout, as their summary links the chain
+        let split = zip::split(&encoded, limits.description);
+        Ok(calendarize::calendarize(
+            split,
+            name,
+            calendarize::ChunkKind::Manifest,
+            Packing::Description,
+            limits,
+        ))
+    }
 }
 
 mod calendarize {
-    use crate::calendar::CalendarEventDetails;
+    use super::{checksum, Packing};
+    use crate::calendar::{CalendarEventDetails, CalendarLimits};
     use chrono::{Duration, Utc};
-    pub fn calendarize(data: Vec<String>) -> Vec<CalendarEventDetails> {
+    use std::{fmt::Display, str::FromStr};
+
+    /// Room left in the location field for the chunk header when packing payload after it
+    const HEADER_RESERVE: usize = 192;
+    const LOCATION_PAYLOAD_PREFIX: &str = " p=";
+    const EXTENDED_PROPERTY_PREFIX: char = 'p';
+
+    /// Metadata written to an event's location field, e.g. `3 c=<hex> o=<hex>`.
+    ///
+    /// Older filesystems only wrote the chunk index, so every other field is optional.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ChunkHeader {
+        pub index: usize,
+        pub kind: ChunkKind,
+        pub packing: Packing,
+        pub checksum: Option<String>,
+        pub object_checksum: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ChunkKind {
+        /// Part of a stored object
+        Data,
+        /// Part of a manifest listing an object's data chunks
+        Manifest,
+        /// Erasure coding parity for a stripe of data chunks
+        Parity,
+    }
+
+    impl Display for ChunkHeader {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.index)?;
+            match self.kind {
+                ChunkKind::Data => (),
+                ChunkKind::Manifest => write!(f, " k=m")?,
+                ChunkKind::Parity => write!(f, " k=p")?,
+            }
+            if self.packing == Packing::AllFields {
+                write!(f, " f=a")?;
+            }
+            if let Some(checksum) = &self.checksum {
+                write!(f, " c={checksum}")?;
+            }
+            if let Some(object_checksum) = &self.object_checksum {
+                write!(f, " o={object_checksum}")?;
+            }
+            Ok(())
+        }
+    }
+
+    impl FromStr for ChunkHeader {
+        type Err = ();
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let mut tokens = s.split_whitespace();
+            let index = tokens.next().ok_or(())?.parse().map_err(|_| ())?;
+            let mut header = ChunkHeader {
+                index,
+                kind: ChunkKind::Data,
+                packing: Packing::Description,
+                checksum: None,
+                object_checksum: None,
+            };
+            for token in tokens {
+                match token.split_once('=') {
+                    Some(("k", "m")) => header.kind = ChunkKind::Manifest,
+                    Some(("k", "p")) => header.kind = ChunkKind::Parity,
+                    Some(("f", "a")) => header.packing = Packing::AllFields,
+                    Some(("c", value)) => header.checksum = Some(value.to_string()),
+                    Some(("o", value)) => header.object_checksum = Some(value.to_string()),
+                    // Unknown fields are left for newer versions of WhenFS
+                    _ => (),
+                }
+            }
+            Ok(header)
+        }
+    }
+
+    /// Number of payload bytes a single event can hold with the given packing strategy
+    pub fn capacity(packing: Packing, limits: &CalendarLimits) -> usize {
+        match packing {
+            Packing::Description => limits.description,
+            Packing::AllFields => {
+                limits.description
+                    + limits.location.saturating_sub(HEADER_RESERVE)
+                    + limits.summary
+                    + limits.extended_property * limits.extended_properties
+            }
+        }
+    }
+
+    /// Turns chunks of at most `capacity(packing, limits)` bytes into event details.
+    ///
+    /// Events that don't use their summary for payload get `label` as their summary instead.
+    pub fn calendarize(
+        data: Vec<String>,
+        label: &str,
+        kind: ChunkKind,
+        packing: Packing,
+        limits: &CalendarLimits,
+    ) -> Vec<CalendarEventDetails> {
         let now = Utc::now();
+        let object_checksum = checksum::digest_all(&data);
         data.into_iter()
             .enumerate()
-            .map(|(i, datum)| CalendarEventDetails {
-                summary: String::new(),
-                description: datum,
-                location: i.to_string(),
-                start: now + Duration::minutes(i as i64 * 5),
-                end: now + Duration::minutes(i as i64 * 5 + 5),
+            .map(|(i, datum)| {
+                let header = ChunkHeader {
+                    index: i,
+                    kind,
+                    packing,
+                    checksum: Some(checksum::digest(&datum)),
+                    object_checksum: Some(object_checksum.clone()),
+                };
+                let mut details = CalendarEventDetails {
+                    summary: label.to_string(),
+                    location: header.to_string(),
+                    description: String::new(),
+                    start: now + Duration::minutes(i as i64 * 5),
+                    end: now + Duration::minutes(i as i64 * 5 + 5),
+                    extended_properties: Default::default(),
+                };
+                match packing {
+                    Packing::Description => details.description = datum,
+                    Packing::AllFields => pack(&mut details, &datum, limits),
+                }
+                details
             })
             .collect()
     }
 
+    /// Spreads a chunk over description, location, summary and extended properties, in that order
+    fn pack(details: &mut CalendarEventDetails, datum: &str, limits: &CalendarLimits) {
+        // Payload is base64, so any byte offset is a char boundary
+        fn take<'a>(rest: &mut &'a str, len: usize) -> &'a str {
+            let (taken, remaining) = rest.split_at(len.min(rest.len()));
+            *rest = remaining;
+            taken
+        }
+
+        let mut rest = datum;
+        details.description = take(&mut rest, limits.description).to_string();
+        let room = limits
+            .location
+            .saturating_sub(details.location.len() + LOCATION_PAYLOAD_PREFIX.len());
+        let location_payload = take(&mut rest, room);
+        if !location_payload.is_empty() {
+            details.location = format!(
+                "{}{LOCATION_PAYLOAD_PREFIX}{location_payload}",
+                details.location
+            );
+        }
+        details.summary = take(&mut rest, limits.summary).to_string();
+        for i in 0..limits.extended_properties {
+            if rest.is_empty() {
+                break;
+            }
+            let value = take(&mut rest, limits.extended_property);
+            details.extended_properties.insert(
+                format!("{EXTENDED_PROPERTY_PREFIX}{i:02}"),
+                value.to_string(),
+            );
+        }
+        assert!(rest.is_empty(), "chunk larger than event capacity");
+    }
+
+    /// Reads the chunk payload back out of an event's fields
+    pub fn payload(details: &CalendarEventDetails, header: &ChunkHeader) -> String {
+        match header.packing {
+            Packing::Description => details.description.clone(),
+            Packing::AllFields => {
+                let mut payload = details.description.clone();
+                if let Some((_, location_payload)) =
+                    details.location.split_once(LOCATION_PAYLOAD_PREFIX)
+                {
+                    payload.push_str(location_payload);
+                }
+                payload.push_str(&details.summary);
+                details
+                    .extended_properties
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(EXTENDED_PROPERTY_PREFIX))
+                    .for_each(|(_, value)| payload.push_str(value));
+                payload
+            }
+        }
+    }
+
     pub fn uncalendarize(events: Vec<CalendarEventDetails>) -> Vec<String> {
         events
             .into_iter()
-            .map(|details| details.description)
+            .map(|details| match details.location.parse::<ChunkHeader>() {
+                Ok(header) => payload(&details, &header),
+                Err(()) => details.description,
+            })
             .collect()
     }
 
     #[cfg(test)]
     pub mod tests {
+        use super::{ChunkKind, Packing};
+        use crate::calendar::CalendarLimits;
+
+        static LIMITS: CalendarLimits = CalendarLimits {
+            summary: 16,
+            description: 32,
+            location: 256,
+            extended_property: 8,
+            extended_properties: 3,
+        };
+
         #[test]
         fn test_calendarize() {
             let source = vec![
                 "The", "quick", "brown", "fox", "jumped", "over", "the", "lazy", "dog",
             ];
             let data: Vec<String> = source.iter().map(ToString::to_string).collect();
-            let calendarized = super::calendarize(data);
+            let calendarized = super::calendarize(
+                data,
+                "label",
+                ChunkKind::Data,
+                Packing::Description,
+                &LIMITS,
+            );
             let uncalendarized = super::uncalendarize(calendarized);
-            let _ = uncalendarized
+            uncalendarized
                 .into_iter()
                 .zip(source)
                 .for_each(|(expected, actual)| assert_eq!(expected, actual));
         }
+
+        #[test]
+        fn test_calendarize_all_fields() {
+            let capacity = super::capacity(Packing::AllFields, &LIMITS);
+            assert_eq!(capacity, 32 + 64 + 16 + 24);
+            let full: String = ('a'..='z').cycle().take(capacity).collect();
+            let data = vec![full, "short".to_string()];
+            let calendarized = super::calendarize(
+                data.clone(),
+                "label",
+                ChunkKind::Data,
+                Packing::AllFields,
+                &LIMITS,
+            );
+            let packed = &calendarized[0];
+            assert!(packed.description.len() <= LIMITS.description);
+            assert!(packed.location.len() <= LIMITS.location);
+            assert!(packed.summary.len() <= LIMITS.summary);
+            assert!(packed.extended_properties.len() <= LIMITS.extended_properties);
+            assert_eq!(calendarized[1].description, "short");
+            assert_eq!(super::uncalendarize(calendarized), data);
+        }
+
+        #[test]
+        fn test_chunk_header() {
+            let header = super::ChunkHeader {
+                index: 7,
+                kind: ChunkKind::Manifest,
+                packing: Packing::AllFields,
+                checksum: Some("abc".into()),
+                object_checksum: Some("def".into()),
+            };
+            assert_eq!(header, header.to_string().parse().unwrap());
+
+            let legacy: super::ChunkHeader = "3".parse().unwrap();
+            assert_eq!(legacy.index, 3);
+            assert_eq!(legacy.kind, ChunkKind::Data);
+            assert_eq!(legacy.packing, Packing::Description);
+            assert_eq!(legacy.checksum, None);
+            assert!("not a header".parse::<super::ChunkHeader>().is_err());
+        }
+    }
+}
+
+mod checksum {
+    pub fn digest(data: &str) -> String {
+        blake3::hash(data.as_bytes()).to_hex().to_string()
+    }
+
+    /// Digest of the given chunks as if they had been zipped back together.
+    pub fn digest_all(chunks: &[String]) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for chunk in chunks {
+            hasher.update(chunk.as_bytes());
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        #[test]
+        fn test_digest_all() {
+            let chunks = vec!["The quick ".to_string(), "brown fox".to_string()];
+            assert_eq!(
+                super::digest_all(&chunks),
+                super::digest("The quick brown fox")
+            );
+            assert_ne!(
+                super::digest_all(&chunks),
+                super::digest("The quick brown fix")
+            );
+        }
     }
 }
 
@@ -276,7 +1424,7 @@ mod encoding {
         Ok(b64)
     }
 
-    pub fn decode<'de, T: DeserializeOwned>(b64: &str) -> Result<T, EncodingError> {
+    pub fn decode<T: DeserializeOwned>(b64: &str) -> Result<T, EncodingError> {
         let json = base64::engine::general_purpose::URL_SAFE.decode(b64)?;
         let data: T = serde_json::from_slice(&json).map_err(EncodingError::JsonDecode)?;
         Ok(data)
@@ -317,12 +1465,321 @@ mod encoding {
 #[cfg(test)]
 mod tests {
     use crate::{
-        calendar::{gcal::GCalClient, CalendarClient},
-        store::{CalStore, Store},
+        calendar::{gcal::GCalClient, memory::MemoryClient, CalendarClient},
+        store::{CalStore, CalStoreConfig, CalStoreError, Packing, Store},
     };
     use serde::{Deserialize, Serialize};
     use tracing::info;
 
+    async fn memory_store() -> CalStore<MemoryClient> {
+        let client = MemoryClient::default();
+        let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+        CalStore::new(client, calendar)
+    }
+
+    fn lorem(len: usize) -> String {
+        "Lorem ipsum dolor sit amet "
+            .chars()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_manifest_round_trip() {
+        let store = memory_store().await;
+        let item = lorem(1000);
+        let entry = store.store(&item, "lorem.txt".into()).await.unwrap();
+        let retrieved: String = store.retrieve(entry).await.unwrap();
+        assert_eq!(item, retrieved);
+    }
+
+    #[tokio::test]
+    async fn test_all_fields_packing_uses_fewer_events() {
+        let item = lorem(4000);
+        let mut events_used = Vec::new();
+        for packing in [Packing::Description, Packing::AllFields] {
+            let client = MemoryClient::default();
+            let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+            let config = CalStoreConfig {
+                packing,
+                ..Default::default()
+            };
+            let store = CalStore::with_config(client, calendar, config);
+            let entry = store.store(&item, "lorem.txt".into()).await.unwrap();
+            events_used.push(entry.event_ids.len());
+            let retrieved: String = store.retrieve(entry).await.unwrap();
+            assert_eq!(item, retrieved);
+        }
+        assert!(events_used[1] < events_used[0]);
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_from_tail_event() {
+        let store = memory_store().await;
+        let item = lorem(1000);
+        let mut entry = store.store(&item, "lorem.txt".into()).await.unwrap();
+        // Recovering a filesystem only gives us the tail of the manifest chain
+        entry.event_ids.drain(..entry.event_ids.len() - 1);
+        let retrieved: String = store.retrieve(entry).await.unwrap();
+        assert_eq!(item, retrieved);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_chain_round_trip() {
+        let store = memory_store().await;
+        let item = lorem(500);
+        let encoded = super::encoding::encode(&item).unwrap();
+        let mut details = super::calendarize::calendarize(
+            super::zip::split(&encoded, 128),
+            "legacy.txt",
+            super::calendarize::ChunkKind::Data,
+            Packing::Description,
+            store.client.limits(),
+        );
+        for (i, detail) in details.iter_mut().enumerate() {
+            detail.location = i.to_string();
+        }
+        let events = store.upload(details, "legacy.txt".into()).await.unwrap();
+        let entry = super::CalStoreEntry {
+            name: "legacy.txt".into(),
+            event_ids: events.into_iter().map(|event| event.id).collect(),
+        };
+        let retrieved: String = store.retrieve(entry).await.unwrap();
+        assert_eq!(item, retrieved);
+    }
+
+    #[test]
+    fn test_entry_with_full_events_is_read_as_ids() {
+        let legacy = r#"{"name":"lorem.txt","events":[
+            {"id":"event0","details":{"summary":"lorem.txt","description":"","location":"0",
+                "start":"2024-01-01T00:00:00Z","end":"2024-01-01T00:05:00Z"}},
+            {"id":"event1","details":{"summary":"event0","description":"","location":"1",
+                "start":"2024-01-01T00:05:00Z","end":"2024-01-01T00:10:00Z"}}
+        ]}"#;
+        let entry: super::CalStoreEntry = serde_json::from_str(legacy).unwrap();
+        assert_eq!(entry.event_ids, vec!["event0", "event1"]);
+        let slim = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            slim,
+            r#"{"name":"lorem.txt","event_ids":["event0","event1"]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<super::CalStoreEntry>(&slim).unwrap(),
+            entry
+        );
+    }
+
+    #[tokio::test]
+    async fn test_entry_without_events_is_reported() {
+        let store = memory_store().await;
+        let entry: super::CalStoreEntry = serde_json::from_str(r#"{"name":"lorem.txt"}"#).unwrap();
+        match store.retrieve::<String>(entry.clone()).await {
+            Err(CalStoreError::EmptyEntry(name)) => assert_eq!(name, "lorem.txt"),
+            other => panic!("expected an empty entry error, got {other:?}"),
+        }
+        assert!(matches!(
+            store.repair(entry.clone()).await,
+            Err(CalStoreError::EmptyEntry(_))
+        ));
+        assert!(matches!(
+            store.overwrite(&entry, &lorem(10)).await,
+            Err(CalStoreError::EmptyEntry(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_chunk_is_reported() {
+        let store = memory_store().await;
+        let entry = store.store(&lorem(1000), "lorem.txt".into()).await.unwrap();
+        let victim = entry.event_ids[1].clone();
+        store.client.tamper(&victim, |details| {
+            details.description.replace_range(0..4, "AAAA")
+        });
+        match store.retrieve::<String>(entry).await {
+            Err(CalStoreError::Corrupt { event_id, .. }) => assert_eq!(event_id, victim),
+            other => panic!("expected corruption to be detected, got {other:?}"),
+        }
+    }
+
+    async fn erasure_store() -> CalStore<MemoryClient> {
+        let client = MemoryClient::default();
+        let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+        let config = CalStoreConfig {
+            erasure: Some("4+2".parse().unwrap()),
+            ..Default::default()
+        };
+        CalStore::with_config(client, calendar, config)
+    }
+
+    #[tokio::test]
+    async fn test_degraded_read() {
+        let store = erasure_store().await;
+        let item = lorem(1000);
+        let entry = store.store(&item, "lorem.txt".into()).await.unwrap();
+        // Lose two data chunks of the first stripe and corrupt one of the second
+        for id in &entry.event_ids[..2] {
+            store
+                .client
+                .delete_event(&store.calendar, id)
+                .await
+                .unwrap();
+        }
+        store.client.tamper(&entry.event_ids[5], |details| {
+            details.description.replace_range(0..4, "AAAA")
+        });
+        let retrieved: String = store.retrieve(entry.clone()).await.unwrap();
+        assert_eq!(item, retrieved);
+
+        // Only the tail is known when recovering, so the chunks are found via the manifest
+        let tail = super::CalStoreEntry {
+            name: entry.name,
+            event_ids: vec![entry.event_ids.last().unwrap().clone()],
+        };
+        let retrieved: String = store.retrieve(tail).await.unwrap();
+        assert_eq!(item, retrieved);
+    }
+
+    #[tokio::test]
+    async fn test_intact_read_skips_parity() {
+        let store = erasure_store().await;
+        let item = lorem(1000);
+        let entry = store.store(&item, "lorem.txt".into()).await.unwrap();
+        let parity_ids: Vec<_> = store
+            .client
+            .list_events(&store.calendar)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|event| {
+                CalStore::<MemoryClient>::header(event)
+                    .is_some_and(|header| header.kind == super::calendarize::ChunkKind::Parity)
+            })
+            .collect();
+        assert!(!parity_ids.is_empty());
+        let before = store.client.gets();
+        let retrieved: String = store.retrieve(entry.clone()).await.unwrap();
+        assert_eq!(item, retrieved);
+        assert_eq!(
+            store.client.gets() - before,
+            (entry.event_ids.len() - parity_ids.len()) as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repair_recreates_lost_events() {
+        let store = erasure_store().await;
+        let item = lorem(1000);
+        let entry = store.store(&item, "lorem.txt".into()).await.unwrap();
+        assert_eq!(store.repair(entry.clone()).await.unwrap(), 0);
+
+        // Lose a data chunk from each of the first two stripes
+        let lost: Vec<_> = entry.event_ids.iter().step_by(4).take(2).collect();
+        for id in &lost {
+            store
+                .client
+                .delete_event(&store.calendar, id)
+                .await
+                .unwrap();
+        }
+        assert_eq!(store.repair(entry.clone()).await.unwrap(), lost.len());
+        assert_eq!(store.repair(entry.clone()).await.unwrap(), 0);
+
+        // With the lost chunks back, the object survives losing another two per stripe
+        for id in entry.event_ids.iter().skip(1).step_by(4).take(2) {
+            store
+                .client
+                .delete_event(&store.calendar, id)
+                .await
+                .unwrap();
+        }
+        for id in entry.event_ids.iter().skip(2).step_by(4).take(2) {
+            store
+                .client
+                .delete_event(&store.calendar, id)
+                .await
+                .unwrap();
+        }
+        let retrieved: String = store.retrieve(entry).await.unwrap();
+        assert_eq!(item, retrieved);
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_keeps_fixed_entry() {
+        let store = memory_store().await;
+        let entry = store
+            .store_fixed(&"first", String::from("fixed"))
+            .await
+            .unwrap();
+        assert_eq!(store.client.event_ids().len(), 1);
+
+        // Growing the item chains more events up to the same tail
+        let item = lorem(1000);
+        store.overwrite(&entry, &item).await.unwrap();
+        let read: String = store.retrieve(entry.clone()).await.unwrap();
+        assert_eq!(read, item);
+        let grown = store.client.event_ids().len();
+        assert!(grown > 1);
+
+        store.overwrite(&entry, &"second").await.unwrap();
+        let read: String = store.retrieve(entry.clone()).await.unwrap();
+        assert_eq!(read, "second");
+        assert_eq!(store.client.event_ids(), entry.event_ids);
+        assert_eq!(store.find_fixed("fixed").await.unwrap(), vec![entry]);
+        assert!(store.find_fixed("other").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_deletes_superseded_events() {
+        let store = erasure_store().await;
+        let entry = store.store(&lorem(1000), "lorem.txt".into()).await.unwrap();
+        let entry = store.update(entry, &lorem(500)).await.unwrap();
+        let entry = store.update(entry, &lorem(2000)).await.unwrap();
+        let mut remaining = store.client.event_ids();
+        let mut expected = entry.event_ids.clone();
+        remaining.sort();
+        expected.sort();
+        assert_eq!(remaining, expected);
+        let retrieved: String = store.retrieve(entry).await.unwrap();
+        assert_eq!(lorem(2000), retrieved);
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_deletes_created_events() {
+        let client = MemoryClient::default();
+        let calendar = client.create_calendar("WhenFS".to_string()).await.unwrap();
+        let config = CalStoreConfig {
+            parallelism: 4,
+            ..Default::default()
+        };
+        let store = CalStore::with_config(client, calendar, config);
+        store.client.fail_creates_after(5);
+        let result = store.store(&lorem(5000), "lorem.txt".into()).await;
+        assert!(matches!(result, Err(CalStoreError::Calendar(_))));
+        assert!(store.client.event_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_deletes_unreachable_events() {
+        let store = memory_store().await;
+        let live = store.store(&lorem(1000), "live.txt".into()).await.unwrap();
+        let orphan = store
+            .store(&lorem(1000), "orphan.txt".into())
+            .await
+            .unwrap();
+        // Only the tail is known after recovering a filesystem
+        let tail = super::CalStoreEntry {
+            name: live.name.clone(),
+            event_ids: vec![live.event_ids.last().unwrap().clone()],
+        };
+        let swept = store.sweep(vec![tail.clone()]).await.unwrap();
+        assert_eq!(swept, orphan.event_ids.len());
+        assert_eq!(store.client.event_ids().len(), live.event_ids.len());
+        assert_eq!(store.sweep(vec![tail.clone()]).await.unwrap(), 0);
+        let retrieved: String = store.retrieve(tail).await.unwrap();
+        assert_eq!(lorem(1000), retrieved);
+    }
+
     #[tokio::test]
     async fn test_block_device_end_to_end() {
         #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -0,0 +1,222 @@
+use base64::Engine;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, ops::Range, str::FromStr};
+use thiserror::Error;
+
+/// Number of data and parity chunks per stripe, written as `data+parity`, e.g. `4+2`.
+///
+/// Any `data` chunks out of a stripe are enough to rebuild the whole stripe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Erasure {
+    pub data: usize,
+    pub parity: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum ErasureError {
+    #[error("Reed-Solomon error: {0}")]
+    Codec(#[from] reed_solomon_erasure::Error),
+    #[error("Base64 decoding error: {0}")]
+    Base64Decode(#[from] base64::DecodeError),
+    #[error("Stripe {stripe} has {present} chunks left but needs {needed}")]
+    Unrecoverable {
+        stripe: usize,
+        present: usize,
+        needed: usize,
+    },
+}
+
+/// Erasure coding details recorded in an object's manifest
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErasureManifest {
+    pub erasure: Erasure,
+    /// Size in bytes of every shard; data chunks shorter than this are zero-padded
+    pub shard_size: usize,
+    /// Total length of the encoded object
+    pub length: usize,
+    /// Checksum of the encoded object, as the chunk headers can't be trusted when rebuilding
+    pub checksum: String,
+    /// Parity chunk event IDs for each stripe
+    pub parity: Vec<Vec<String>>,
+}
+
+impl FromStr for Erasure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (data, parity) = s
+            .split_once('+')
+            .ok_or_else(|| format!("expected <data>+<parity>, got {s:?}"))?;
+        let data: usize = data.parse().map_err(|e| format!("{e}"))?;
+        let parity: usize = parity.parse().map_err(|e| format!("{e}"))?;
+        if data == 0 || parity == 0 || data + parity > 256 {
+            return Err(String::from(
+                "need at least one data and one parity chunk, and at most 256 in total",
+            ));
+        }
+        Ok(Self { data, parity })
+    }
+}
+
+impl Display for Erasure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}+{}", self.data, self.parity)
+    }
+}
+
+/// Largest shard whose base64 encoding still fits in `capacity` bytes
+pub fn shard_size(capacity: usize) -> usize {
+    capacity / 4 * 3
+}
+
+/// Chunk index ranges making up each stripe. The last stripe may be short.
+pub fn stripes(chunks: usize, erasure: Erasure) -> impl Iterator<Item = Range<usize>> {
+    (0..chunks)
+        .step_by(erasure.data)
+        .map(move |start| start..(start + erasure.data).min(chunks))
+}
+
+/// Length of every data chunk of an object
+pub fn chunk_lengths(length: usize, shard_size: usize) -> Vec<usize> {
+    (0..length)
+        .step_by(shard_size)
+        .map(|start| shard_size.min(length - start))
+        .collect()
+}
+
+/// Computes base64-encoded parity chunks for each stripe of data chunks
+pub fn encode(
+    chunks: &[String],
+    erasure: Erasure,
+    shard_size: usize,
+) -> Result<Vec<Vec<String>>, ErasureError> {
+    stripes(chunks.len(), erasure)
+        .map(|stripe| {
+            let codec = ReedSolomon::new(stripe.len(), erasure.parity)?;
+            let mut shards: Vec<Vec<u8>> = chunks[stripe]
+                .iter()
+                .map(|chunk| pad(chunk.as_bytes(), shard_size))
+                .chain((0..erasure.parity).map(|_| vec![0; shard_size]))
+                .collect();
+            codec.encode(&mut shards)?;
+            Ok(shards
+                .split_off(codec.data_shard_count())
+                .iter()
+                .map(|shard| base64::engine::general_purpose::URL_SAFE.encode(shard))
+                .collect())
+        })
+        .collect()
+}
+
+/// Fills in the missing chunks of one stripe, given the length of each of its data chunks
+pub fn reconstruct(
+    stripe: usize,
+    data: &mut [Option<String>],
+    parity: &mut [Option<String>],
+    lengths: &[usize],
+    shard_size: usize,
+) -> Result<(), ErasureError> {
+    let present = data.iter().chain(parity.iter()).flatten().count();
+    if present < data.len() {
+        return Err(ErasureError::Unrecoverable {
+            stripe,
+            present,
+            needed: data.len(),
+        });
+    }
+    let codec = ReedSolomon::new(data.len(), parity.len())?;
+    let mut shards: Vec<Option<Vec<u8>>> = data
+        .iter()
+        .map(|chunk| {
+            chunk
+                .as_ref()
+                .map(|chunk| pad(chunk.as_bytes(), shard_size))
+        })
+        .collect();
+    for chunk in parity.iter() {
+        let shard = match chunk {
+            Some(chunk) => Some(base64::engine::general_purpose::URL_SAFE.decode(chunk)?),
+            None => None,
+        };
+        shards.push(shard);
+    }
+    codec.reconstruct(&mut shards)?;
+    let mut shards = shards.into_iter().map(Option::unwrap);
+    for (chunk, &length) in data.iter_mut().zip(lengths) {
+        let mut shard = shards.next().unwrap();
+        shard.truncate(length);
+        // Data chunks are slices of a base64 string, so this only fails if parity lied
+        *chunk = Some(String::from_utf8_lossy(&shard).into_owned());
+    }
+    for chunk in parity.iter_mut() {
+        *chunk = Some(base64::engine::general_purpose::URL_SAFE.encode(shards.next().unwrap()));
+    }
+    Ok(())
+}
+
+fn pad(chunk: &[u8], shard_size: usize) -> Vec<u8> {
+    let mut shard = chunk.to_vec();
+    shard.resize(shard_size, 0);
+    shard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Erasure;
+
+    #[test]
+    fn test_erasure_from_str() {
+        assert_eq!(
+            "4+2".parse::<Erasure>().unwrap(),
+            Erasure { data: 4, parity: 2 }
+        );
+        assert!("4".parse::<Erasure>().is_err());
+        assert!("0+2".parse::<Erasure>().is_err());
+        assert!("200+100".parse::<Erasure>().is_err());
+    }
+
+    #[test]
+    fn test_reconstruct_missing_chunks() {
+        let erasure = Erasure { data: 3, parity: 2 };
+        let encoded = "VGhlIHF1aWNrIGJyb3duIGZveCBqdW1wZWQgb3ZlciB0aGUgbGF6eSBkb2c=";
+        let shard_size = super::shard_size(16);
+        let lengths = super::chunk_lengths(encoded.len(), shard_size);
+        let chunks: Vec<String> = encoded
+            .as_bytes()
+            .chunks(shard_size)
+            .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap())
+            .collect();
+        let parity = super::encode(&chunks, erasure, shard_size).unwrap();
+        let stripes: Vec<_> = super::stripes(chunks.len(), erasure).collect();
+        assert_eq!(stripes.len(), parity.len());
+
+        for (i, stripe) in stripes.into_iter().enumerate() {
+            let mut data: Vec<Option<String>> =
+                chunks[stripe.clone()].iter().cloned().map(Some).collect();
+            let mut stripe_parity: Vec<Option<String>> =
+                parity[i].iter().cloned().map(Some).collect();
+            // Lose as many chunks as there are parity chunks
+            data[0] = None;
+            stripe_parity[1] = None;
+            super::reconstruct(
+                i,
+                &mut data,
+                &mut stripe_parity,
+                &lengths[stripe.clone()],
+                shard_size,
+            )
+            .unwrap();
+            let data: Vec<String> = data.into_iter().map(Option::unwrap).collect();
+            assert_eq!(data, chunks[stripe]);
+            assert_eq!(stripe_parity[1].as_ref(), Some(&parity[i][1]));
+        }
+    }
+
+    #[test]
+    fn test_too_many_missing_chunks() {
+        let mut data = vec![None, None, Some(String::from("abc"))];
+        let mut parity = vec![Some(String::from("AAAA"))];
+        assert!(super::reconstruct(0, &mut data, &mut parity, &[3, 3, 3], 3).is_err());
+    }
+}